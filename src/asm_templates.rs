@@ -1,16 +1,62 @@
-pub const COMMAND_UNARY: &'static str = "@SP  // UNARY command
+pub const COMMAND_BOOTSTRAP_SET: &str = "// BOOTSTRAP - set REGISTER
+LOAD_VALUE@REGISTER
+M=D
+";
+
+pub const COMMAND_BOOTSTRAP_HALT: &str = "(BOOTSTRAP$HALT)   // BOOTSTRAP - halt after returning
+@BOOTSTRAP$HALT
+0;JMP
+";
+
+pub const COMMAND_UNARY: &str = "@SP  // UNARY command
 A=M-1
 M={}M
 ";
 
-pub const ARITHMETIC_FORMAT_1: &'static str = "@SP
+pub const ARITHMETIC_FORMAT_1: &str = "@SP
+AM=M-1
+D=M
+A=A-1
+";
+
+/// Leaves a value in D whose sign matches x - y, where y is the top of the
+/// stack and x is below it, and pops y. Subtracting operands of opposite signs
+/// can overflow, so the signs are checked first and the subtraction is only
+/// performed when they agree.
+pub const ARITHMETIC_COMPARE_SIGNED: &str = "@SP  // SIGNED COMPARE command
 AM=M-1
+D=M              // D = y
+@COMPARE_Y_NEGATIVE.JUMP_NUMBER
+D;JLT
+@SP              // y >= 0, so x - y only overflows when x < 0
+A=M-1
+D=M              // D = x
+@COMPARE_RESULT.JUMP_NUMBER
+D;JLT            // x < 0 <= y, D = x is already negative
+@COMPARE_SUBTRACT.JUMP_NUMBER
+0;JMP
+
+(COMPARE_Y_NEGATIVE.JUMP_NUMBER)
+@SP              // y < 0, so x - y only overflows when x >= 0
+A=M-1
+D=M              // D = x
+@COMPARE_SUBTRACT.JUMP_NUMBER
+D;JLT
+D=1              // y < 0 <= x, any positive value will do
+@COMPARE_RESULT.JUMP_NUMBER
+0;JMP
+
+(COMPARE_SUBTRACT.JUMP_NUMBER)
+@SP              // Same signs, x - y cannot overflow
+A=M
 D=M
 A=A-1
+D=M-D
+
+(COMPARE_RESULT.JUMP_NUMBER)
 ";
 
-pub const ARITHMETIC_FORMAT_2: &'static str = "D=M-D
-@FALSE.JUMP_NUMBER
+pub const ARITHMETIC_FORMAT_2: &str = "@FALSE.JUMP_NUMBER
 D;JUMP_TYPE
 @SP
 A=M-1
@@ -26,14 +72,95 @@ M=0
 (CONTINUE.JUMP_NUMBER)
 ";
 
-pub const COMMAND_SHIFT: &'static str = "@SP  // SHIFT command
+/// Follows a comparison that left the sign of x - y in D with y already
+/// popped: pops x and jumps on D instead of materialising a boolean.
+pub const COMMAND_COMPARE_BRANCH: &str = "@SP  // COMPARE AND BRANCH
+M=M-1
+@LABEL
+D;JUMP_TYPE
+";
+
+/// x ^ y as (x & !y) + (y & !x), the two having no bits in common. Both are
+/// x or y less x & y, which leaves y's slot for the second.
+pub const COMMAND_XOR: &str = "@SP  // XOR command
 AM=M-1
-M=M{}
+D=M
+A=A-1
+D=D&M            // D = x & y
+M=M-D            // x = x & !y
+A=A+1
+M=M-D            // y = y & !x
+D=M
+A=A-1
+M=D+M
+";
+
+/// Flipping the sign bits of x and y turns their unsigned order into the
+/// signed order, which the signed comparisons then test.
+pub const COMMAND_FLIP_SIGNS: &str = "@32767  // FLIP SIGN BITS of x and y
+D=!A
 @SP
-M=M+1
+A=M-1
+M=D+M
+A=A-1
+M=D+M
+";
+
+/// `Memory.peek(address)`, replacing the address with the word at it.
+pub const INTRINSIC_PEEK: &str = "@SP  // INTRINSIC Memory.peek
+A=M-1
+A=M
+D=M
+@SP
+A=M-1
+M=D
 ";
 
-pub const COMMAND_PUSH: &'static str = "@SEGMENT   // PUSH command
+/// `Memory.poke(address, value)`, replacing the arguments with the 0 the OS
+/// function returns.
+pub const INTRINSIC_POKE: &str = "@SP  // INTRINSIC Memory.poke
+AM=M-1
+D=M              // D = value
+@SP
+A=M-1
+A=M
+M=D
+@SP
+A=M-1
+M=0
+";
+
+/// Pops y into R14 and x into R13 for a routine.
+pub const COMMAND_ROUTINE_OPERANDS: &str = "@SP  // POP routine operands
+AM=M-1
+D=M
+@R14
+M=D
+@SP
+AM=M-1
+D=M
+@R13
+M=D
+";
+
+/// Calls a routine whose operands are in place, see `routines`.
+pub const COMMAND_ROUTINE_CALL: &str =
+    "@ROUTINE_NAME_RETURN.JUMP_NUMBER  // CALL routine ROUTINE_NAME
+D=A
+@R15
+M=D
+@ROUTINE$ROUTINE_NAME
+0;JMP
+(ROUTINE_NAME_RETURN.JUMP_NUMBER)
+";
+
+pub const COMMAND_ROUTINE_RESULT: &str = "@SP  // PUSH routine result
+AM=M+1
+A=A-1
+M=D
+";
+
+pub const COMMAND_PUSH: &str = "@SEGMENT   // PUSH command
 D=M              // Load the base address or constant value into D
 @INDEX
 A=D+A            // Compute the effective address (base + index)
@@ -45,7 +172,7 @@ M=D              // Push the value onto the stack
 M=M+1            // Increment the stack pointer
 ";
 
-pub const COMMAND_PUSH_DIRECT: &'static str = "@INDEX // PUSH CONSTANT command
+pub const COMMAND_PUSH_DIRECT: &str = "@INDEX // PUSH CONSTANT command
 D=ORIGIN         // Load the constant value into D
 @SP
 A=M              // Point to the top of the stack
@@ -54,7 +181,21 @@ M=D              // Push the constant onto the stack
 M=M+1            // Increment the stack pointer
 ";
 
-pub const COMMAND_POP: &'static str = "@SEGMENT    // POP command
+pub const COMMAND_PUSH_LITERAL: &str = "@SP   // PUSH literal command
+AM=M+1
+A=A-1
+M=VALUE            // Hack can write 0, 1 and -1 without going through D
+";
+
+pub const COMMAND_PUSH_SMALL_INDEX: &str = "@SEGMENT   // PUSH command for index 0 or 1
+ADDRESSD=M         // Load the value at base + index into D
+@SP
+AM=M+1
+A=A-1
+M=D
+";
+
+pub const COMMAND_POP: &str = "@SEGMENT    // POP command
 D=M              // Load the base address into D
 @INDEX
 D=D+A            // Compute the effective address (base + index)
@@ -68,7 +209,7 @@ A=M              // Point to the effective address
 M=D              // Store the value at the effective address
 ";
 
-pub const COMMAND_POP_DIRECT: &'static str = "@SEGMENT // POP DIRECT
+pub const COMMAND_POP_DIRECT: &str = "@SEGMENT // POP DIRECT
 D=A
 @R13
 M=D
@@ -80,20 +221,34 @@ A=M
 M=D
 ";
 
-pub const COMMAND_LABEL: &'static str = "(LABEL_NAME)
+pub const COMMAND_POP_SMALL_INDEX: &str = "@SP   // POP command for index 0 or 1
+AM=M-1
+D=M
+@SEGMENT
+ADDRESSM=D         // Store the value at base + index
+";
+
+pub const COMMAND_POP_FIXED: &str = "@SP   // POP command to an address known at compile time
+AM=M-1
+D=M
+@ADDRESS
+M=D
 ";
 
-pub const COMMAND_GOTO: &'static str = "@LABEL // GOTO
+pub const COMMAND_LABEL: &str = "(LABEL_NAME)
+";
+
+pub const COMMAND_GOTO: &str = "@LABEL // GOTO
 0;JMP
 ";
 
-pub const COMMAND_IF_GOTO: &'static str = "// IF-GOTO
+pub const COMMAND_IF_GOTO: &str = "// IF-GOTO
 ARITHMETIC_FORMAT_1
 @LABEL
 D;JNE            // True is any non zero value
 ";
 
-pub const COMMAND_CALL: &'static str = "@FUNCTION_LABEL     // CALL FUNCTION
+pub const COMMAND_CALL: &str = "@FUNCTION_LABEL     // CALL FUNCTION
 D=A                // Push return address
 @SP
 AM=M+1
@@ -110,7 +265,7 @@ PUSH_THAT
 
 @SP                // Reposition ARG for the callee
 D=M
-@5
+@FRAME_SIZE
 D=D-A
 @N_ARGS
 D=D-A
@@ -128,19 +283,61 @@ M=D
 (FUNCTION_LABEL)   // Declare return address label
 ";
 
-pub const COMMAND_FUNCTION: &'static str = "(FUNCTION_NAME) // FUNCTION create new function
+/// Jumps to a function through the frame of the function making the call.
+/// SAVE_FRAME stages the callee's frame above the stack, MOVE_ARGS moves the
+/// arguments down to ARG and RESTORE_FRAME copies the staged frame below the
+/// new LCL.
+pub const COMMAND_TAIL_CALL: &str = "// TAIL CALL FUNCTION_NAME
+SAVE_FRAME
+MOVE_ARGS
+@ARG                // The callee's frame follows its arguments (LCL = ARG + N_ARGS + FRAME_SIZE)
+D=M
+@LCL_OFFSET
+D=D+A
+@LCL
+M=D
+
+RESTORE_FRAME
+@LCL                // Set SP to LCL
+D=M
+@SP
+M=D
+
+@FUNCTION_NAME      // Jump to the function
+0;JMP
+";
+
+pub const COMMAND_FUNCTION: &str = "(FUNCTION_NAME) // FUNCTION create new function
 SETUP_VARIABLES
 ";
 
-pub const COMMAND_RETURN: &'static str = "@LCL  // COMMAND RETURN
-D=M                 // Save the current LCL in a temporary variable (FRAME = LCL)
-@FRAME
+/// Zeroes the locals from the last one down, relying on the caller having set
+/// LCL to SP. The parser rejects `$` in VM labels, so the loop label cannot
+/// clash with one of the function's own labels.
+pub const COMMAND_INIT_LOCALS_LOOP: &str = "@N_LOCALS   // Zero the locals in a loop
+D=A
+(FUNCTION_NAME$$INIT_LOCALS)
+@LCL
+A=D+M
+A=A-1            // Point to local D - 1
+M=0
+@FUNCTION_NAME$$INIT_LOCALS
+D=D-1;JGT
+@N_LOCALS        // Move SP past the locals
+D=A
+@SP
+M=D+M
+";
+
+pub const COMMAND_RETURN: &str = "@LCL  // COMMAND RETURN
+D=M                 // Save the current LCL in a scratch register (R14 = FRAME = LCL)
+@R14
 M=D
 
-@5                  // Get the return address (RET = *(FRAME - 5))
+@5                  // Get the return address (R15 = RET = *(FRAME - 5))
 A=D-A
 D=M
-@RET
+@R15
 M=D
 
 POP_ARG
@@ -150,36 +347,501 @@ D=M+1
 @SP
 M=D
 
-@FRAME              // Restore THAT of the caller (THAT = *(FRAME - 1))
+@R14                // Restore THAT of the caller (THAT = *(FRAME - 1))
 D=M-1
 AM=D
 D=M
 @THAT
 M=D
 
-@FRAME              // Restore THIS of the caller (THIS = *(FRAME - 2))
+@R14                // Restore THIS of the caller (THIS = *(FRAME - 2))
 D=M-1
 AM=D
 D=M
 @THIS
 M=D
 
-@FRAME              // Restore ARG of the caller (ARG = *(FRAME - 3))
+@R14                // Restore ARG of the caller (ARG = *(FRAME - 3))
 D=M-1
 AM=D
 D=M
 @ARG
 M=D
 
-@FRAME              // Restore LCL of the caller (LCL *(FRAME - 4))
+@R14                // Restore LCL of the caller (LCL *(FRAME - 4))
 D=M-1
 AM=D
 D=M
 @LCL
 M=D
 
-@RET                // Go to the return address (goto RET)
+@R15                // Go to the return address (goto RET)
+A=M
+0;JMP
+";
+
+/// Returns from a function called with a lite frame, which only holds the
+/// return address, LCL and ARG.
+pub const COMMAND_RETURN_LITE: &str = "@LCL  // COMMAND RETURN from a lite frame
+D=M                 // Save the current LCL in a scratch register (R14 = FRAME = LCL)
+@R14
+M=D
+
+@3                  // Get the return address (R15 = RET = *(FRAME - 3))
+A=D-A
+D=M
+@R15
+M=D
+
+POP_ARG
+
+@ARG                // Restore SP of the caller (SP = ARG + 1)
+D=M+1
+@SP
+M=D
+
+@R14                // Restore ARG of the caller (ARG = *(FRAME - 1))
+D=M-1
+AM=D
+D=M
+@ARG
+M=D
+
+@R14                // Restore LCL of the caller (LCL = *(FRAME - 2))
+D=M-1
+AM=D
+D=M
+@LCL
+M=D
+
+@R15                // Go to the return address (goto RET)
+A=M
+0;JMP
+";
+
+// The templates below are used when the top of the stack is cached in D. The
+// memory stack then holds every value below the top, and SP points just past
+// them.
+
+pub const CACHED_SPILL: &str = "@SP  // SPILL cached top of stack
+AM=M+1
+A=A-1
+M=D
+";
+
+pub const CACHED_LOAD: &str = "@SP  // LOAD top of stack into D
+AM=M-1
+D=M
+";
+
+pub const CACHED_PUSH: &str = "@SEGMENT   // PUSH into D
+D=M
+@INDEX
+A=D+A
+D=M
+";
+
+pub const CACHED_PUSH_DIRECT: &str = "@INDEX // PUSH DIRECT into D
+D=ORIGIN
+";
+
+pub const CACHED_POP: &str = "@R13    // POP cached top into a segment
+M=D              // R13 = value
+@SEGMENT
+D=M
+@INDEX
+D=D+A            // D = address
+@R13
+D=D+M            // D = address + value
+A=D-M            // A = address
+M=D-A            // RAM[address] = value
+";
+
+pub const CACHED_POP_DIRECT: &str = "@SEGMENT // POP DIRECT from D
+M=D
+";
+
+pub const CACHED_UNARY: &str = "D={}D  // UNARY command on D
+";
+
+pub const CACHED_BINARY: &str = "@SP  // BINARY command with y in D
+AM=M-1
+D={}
+";
+
+/// Like `ARITHMETIC_COMPARE_SIGNED`, but with y in D. Pops x and leaves a value
+/// in D whose sign matches x - y.
+pub const CACHED_COMPARE_SIGNED: &str = "@R13  // SIGNED COMPARE with y in D
+M=D              // R13 = y
+@SP
+AM=M-1
+D=M              // D = x
+@COMPARE_X_NEGATIVE.JUMP_NUMBER
+D;JLT
+@R13             // x >= 0, so x - y only overflows when y < 0
+D=M
+@COMPARE_SUBTRACT.JUMP_NUMBER
+D;JGE
+D=1              // x >= 0 > y, any positive value will do
+@COMPARE_RESULT.JUMP_NUMBER
+0;JMP
+
+(COMPARE_X_NEGATIVE.JUMP_NUMBER)
+@R13             // x < 0, so x - y only overflows when y >= 0
+D=M
+@COMPARE_SUBTRACT.JUMP_NUMBER
+D;JLT
+D=-1             // x < 0 <= y, any negative value will do
+@COMPARE_RESULT.JUMP_NUMBER
+0;JMP
+
+(COMPARE_SUBTRACT.JUMP_NUMBER)
+@R13             // Same signs, x - y cannot overflow
+D=M
+@SP
 A=M
+D=M-D
+
+(COMPARE_RESULT.JUMP_NUMBER)
+";
+
+pub const CACHED_COMPARE_EQUAL: &str = "@SP  // EQUAL COMPARE with y in D
+AM=M-1
+D=M-D
+";
+
+pub const CACHED_COMPARE_RESULT: &str = "@TRUE.JUMP_NUMBER
+D;JUMP_TYPE
+D=0
+@CONTINUE.JUMP_NUMBER
 0;JMP
+
+(TRUE.JUMP_NUMBER)
+D=-1
+
+(CONTINUE.JUMP_NUMBER)
+";
+
+pub const CACHED_BRANCH: &str = "@LABEL // BRANCH on D
+D;JUMP_TYPE
+";
+
+// The templates below are used when SP updates are batched within a basic
+// block. STACK_SLOT points A at a stack slot relative to the SP stored in
+// memory, and STACK_WRITE_BACK moves that SP by the pending offset. Neither of
+// them touches D.
+
+pub const BATCHED_PUSH: &str = "@SEGMENT   // PUSH into batched stack slot
+D=M
+@INDEX
+A=D+A
+D=M
+STACK_SLOTM=D
+";
+
+pub const BATCHED_PUSH_DIRECT: &str = "@INDEX // PUSH DIRECT into batched stack slot
+D=ORIGIN
+STACK_SLOTM=D
+";
+
+pub const BATCHED_PUSH_LITERAL: &str = "// PUSH literal into batched stack slot
+STACK_SLOTM=VALUE
+";
+
+pub const BATCHED_PUSH_SMALL_INDEX: &str =
+    "@SEGMENT   // PUSH into batched stack slot for index 0 or 1
+ADDRESSD=M
+STACK_SLOTM=D
+";
+
+pub const BATCHED_POP_SMALL_INDEX: &str = "// POP from batched stack slot for index 0 or 1
+STACK_SLOTD=M
+@SEGMENT
+ADDRESSM=D
+";
+
+pub const BATCHED_POP: &str = "@SEGMENT    // POP from batched stack slot
+D=M
+@INDEX
+D=D+A
+@R13
+M=D              // Store the effective address in R13
+STACK_SLOTD=M
+@R13
+A=M
+M=D
+";
+
+pub const BATCHED_POP_DIRECT: &str = "// POP DIRECT from batched stack slot
+STACK_SLOTD=M
+@SEGMENT
+M=D
+";
+
+pub const BATCHED_UNARY: &str = "// UNARY command on batched stack slot
+STACK_SLOTM={}M
+";
+
+pub const BATCHED_BINARY: &str = "// BINARY command on batched stack slots
+STACK_SLOTD=M
+A=A-1
+";
+
+pub const BATCHED_IF_GOTO: &str = "// IF-GOTO on batched stack slot
+STACK_SLOTD=M
+STACK_WRITE_BACK@LABEL
+D;JNE
+";
+
+pub const CACHED_ROUTINE_OPERANDS: &str = "@R14  // POP routine operands with y in D
+M=D
+@SP
+AM=M-1
+D=M
+@R13
+M=D
 ";
 
+// The routines below are shared by the whole program and emitted once after
+// it. They take x in R13, y in R14 and their return address in R15, and leave
+// their result in D. Routines that need more room keep the return address and
+// the rest of their state in `ROUTINE$` variables, which the assembler places
+// with the statics.
+
+/// Returns from a routine that kept its return address in a variable.
+pub const ROUTINE_RETURN_FROM_VARIABLE: &str = "@ROUTINE$RETURN_ADDRESS
+A=M
+0;JMP
+";
+
+/// Adds x for each set bit of y, doubling x and halving y until y is 0 or
+/// -1. The bits of y left then are all ones, worth -x.
+pub const ROUTINE_MULTIPLY_EXTENDED: &str = "(ROUTINE$MULTIPLY)  // MULTIPLY routine, D = R13 * R14
+@R15
+D=M
+@ROUTINE$RETURN_ADDRESS
+M=D
+@R15             // R15 = product
+M=0
+(ROUTINE$MULTIPLY.LOOP)
+@R14
+D=M
+@ROUTINE$MULTIPLY.END
+D;JEQ
+@ROUTINE$MULTIPLY.ALL_ONES
+D+1;JEQ
+@1
+D=D&A
+@ROUTINE$MULTIPLY.NEXT
+D;JEQ
+@R13
+D=M
+@R15
+M=D+M
+(ROUTINE$MULTIPLY.NEXT)
+@R13
+M=M<<
+@R14
+M=M>>
+@ROUTINE$MULTIPLY.LOOP
+0;JMP
+(ROUTINE$MULTIPLY.ALL_ONES)
+@R13
+D=M
+@R15
+M=M-D
+(ROUTINE$MULTIPLY.END)
+@R15
+D=M
+";
+
+/// Adds x for each set bit of y, doubling x and a mask that finds the bits.
+/// Each bit found is cleared, so the loop ends once y has no bits left.
+pub const ROUTINE_MULTIPLY_STANDARD: &str = "(ROUTINE$MULTIPLY)  // MULTIPLY routine, D = R13 * R14
+@R15
+D=M
+@ROUTINE$RETURN_ADDRESS
+M=D
+@ROUTINE$MASK
+M=1
+@R15             // R15 = product
+M=0
+(ROUTINE$MULTIPLY.LOOP)
+@R14
+D=M
+@ROUTINE$MULTIPLY.END
+D;JEQ
+@ROUTINE$MASK
+D=D&M
+@ROUTINE$MULTIPLY.NEXT
+D;JEQ
+@R14
+M=M-D
+@R13
+D=M
+@R15
+M=D+M
+(ROUTINE$MULTIPLY.NEXT)
+@R13
+D=M
+M=D+M
+@ROUTINE$MASK
+D=M
+M=D+M
+@ROUTINE$MULTIPLY.LOOP
+0;JMP
+(ROUTINE$MULTIPLY.END)
+@R15
+D=M
+";
+
+/// Long division of the magnitudes, where that of -32768 is 0x8000. The bits
+/// of x move into the remainder from the top while the quotient bits fill x
+/// from the bottom. Leaves the remainder in R14.
+pub const ROUTINE_DIVIDE: &str =
+    "(ROUTINE$DIVIDE)  // DIVIDE routine, D = R13 / R14, R14 = R13 % R14
+@R14
+D=M
+@ROUTINE$DIVIDE.BY_ZERO
+D;JEQ
+@R15
+D=M
+@ROUTINE$RETURN_ADDRESS
+M=D
+@R13             // Keep x for the sign of the remainder
+D=M
+@ROUTINE$X
+M=D
+@ROUTINE$DIVIDE.X_NEGATIVE
+D;JLT
+@R14
+D=M
+@ROUTINE$DIVIDE.SIGNS
+0;JMP
+(ROUTINE$DIVIDE.X_NEGATIVE)
+@R13
+M=-M
+@R14
+D=!M
+(ROUTINE$DIVIDE.SIGNS)
+@ROUTINE$QUOTIENT_SIGN  // Negative when the quotient is
+M=D
+@R14
+D=M
+@ROUTINE$DIVIDE.Y_POSITIVE
+D;JGE
+@R14
+M=-M
+(ROUTINE$DIVIDE.Y_POSITIVE)
+@16              // Bits of x left
+D=A
+@ROUTINE$BITS
+M=D
+@R15             // R15 = remainder
+M=0
+(ROUTINE$DIVIDE.LOOP)
+@R15
+D=M
+M=D+M
+@R13
+D=M
+@ROUTINE$DIVIDE.SHIFT
+D;JGE
+@R15
+M=M+1
+(ROUTINE$DIVIDE.SHIFT)
+@R13
+M=D+M
+@R15             // With y at most 0x8000, a remainder with its top bit set is at least y
+D=M
+@ROUTINE$DIVIDE.SUBTRACT
+D;JLT
+@R14
+D=D-M
+@ROUTINE$DIVIDE.NEXT
+D;JLT
+(ROUTINE$DIVIDE.SUBTRACT)
+@R14
+D=M
+@R15
+M=M-D
+@R13
+M=M+1
+(ROUTINE$DIVIDE.NEXT)
+@ROUTINE$BITS
+M=M-1
+D=M
+@ROUTINE$DIVIDE.LOOP
+D;JGT
+@ROUTINE$X
+D=M
+@ROUTINE$DIVIDE.REMAINDER
+D;JGE
+@R15
+M=-M
+(ROUTINE$DIVIDE.REMAINDER)
+@R15
+D=M
+@R14
+M=D
+@ROUTINE$QUOTIENT_SIGN
+D=M
+@ROUTINE$DIVIDE.QUOTIENT
+D;JGE
+@R13
+M=-M
+(ROUTINE$DIVIDE.QUOTIENT)
+@R13
+D=M
+@ROUTINE$DIVIDE.RETURN
+0;JMP
+(ROUTINE$DIVIDE.BY_ZERO)  // x / 0 = 0 and x % 0 = x
+@R13
+D=M
+@R14
+M=D
+D=0
+@R15
+A=M
+0;JMP
+(ROUTINE$DIVIDE.RETURN)
+";
+
+/// An arithmetic shift right of R13 into D in plain Hack, which copies each
+/// bit of R13 down by AMOUNT. A negative value sets the bits from 15 - AMOUNT
+/// up, and `ROUTINE_SHIFT_RIGHT_BIT` handles each of the bits from AMOUNT to
+/// 14. Uses R14 for the result.
+pub const ROUTINE_SHIFT_RIGHT: &str =
+    "(ROUTINE$SHIFT_RIGHT_AMOUNT)  // SHIFT RIGHT routine, D = R13 shifted right by AMOUNT
+@R14
+M=0
+@R13
+D=M
+@ROUTINE$SHIFT_RIGHT_AMOUNT.POSITIVE
+D;JGE
+@SIGN_BITS
+D=-A
+@R14
+M=D
+(ROUTINE$SHIFT_RIGHT_AMOUNT.POSITIVE)
+";
+
+pub const ROUTINE_SHIFT_RIGHT_BIT: &str = "@R13  // Copy bit BIT down by AMOUNT
+D=M
+@MASK
+D=D&A
+@ROUTINE$SHIFT_RIGHT_AMOUNT.BIT
+D;JEQ
+@SHIFTED_MASK
+D=A
+@R14
+M=D|M
+(ROUTINE$SHIFT_RIGHT_AMOUNT.BIT)
+";
+
+pub const ROUTINE_SHIFT_RIGHT_END: &str = "@R14
+D=M
+@R15
+A=M
+0;JMP
+";
@@ -0,0 +1,155 @@
+use crate::c_backend::CBackend;
+use crate::compiler::{CodegenOptions, HackBackend};
+use crate::instructions::{Instruction, Module};
+use crate::llvm_backend::LlvmBackend;
+use crate::memory_layout::MemoryLayout;
+use crate::wat_backend::WatBackend;
+use crate::x86_backend::X86Backend;
+
+/// The names `--target` accepts, the first being the default.
+pub const TARGETS: [&str; 5] = ["hack", "c", "x86", "wat", "llvm"];
+
+/// A code generation target. `compile_program` drives it through the
+/// program: the prologue, then each module from its beginning through each
+/// of its instructions to its end, then the epilogue.
+pub trait Backend {
+    /// The extension of the file the program is written to.
+    fn file_extension(&self) -> &'static str;
+
+    /// The code that starts the program, such as the bootstrap. Backends
+    /// that need to know about the whole program up front collect it here.
+    fn begin_program(&mut self, modules: &[Module]) -> String;
+
+    fn begin_module(&mut self, _module: &Module) -> String {
+        String::new()
+    }
+
+    /// Translates the instruction at `position` in the module. A backend may
+    /// translate the instructions after it along with it, up to the whole
+    /// rest of the module, and returns how many instructions it translated.
+    fn emit_instruction(&mut self, module: &Module, position: usize) -> (String, usize);
+
+    fn end_module(&mut self, _module: &Module) -> String {
+        String::new()
+    }
+
+    fn end_program(&mut self) -> String {
+        String::new()
+    }
+
+    /// Whether the target runs `asm` blocks, which hold Hack assembly.
+    fn supports_asm(&self) -> bool {
+        false
+    }
+}
+
+/// The backend for a `--target` name, or `None` for an unknown target.
+pub fn create<'a>(
+    target: &str,
+    layout: &'a MemoryLayout,
+    codegen: &CodegenOptions,
+) -> Option<Box<dyn Backend + 'a>> {
+    let backend: Box<dyn Backend + 'a> = match target {
+        "hack" => Box::new(HackBackend::new(layout, codegen)),
+        "c" => Box::new(CBackend::new(layout)),
+        "x86" => Box::new(X86Backend::new(layout)),
+        "wat" => Box::new(WatBackend::new(layout)),
+        "llvm" => Box::new(LlvmBackend::new(layout)),
+        _ => return None,
+    };
+    Some(backend)
+}
+
+/// Rejects a program the backend cannot translate, before any code is
+/// generated for it.
+pub fn check_program(backend: &dyn Backend, modules: &[Module]) -> Result<(), String> {
+    if backend.supports_asm() {
+        return Ok(());
+    }
+    for module in modules {
+        let has_asm = module
+            .instructions
+            .iter()
+            .any(|instruction| matches!(instruction, Instruction::CAsm(_)));
+        if has_asm {
+            return Err(format!(
+                "{}: asm blocks only compile for the hack target",
+                module.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub fn compile_program(backend: &mut dyn Backend, modules: &[Module]) -> String {
+    let mut code = backend.begin_program(modules);
+    for module in modules {
+        code.push_str(&backend.begin_module(module));
+        let mut position = 0;
+        while position < module.instructions.len() {
+            let (instruction_code, consumed) = backend.emit_instruction(module, position);
+            code.push_str(&instruction_code);
+            position += consumed;
+        }
+        code.push_str(&backend.end_module(module));
+    }
+    code + &backend.end_program()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_programs::parse_modules;
+
+    #[test]
+    fn every_target_has_a_backend() {
+        let layout = MemoryLayout::default();
+        let codegen = CodegenOptions::default();
+        for target in TARGETS {
+            assert!(create(target, &layout, &codegen).is_some(), "{}", target);
+        }
+        assert!(create("arm", &layout, &codegen).is_none());
+    }
+
+    #[test]
+    fn only_the_hack_target_accepts_asm_blocks() {
+        let layout = MemoryLayout::default();
+        let codegen = CodegenOptions::default();
+        let modules = [
+            Module::new("Other", vec![Instruction::CReturn]),
+            Module::new("Main", vec![Instruction::CAsm(vec![String::from("D=0")])]),
+        ];
+        for target in TARGETS {
+            let backend = create(target, &layout, &codegen).unwrap();
+            assert_eq!(check_program(backend.as_ref(), &modules[..1]), Ok(()));
+            let result = check_program(backend.as_ref(), &modules);
+            if target == "hack" {
+                assert_eq!(result, Ok(()));
+            } else {
+                assert_eq!(
+                    result,
+                    Err(String::from(
+                        "Main: asm blocks only compile for the hack target"
+                    )),
+                    "{}",
+                    target
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Sys: the asm block [\"D=0\"] only compiles for the hack target, not c"
+    )]
+    fn unchecked_asm_blocks_name_the_target_they_reach() {
+        let layout = MemoryLayout::default();
+        let modules = parse_modules(&[(
+            "Sys",
+            "function Sys.init 0
+            asm \"D=0\"
+            return",
+        )]);
+        compile_program(&mut CBackend::new(&layout), &modules);
+    }
+}
@@ -0,0 +1,342 @@
+use crate::backend::Backend;
+use crate::c_templates::{C_CALL, C_CALL_LITE, C_EPILOGUE, C_PROLOGUE, C_RETURN, C_RETURN_LITE};
+use crate::frames;
+use crate::instructions::{
+    ArithmeticType, BinaryArithmeticOperator, Call, ExtendedArithmeticOperator, FrameKind,
+    Instruction, Module, Segment, ShiftArithmeticOperator, UnaryArithmeticOperator,
+};
+use crate::memory_layout::{io_address, MemoryLayout};
+use crate::passes::format_instruction;
+use crate::program_symbols::{is_halt_loop, ProgramSymbols};
+
+/// The C target, which runs the program on a model of the Hack RAM and prints
+/// its non-zero words once it halts. Calls push their call site number as the
+/// return address.
+pub struct CBackend<'a> {
+    layout: &'a MemoryLayout,
+    symbols: ProgramSymbols,
+    return_count: usize,
+    /// The frame of the function being translated, which its returns unwind.
+    frame: FrameKind,
+}
+
+impl<'a> CBackend<'a> {
+    pub fn new(layout: &'a MemoryLayout) -> Self {
+        Self {
+            layout,
+            symbols: ProgramSymbols::new(&[]),
+            return_count: 0,
+            frame: FrameKind::Full,
+        }
+    }
+}
+
+impl Backend for CBackend<'_> {
+    fn file_extension(&self) -> &'static str {
+        "c"
+    }
+
+    fn begin_program(&mut self, modules: &[Module]) -> String {
+        self.symbols = ProgramSymbols::new(modules);
+        let layout = self.layout;
+        let mut entry_call = Call::new(
+            &layout.entry_point,
+            &format!("{}&ret.0", layout.entry_point),
+            0,
+        );
+        entry_call.frame = frames::entry_frame(modules, &layout.entry_point);
+        C_PROLOGUE
+            .replace("STACK_BASE", &layout.stack_base.to_string())
+            .replace("INITIAL_LCL", &layout.initial_lcl.to_string())
+            .replace("INITIAL_ARG", &layout.initial_arg.to_string())
+            .replace("INITIAL_THIS", &layout.initial_this.to_string())
+            .replace("INITIAL_THAT", &layout.initial_that.to_string())
+            + &self.create_call(&entry_call)
+            + "    goto halt;\n"
+    }
+
+    fn begin_module(&mut self, module: &Module) -> String {
+        format!("\n    /* {}.vm */\n", module.name)
+    }
+
+    fn emit_instruction(&mut self, module: &Module, position: usize) -> (String, usize) {
+        if is_halt_loop(&module.instructions, position) {
+            return (String::from("    goto halt; /* halt */\n"), 1);
+        }
+        let code = self.create_instruction(&module.instructions[position], &module.name);
+        (code, 1)
+    }
+
+    fn end_program(&mut self) -> String {
+        let return_cases: String = (0..self.return_count)
+            .map(|id| format!("    case {}: goto R{};\n", id, id))
+            .collect();
+        C_EPILOGUE.replace("RETURN_CASES", &return_cases)
+    }
+}
+
+impl CBackend<'_> {
+    fn create_instruction(&mut self, instruction: &Instruction, module_name: &str) -> String {
+        let comment = format_instruction(instruction);
+        match instruction {
+            Instruction::CArithmetic(operator) => {
+                format!("    {} /* {} */\n", arithmetic(*operator), comment)
+            }
+            Instruction::CPush(push) => {
+                let value = match push.segment {
+                    Segment::Constant => push.index.to_string(),
+                    segment => self.segment_slot(segment, push.index, module_name),
+                };
+                format!("    PUSH({}); /* {} */\n", value, comment)
+            }
+            Instruction::CPop(pop) => {
+                let slot = match pop.segment {
+                    Segment::Constant => panic!("Cannot pop to constant"),
+                    segment => self.segment_slot(segment, pop.index, module_name),
+                };
+                format!("    {} = POP(); /* {} */\n", slot, comment)
+            }
+            Instruction::CLabel(_) => {
+                format!("{}:; /* {} */\n", self.label(instruction), comment)
+            }
+            Instruction::CGoto(_) => {
+                format!("    goto {}; /* {} */\n", self.label(instruction), comment)
+            }
+            Instruction::CIf(_) => format!(
+                "    if (POP() != 0) goto {}; /* {} */\n",
+                self.label(instruction),
+                comment
+            ),
+            Instruction::CFunction(function) => {
+                let id = self.symbols.function(&function.function_name);
+                self.frame = function.frame;
+                let mut code = format!("\nF{}:; /* {} */\n", id, comment);
+                for _ in 0..function.n_args {
+                    code.push_str("    PUSH(0);\n");
+                }
+                code
+            }
+            Instruction::CReturn => String::from(match self.frame {
+                FrameKind::Full => C_RETURN,
+                FrameKind::Lite => C_RETURN_LITE,
+            }),
+            Instruction::CCall(call) => self.create_call(call),
+            Instruction::CAsm(asm) => panic!(
+                "{}: the asm block {:?} only compiles for the hack target, not c",
+                module_name, asm
+            ),
+        }
+    }
+
+    fn create_call(&mut self, call: &Call) -> String {
+        let id = self.symbols.function(&call.function_name);
+        let template = match call.frame {
+            FrameKind::Full => C_CALL,
+            FrameKind::Lite => C_CALL_LITE,
+        };
+        let return_id = self.return_count;
+        self.return_count += 1;
+        template
+            .replace("RETURN_ID", &return_id.to_string())
+            .replace(
+                "COMMENT",
+                &format_instruction(&Instruction::CCall(call.clone())),
+            )
+            .replace("N_ARGS", &call.n_args.to_string())
+            .replace("FUNCTION_LABEL", &format!("F{}", id))
+            .replace("RETURN_LABEL", &format!("R{}", return_id))
+    }
+
+    /// The C lvalue of a segment slot.
+    fn segment_slot(&self, segment: Segment, index: u16, module_name: &str) -> String {
+        match segment {
+            Segment::Local | Segment::Argument | Segment::This | Segment::That => {
+                format!("M({} + {})", segment, index)
+            }
+            Segment::Pointer if index == 0 => String::from("THIS"),
+            Segment::Pointer => String::from("THAT"),
+            Segment::Temp => format!("ram[{}]", self.layout.temp_address(index)),
+            Segment::Screen | Segment::Keyboard => format!("ram[{}]", io_address(segment, index)),
+            Segment::Static => format!("ram[{}]", self.symbols.static_address(module_name, index)),
+            Segment::Constant => unreachable!(),
+        }
+    }
+
+    fn label(&self, instruction: &Instruction) -> String {
+        let label = match instruction {
+            Instruction::CLabel(label) | Instruction::CGoto(label) | Instruction::CIf(label) => {
+                label
+            }
+            _ => unreachable!(),
+        };
+        format!("L{}", self.symbols.label(label))
+    }
+}
+
+/// A C statement applying an operator to the top of the stack.
+fn arithmetic(operator: ArithmeticType) -> String {
+    let binary = |expression: &str| format!("y = POP(); TOP = {};", expression);
+    match operator {
+        ArithmeticType::Unary(UnaryArithmeticOperator::Negate) => String::from("TOP = WRAP(-TOP);"),
+        ArithmeticType::Unary(UnaryArithmeticOperator::Not) => String::from("TOP = ~TOP;"),
+        ArithmeticType::Binary(operator) => binary(match operator {
+            BinaryArithmeticOperator::Add => "WRAP(TOP + y)",
+            BinaryArithmeticOperator::Subtract => "WRAP(TOP - y)",
+            BinaryArithmeticOperator::And => "TOP & y",
+            BinaryArithmeticOperator::Or => "TOP | y",
+            BinaryArithmeticOperator::Eq => "TOP == y ? -1 : 0",
+            BinaryArithmeticOperator::Gt => "TOP > y ? -1 : 0",
+            BinaryArithmeticOperator::Lt => "TOP < y ? -1 : 0",
+        }),
+        ArithmeticType::Shift(ShiftArithmeticOperator::ShiftLeft, amount) => {
+            format!("TOP = WRAP((uint16_t)TOP << {});", amount)
+        }
+        // Shifting a negative value right is implementation defined in C,
+        // so the sign is carried over by hand
+        ArithmeticType::Shift(ShiftArithmeticOperator::ShiftRight, amount) => {
+            format!("TOP = TOP < 0 ? ~(~TOP >> {0}) : TOP >> {0};", amount)
+        }
+        // Division works on ints, where the most negative value divided by
+        // -1 fits before it wraps
+        ArithmeticType::Extended(operator) => binary(match operator {
+            ExtendedArithmeticOperator::Multiply => "WRAP(TOP * y)",
+            ExtendedArithmeticOperator::Divide => "y == 0 ? 0 : WRAP(TOP / y)",
+            ExtendedArithmeticOperator::Modulo => "y == 0 ? TOP : WRAP(TOP % y)",
+            ExtendedArithmeticOperator::Xor => "TOP ^ y",
+            ExtendedArithmeticOperator::UnsignedLt => "(uint16_t)TOP < (uint16_t)y ? -1 : 0",
+            ExtendedArithmeticOperator::UnsignedGt => "(uint16_t)TOP > (uint16_t)y ? -1 : 0",
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+    use std::{env, fs};
+
+    use super::*;
+    use crate::backend;
+    use crate::compiler::CodegenOptions;
+    use crate::test_programs::{assert_same_ram, parse_modules, run_hack};
+
+    const SYS: &str = "function Sys.init 0
+        push constant 3000
+        pop pointer 1
+        push constant 10
+        call Main.fib 1
+        pop that 0
+        push constant 32767
+        neg
+        push constant 3
+        call Main.mix 2
+        pop that 1
+        push static 0
+        pop that 2
+        push constant 1234
+        neg
+        push constant 77
+        call Main.extended 2
+        pop that 3
+        label HALT
+        goto HALT";
+
+    const MAIN: &str = "function Main.fib 0
+        push argument 0
+        push constant 2
+        lt
+        if-goto BASE
+        push argument 0
+        push constant 1
+        sub
+        call Main.fib 1
+        push argument 0
+        push constant 2
+        sub
+        call Main.fib 1
+        add
+        return
+        label BASE
+        push argument 0
+        return
+        function Main.mix 1
+        push argument 0
+        shiftright
+        push argument 1
+        shiftleft
+        sub
+        pop local 0
+        push static 1
+        push constant 1
+        add
+        pop static 0
+        push local 0
+        push argument 0
+        gt
+        push local 0
+        not
+        and
+        pop temp 2
+        push local 0
+        return
+        function Main.extended 0
+        push argument 0
+        push argument 1
+        mul
+        push argument 0
+        push argument 1
+        div
+        xor
+        push argument 0
+        push argument 1
+        mod
+        shl 2
+        add
+        push argument 0
+        push argument 1
+        ult
+        sub
+        push argument 0
+        shr 3
+        push argument 1
+        ugt
+        sub
+        return";
+
+    fn run_c(modules: &[Module], layout: &MemoryLayout) -> Vec<i16> {
+        let directory = env::temp_dir().join(format!("vm_c_backend_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let source = directory.join("program.c");
+        let binary = directory.join("program");
+        fs::write(
+            &source,
+            backend::compile_program(&mut CBackend::new(layout), modules),
+        )
+        .unwrap();
+        let built = Command::new("cc")
+            .arg("-o")
+            .arg(&binary)
+            .arg(&source)
+            .status()
+            .expect("Cannot run cc");
+        assert!(built.success(), "The C translation does not build");
+        let output = Command::new(&binary).output().unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        let mut ram = vec![0; 32768];
+        for line in String::from_utf8(output.stdout).unwrap().lines() {
+            let (address, value) = line.split_once(' ').unwrap();
+            ram[address.parse::<usize>().unwrap()] = value.parse().unwrap();
+        }
+        ram
+    }
+
+    #[test]
+    #[ignore = "needs cc, run with --ignored"]
+    fn c_translation_leaves_the_same_ram_as_hack_code() {
+        let layout = MemoryLayout::default();
+        let modules = parse_modules(&[("Sys", SYS), ("Main", MAIN)]);
+        let emulator = run_hack(&modules, &layout, &CodegenOptions::default());
+        assert_eq!(emulator.ram[3000..3004], [55, -16390, 0, 29471]);
+        let ram = run_c(&modules, &layout);
+        assert_same_ram(&ram, &emulator.ram, &modules, 3000..3004);
+    }
+}
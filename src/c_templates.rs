@@ -0,0 +1,92 @@
+pub const C_PROLOGUE: &str = "#include <stdint.h>
+#include <stdio.h>
+
+/* The Hack data memory. Addresses wrap at 15 bits like the A register. */
+static int16_t ram[32768];
+
+#define M(address) ram[(uint16_t)(address) & 0x7FFF]
+#define SP ram[0]
+#define LCL ram[1]
+#define ARG ram[2]
+#define THIS ram[3]
+#define THAT ram[4]
+#define TOP M(SP - 1)
+#define PUSH(value) (M(SP) = (int16_t)(value), SP++)
+#define POP() (SP--, M(SP))
+/* Results wrap to 16 bits, as they do with every mainstream compiler */
+#define WRAP(value) ((int16_t)(uint16_t)(value))
+
+static void run(void) {
+    int16_t y;
+    int16_t frame;
+    int16_t return_id;
+
+    /* Bootstrap */
+    SP = STACK_BASE;
+    LCL = INITIAL_LCL;
+    ARG = INITIAL_ARG;
+    THIS = INITIAL_THIS;
+    THAT = INITIAL_THAT;
+";
+
+pub const C_CALL: &str = "    PUSH(RETURN_ID); /* COMMENT */
+    PUSH(LCL);
+    PUSH(ARG);
+    PUSH(THIS);
+    PUSH(THAT);
+    ARG = WRAP(SP - 5 - N_ARGS);
+    LCL = SP;
+    goto FUNCTION_LABEL;
+RETURN_LABEL:;
+";
+
+pub const C_CALL_LITE: &str = "    PUSH(RETURN_ID); /* COMMENT */
+    PUSH(LCL);
+    PUSH(ARG);
+    ARG = WRAP(SP - 3 - N_ARGS);
+    LCL = SP;
+    goto FUNCTION_LABEL;
+RETURN_LABEL:;
+";
+
+pub const C_RETURN: &str = "    frame = LCL; /* return */
+    return_id = M(frame - 5);
+    M(ARG) = POP();
+    SP = WRAP(ARG + 1);
+    THAT = M(frame - 1);
+    THIS = M(frame - 2);
+    ARG = M(frame - 3);
+    LCL = M(frame - 4);
+    goto dispatch;
+";
+
+pub const C_RETURN_LITE: &str = "    frame = LCL; /* return // lite frame */
+    return_id = M(frame - 3);
+    M(ARG) = POP();
+    SP = WRAP(ARG + 1);
+    ARG = M(frame - 1);
+    LCL = M(frame - 2);
+    goto dispatch;
+";
+
+/// Returns jump back through the ids their calls pushed.
+pub const C_EPILOGUE: &str = "
+dispatch:
+    switch (return_id) {
+RETURN_CASES    default: goto halt;
+    }
+halt:
+    return;
+}
+
+/* Runs the program until it halts and prints every non-zero RAM word */
+int main(void) {
+    run();
+    for (int address = 0; address < 32768; address++) {
+        if (ram[address] != 0) {
+            printf(\"%d %d\\n\", address, ram[address]);
+        }
+    }
+    return 0;
+}
+";
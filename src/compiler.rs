@@ -1,42 +1,392 @@
 use crate::asm_templates::{
-    ARITHMETIC_FORMAT_1, ARITHMETIC_FORMAT_2, COMMAND_CALL, COMMAND_FUNCTION, COMMAND_GOTO,
-    COMMAND_IF_GOTO, COMMAND_LABEL, COMMAND_POP, COMMAND_POP_DIRECT, COMMAND_PUSH,
-    COMMAND_PUSH_DIRECT, COMMAND_RETURN, COMMAND_SHIFT, COMMAND_UNARY,
+    ARITHMETIC_COMPARE_SIGNED, ARITHMETIC_FORMAT_1, ARITHMETIC_FORMAT_2, COMMAND_BOOTSTRAP_HALT,
+    COMMAND_BOOTSTRAP_SET, COMMAND_CALL, COMMAND_COMPARE_BRANCH, COMMAND_FLIP_SIGNS,
+    COMMAND_FUNCTION, COMMAND_GOTO, COMMAND_IF_GOTO, COMMAND_INIT_LOCALS_LOOP, COMMAND_LABEL,
+    COMMAND_POP, COMMAND_POP_DIRECT, COMMAND_POP_FIXED, COMMAND_POP_SMALL_INDEX, COMMAND_PUSH,
+    COMMAND_PUSH_DIRECT, COMMAND_PUSH_LITERAL, COMMAND_PUSH_SMALL_INDEX, COMMAND_RETURN,
+    COMMAND_RETURN_LITE, COMMAND_ROUTINE_OPERANDS, COMMAND_ROUTINE_RESULT, COMMAND_TAIL_CALL,
+    COMMAND_UNARY, COMMAND_XOR,
 };
 
+use crate::backend::Backend;
 use crate::instructions::{
-    ArithmeticType, BinaryArithmeticOperator, Call, Function, Instruction, Label, Pop, Push,
-    Segment, ShiftArithmeticOperator, UnaryArithmeticOperator,
+    ArithmeticType, BinaryArithmeticOperator, Call, ExtendedArithmeticOperator, FrameKind,
+    Function, Instruction, Label, Module, Pop, Push, Segment, ShiftArithmeticOperator,
+    UnaryArithmeticOperator,
 };
+use crate::memory_layout::{io_address, MemoryLayout};
+use crate::routines::{
+    create_extended_routine_call, create_routine_call, create_routines, Routine,
+};
+use crate::{frames, intrinsics, register_backend, stack_batch, stack_cache};
+
+/// Functions with more locals than this zero them in a loop instead of
+/// pushing each one. The loop costs 12 instructions against 4 per unrolled
+/// push, so below this the unrolled form is kept for its speed.
+const UNROLLED_LOCALS_LIMIT: u16 = 4;
+
+/// Frame slots this close to a pointer are reached by stepping A one address
+/// at a time, which is cheaper than adding the offset.
+const MAX_ADDRESS_STEPS: u16 = 3;
+
+/// How the generated code moves values between the VM stack and the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackStrategy {
+    /// Every push and pop goes through the stack in memory.
+    Memory,
+    /// The top of the stack is kept in D across instructions when possible.
+    CacheTop,
+    /// SP is only written back at basic block exits, with stack slots
+    /// addressed relative to the SP stored in memory in between.
+    BatchPointer,
+    /// Functions are lowered to a register IR and values are kept in free
+    /// RAM registers where possible. See `register_backend::compile`.
+    Registers,
+}
+
+impl StackStrategy {
+    pub fn from(strategy: &str) -> Option<StackStrategy> {
+        match strategy {
+            "memory" => Some(StackStrategy::Memory),
+            "cache-top" => Some(StackStrategy::CacheTop),
+            "batch-sp" => Some(StackStrategy::BatchPointer),
+            "registers" => Some(StackStrategy::Registers),
+            _ => None,
+        }
+    }
+}
+
+/// The Hack CPU the generated code runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetProfile {
+    /// The nand2tetris CPU, as run by the stock CPUEmulator.
+    Standard,
+    /// The CPU with the `<<` and `>>` shift computations.
+    Extended,
+}
+
+impl TargetProfile {
+    pub fn from(profile: &str) -> Option<TargetProfile> {
+        match profile {
+            "standard" => Some(TargetProfile::Standard),
+            "extended" => Some(TargetProfile::Extended),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CodegenOptions {
+    pub stack_strategy: StackStrategy,
+    pub profile: TargetProfile,
+    /// The `temp` indices the register strategy may use as registers, which
+    /// must be unused by the whole program. See `register_backend::unused_temps`.
+    pub register_temps: Vec<u16>,
+    /// Whether calls to the OS functions in `intrinsics` become asm of their
+    /// own.
+    pub intrinsics: bool,
+}
+
+impl Default for CodegenOptions {
+    fn default() -> Self {
+        Self {
+            stack_strategy: StackStrategy::Memory,
+            profile: TargetProfile::Extended,
+            register_temps: vec![],
+            intrinsics: true,
+        }
+    }
+}
 
-pub fn compile(instructions: Vec<Instruction>, file_name: &str) -> Vec<String> {
+pub fn compile(
+    instructions: Vec<Instruction>,
+    file_name: &str,
+    layout: &MemoryLayout,
+    options: &CodegenOptions,
+) -> Vec<String> {
+    match options.stack_strategy {
+        StackStrategy::Memory => compile_in_memory(instructions, file_name, layout, options),
+        StackStrategy::CacheTop => stack_cache::compile(instructions, file_name, layout, options),
+        StackStrategy::BatchPointer => {
+            stack_batch::compile(instructions, file_name, layout, options)
+        }
+        StackStrategy::Registers => {
+            register_backend::compile(instructions, file_name, layout, options)
+        }
+    }
+}
+
+fn compile_in_memory(
+    instructions: Vec<Instruction>,
+    file_name: &str,
+    layout: &MemoryLayout,
+    options: &CodegenOptions,
+) -> Vec<String> {
     let mut result: Vec<String> = vec![];
-    let mut comparison_count: u16 = 0;
-    for instruction in instructions {
-        let compiled_instruction: Option<String> = match instruction {
-            Instruction::CArithmetic(number_of_operands) => Some(create_arithmetic_operator(
-                number_of_operands,
-                &mut comparison_count,
-            )),
-            Instruction::CPush(push) => create_push_operator(&push, file_name),
-            Instruction::CPop(pop) => create_pop_operator(&pop, file_name),
-            Instruction::CLabel(ref label) => create_label_operator(label),
-            Instruction::CIf(ref label) => create_if_operator(label),
-            Instruction::CGoto(ref label) => create_goto_operator(label),
-            Instruction::CCall(ref call) => create_call_operator(call),
-            Instruction::CFunction(ref function) => create_function_operator(function),
-            Instruction::CReturn => create_return_operator(),
+    let mut state = ModuleState::new();
+    let mut position: usize = 0;
+    while position < instructions.len() {
+        let (instruction_asm, consumed) = compile_in_memory_at(
+            &instructions,
+            position,
+            file_name,
+            layout,
+            options,
+            &mut state,
+        );
+        result.push(instruction_asm);
+        position += consumed;
+    }
+    result
+}
+
+/// What the memory strategy keeps track of while translating a module.
+struct ModuleState {
+    comparison_count: u16,
+    current_frame: FrameKind,
+}
+
+impl ModuleState {
+    fn new() -> Self {
+        Self {
+            comparison_count: 0,
+            current_frame: FrameKind::Full,
+        }
+    }
+}
+
+/// Translates the instruction at `position` with the memory strategy, along
+/// with the instructions after it that combine with it. Returns the asm and
+/// the number of instructions translated.
+fn compile_in_memory_at(
+    instructions: &[Instruction],
+    position: usize,
+    file_name: &str,
+    layout: &MemoryLayout,
+    options: &CodegenOptions,
+    state: &mut ModuleState,
+) -> (String, usize) {
+    if let Some((call, consumed)) = match_tail_call(&instructions[position..], options) {
+        return (
+            create_tail_call_operator(call, state.current_frame),
+            consumed,
+        );
+    }
+    if let Some((branch, consumed)) = match_compare_branch(&instructions[position..]) {
+        let asm = create_compare_branch_operator(&branch, file_name, &mut state.comparison_count);
+        return (asm, consumed);
+    }
+    if let Some((value, consumed)) = match_literal_push(&instructions[position..]) {
+        return (COMMAND_PUSH_LITERAL.replace("VALUE", value), consumed);
+    }
+    let instruction = &instructions[position];
+    let compiled_instruction: Option<String> = match instruction {
+        Instruction::CArithmetic(number_of_operands) => Some(create_arithmetic_operator(
+            *number_of_operands,
+            file_name,
+            options.profile,
+            &mut state.comparison_count,
+        )),
+        Instruction::CPush(push) => create_push_operator(push, file_name, layout),
+        Instruction::CPop(pop) => create_pop_operator(pop, file_name, layout),
+        Instruction::CLabel(label) => create_label_operator(label),
+        Instruction::CIf(label) => create_if_operator(label),
+        Instruction::CGoto(label) => create_goto_operator(label),
+        Instruction::CCall(call) => match intrinsics::find(call, options) {
+            Some(intrinsic) => {
+                Some(intrinsic.create(file_name, options.profile, &mut state.comparison_count))
+            }
+            None => create_call_operator(call),
+        },
+        Instruction::CFunction(function) => {
+            state.current_frame = function.frame;
+            create_function_operator(function, layout)
+        }
+        Instruction::CReturn => create_return_operator(layout, state.current_frame),
+        Instruction::CAsm(asm) => create_asm_operator(asm),
+    };
+    match compiled_instruction {
+        Some(instruction_asm) => (instruction_asm, 1),
+        None => panic!("Couldn't compile instruction {:?}", instruction),
+    }
+}
+
+/// The Hack assembly target. With the memory strategy modules are translated
+/// instruction by instruction, while the other strategies look at whole
+/// modules.
+pub struct HackBackend<'a> {
+    layout: &'a MemoryLayout,
+    codegen: CodegenOptions,
+    module_state: ModuleState,
+    /// The shared routines the program calls, see `create_routines`.
+    routines: String,
+}
+
+impl<'a> HackBackend<'a> {
+    pub fn new(layout: &'a MemoryLayout, codegen: &CodegenOptions) -> Self {
+        Self {
+            layout,
+            codegen: codegen.clone(),
+            module_state: ModuleState::new(),
+            routines: String::new(),
+        }
+    }
+}
+
+impl Backend for HackBackend<'_> {
+    fn file_extension(&self) -> &'static str {
+        "asm"
+    }
+
+    fn supports_asm(&self) -> bool {
+        true
+    }
+
+    fn begin_program(&mut self, modules: &[Module]) -> String {
+        if self.codegen.stack_strategy == StackStrategy::Registers {
+            self.codegen.register_temps = register_backend::unused_temps(modules);
+        }
+        self.routines = create_routines(modules, &self.codegen);
+        let entry_frame = frames::entry_frame(modules, &self.layout.entry_point);
+        create_bootstrap_code(self.layout, entry_frame)
+    }
+
+    fn begin_module(&mut self, _module: &Module) -> String {
+        self.module_state = ModuleState::new();
+        String::new()
+    }
+
+    fn emit_instruction(&mut self, module: &Module, position: usize) -> (String, usize) {
+        let (asm, consumed) = if self.codegen.stack_strategy != StackStrategy::Memory {
+            let instructions = module.instructions[position..].to_vec();
+            let asm = compile(instructions, &module.name, self.layout, &self.codegen).concat();
+            (asm, module.instructions.len() - position)
+        } else {
+            compile_in_memory_at(
+                &module.instructions,
+                position,
+                &module.name,
+                self.layout,
+                &self.codegen,
+                &mut self.module_state,
+            )
         };
-        match compiled_instruction {
-            Some(instruction_asm) => result.push(instruction_asm),
-            None => panic!("Couldn't compile instruction {:?}", instruction),
+        check_profile(&asm, &module.name, self.codegen.profile);
+        (asm, consumed)
+    }
+
+    fn end_program(&mut self) -> String {
+        std::mem::take(&mut self.routines)
+    }
+}
+
+/// Recognises pushes of values that Hack can write to memory directly: the
+/// constants 0 and 1, and -1 in the `push constant 0; not` form Jack uses for
+/// `true` or as `push constant 1; neg`.
+fn match_literal_push(instructions: &[Instruction]) -> Option<(&'static str, usize)> {
+    let constant = match instructions.first() {
+        Some(Instruction::CPush(Push {
+            segment: Segment::Constant,
+            index: constant @ (0 | 1),
+        })) => *constant,
+        _ => return None,
+    };
+    match (constant, instructions.get(1)) {
+        (
+            0,
+            Some(Instruction::CArithmetic(ArithmeticType::Unary(UnaryArithmeticOperator::Not))),
+        )
+        | (
+            1,
+            Some(Instruction::CArithmetic(ArithmeticType::Unary(UnaryArithmeticOperator::Negate))),
+        ) => Some(("-1", 2)),
+        (0, _) => Some(("0", 1)),
+        _ => Some(("1", 1)),
+    }
+}
+
+/// A comparison whose result is consumed straight away by an `if-goto`,
+/// optionally through a `not`.
+pub(crate) struct CompareBranch<'a> {
+    pub(crate) operator: BinaryArithmeticOperator,
+    pub(crate) negated: bool,
+    pub(crate) label: &'a Label,
+}
+
+/// Recognises `eq/gt/lt` followed by `if-goto`, or by `not` and `if-goto`,
+/// and returns the fused branch along with the number of instructions it covers.
+pub(crate) fn match_compare_branch(
+    instructions: &[Instruction],
+) -> Option<(CompareBranch<'_>, usize)> {
+    let operator = match instructions.first() {
+        Some(Instruction::CArithmetic(ArithmeticType::Binary(
+            operator @ (BinaryArithmeticOperator::Eq
+            | BinaryArithmeticOperator::Gt
+            | BinaryArithmeticOperator::Lt),
+        ))) => *operator,
+        _ => return None,
+    };
+    let (negated, label) = match instructions.get(1..3) {
+        Some(
+            [Instruction::CArithmetic(ArithmeticType::Unary(UnaryArithmeticOperator::Not)), Instruction::CIf(label)],
+        ) => (true, label),
+        _ => match instructions.get(1) {
+            Some(Instruction::CIf(label)) => (false, label),
+            _ => return None,
+        },
+    };
+    let consumed = if negated { 3 } else { 2 };
+    Some((
+        CompareBranch {
+            operator,
+            negated,
+            label,
+        },
+        consumed,
+    ))
+}
+
+impl CompareBranch<'_> {
+    /// The jump that is taken when the branch should be followed, given the
+    /// sign of x - y in D.
+    pub(crate) fn jump_type(&self) -> &'static str {
+        match (self.operator, self.negated) {
+            (BinaryArithmeticOperator::Eq, false) => "JEQ",
+            (BinaryArithmeticOperator::Eq, true) => "JNE",
+            (BinaryArithmeticOperator::Gt, false) => "JGT",
+            (BinaryArithmeticOperator::Gt, true) => "JLE",
+            (BinaryArithmeticOperator::Lt, false) => "JLT",
+            (BinaryArithmeticOperator::Lt, true) => "JGE",
+            (operator, _) => panic!("Cannot branch on {:?}", operator),
         }
     }
-    result
 }
 
-fn create_arithmetic_operator(
+pub(crate) fn create_compare_branch_operator(
+    branch: &CompareBranch,
+    file_name: &str,
+    comparison_count: &mut u16,
+) -> String {
+    let compare = match branch.operator {
+        BinaryArithmeticOperator::Eq => ARITHMETIC_FORMAT_1.to_string() + "D=M-D\n",
+        _ => {
+            *comparison_count += 1;
+            ARITHMETIC_COMPARE_SIGNED.replace(
+                "JUMP_NUMBER",
+                &comparison_label(file_name, *comparison_count),
+            )
+        }
+    };
+    compare
+        + &COMMAND_COMPARE_BRANCH
+            .replace("LABEL", &branch.label.extract_label_name())
+            .replace("JUMP_TYPE", branch.jump_type())
+}
+
+pub(crate) fn create_arithmetic_operator(
     arithmetic_operator: ArithmeticType,
+    file_name: &str,
+    profile: TargetProfile,
     comparison_count: &mut u16,
 ) -> String {
     match arithmetic_operator {
@@ -45,59 +395,237 @@ fn create_arithmetic_operator(
             UnaryArithmeticOperator::Not => COMMAND_UNARY.replace("{}", "!"),
         },
         ArithmeticType::Binary(operator) => match operator {
-            BinaryArithmeticOperator::Add => ARITHMETIC_FORMAT_1.to_string() + "M=M+D\n",
+            BinaryArithmeticOperator::Add => ARITHMETIC_FORMAT_1.to_string() + "M=D+M\n",
             BinaryArithmeticOperator::Subtract => ARITHMETIC_FORMAT_1.to_string() + "M=M-D\n",
-            BinaryArithmeticOperator::And => ARITHMETIC_FORMAT_1.to_string() + "M=M&D\n",
-            BinaryArithmeticOperator::Or => ARITHMETIC_FORMAT_1.to_string() + "M=M|D\n",
+            BinaryArithmeticOperator::And => ARITHMETIC_FORMAT_1.to_string() + "M=D&M\n",
+            BinaryArithmeticOperator::Or => ARITHMETIC_FORMAT_1.to_string() + "M=D|M\n",
             BinaryArithmeticOperator::Gt => {
                 *comparison_count += 1;
-                ARITHMETIC_FORMAT_1.to_string()
-                    + &ARITHMETIC_FORMAT_2
-                        .replace("JUMP_TYPE", "JLE")
-                        .replace("JUMP_NUMBER", &*comparison_count.to_string())
+                (ARITHMETIC_COMPARE_SIGNED.to_string()
+                    + &ARITHMETIC_FORMAT_2.replace("JUMP_TYPE", "JLE"))
+                    .replace(
+                        "JUMP_NUMBER",
+                        &comparison_label(file_name, *comparison_count),
+                    )
             }
             BinaryArithmeticOperator::Eq => {
+                // x - y is zero exactly when x == y, even if the subtraction wraps
                 *comparison_count += 1;
-                ARITHMETIC_FORMAT_1.to_string()
-                    + &ARITHMETIC_FORMAT_2
-                        .replace("JUMP_TYPE", "JNE")
-                        .replace("JUMP_NUMBER", &*comparison_count.to_string())
+                (ARITHMETIC_FORMAT_1.to_string()
+                    + "D=M-D\n"
+                    + &ARITHMETIC_FORMAT_2.replace("JUMP_TYPE", "JNE"))
+                    .replace(
+                        "JUMP_NUMBER",
+                        &comparison_label(file_name, *comparison_count),
+                    )
             }
             BinaryArithmeticOperator::Lt => {
                 *comparison_count += 1;
-                ARITHMETIC_FORMAT_1.to_string()
-                    + &ARITHMETIC_FORMAT_2
-                        .replace("JUMP_TYPE", "JGE")
-                        .replace("JUMP_NUMBER", &*comparison_count.to_string())
+                (ARITHMETIC_COMPARE_SIGNED.to_string()
+                    + &ARITHMETIC_FORMAT_2.replace("JUMP_TYPE", "JGE"))
+                    .replace(
+                        "JUMP_NUMBER",
+                        &comparison_label(file_name, *comparison_count),
+                    )
             }
         },
-        ArithmeticType::Shift(operator) => match operator {
-            ShiftArithmeticOperator::ShiftLeft => COMMAND_SHIFT.replace("{}", "<<"),
-            ShiftArithmeticOperator::ShiftRight => COMMAND_SHIFT.replace("{}", ">>"),
-        },
+        ArithmeticType::Shift(operator, amount) => create_shift_at(
+            "@SP\nA=M-1\n",
+            operator,
+            amount,
+            profile,
+            file_name,
+            comparison_count,
+        ),
+        ArithmeticType::Extended(operator) => {
+            create_extended_operator(operator, file_name, profile, comparison_count)
+        }
     }
 }
 
-pub fn create_bootstrap_code() -> String {
-    let mut code: String = String::from(
-        "@256   // BOOTSTRAP - set stack pointer
-D=A
-@SP
-M=D
-",
-    );
-    code.push_str(
-        &create_call_operator(&Call::new(
-            &String::from("Sys.init"),
-            &String::from("Sys.init&ret.0"),
-            0,
-        ))
-        .unwrap(),
+/// Shifts the word `address` points A at in place.
+pub(crate) fn create_shift_at(
+    address: &str,
+    operator: ShiftArithmeticOperator,
+    amount: u16,
+    profile: TargetProfile,
+    file_name: &str,
+    comparison_count: &mut u16,
+) -> String {
+    let shift = match (profile, operator) {
+        (TargetProfile::Extended, ShiftArithmeticOperator::ShiftLeft) => {
+            "M=M<<\n".repeat(amount as usize)
+        }
+        (TargetProfile::Extended, ShiftArithmeticOperator::ShiftRight) => {
+            "M=M>>\n".repeat(amount as usize)
+        }
+        (TargetProfile::Standard, ShiftArithmeticOperator::ShiftLeft) => {
+            "D=M\nM=D+M\n".repeat(amount as usize)
+        }
+        (TargetProfile::Standard, ShiftArithmeticOperator::ShiftRight) => {
+            if amount == 0 {
+                return String::new();
+            }
+            return String::from("// SHIFT command\n")
+                + address
+                + "D=M\n"
+                + &create_shift_on_d(operator, amount, profile, file_name, comparison_count)
+                + address
+                + "M=D\n";
+        }
+    };
+    String::from("// SHIFT command\n") + address + &shift
+}
+
+/// Shifts D in place, leaving A undefined.
+pub(crate) fn create_shift_on_d(
+    operator: ShiftArithmeticOperator,
+    amount: u16,
+    profile: TargetProfile,
+    file_name: &str,
+    comparison_count: &mut u16,
+) -> String {
+    match (profile, operator) {
+        (TargetProfile::Extended, ShiftArithmeticOperator::ShiftLeft) => {
+            "D=D<<\n".repeat(amount as usize)
+        }
+        (TargetProfile::Extended, ShiftArithmeticOperator::ShiftRight) => {
+            "D=D>>\n".repeat(amount as usize)
+        }
+        (TargetProfile::Standard, ShiftArithmeticOperator::ShiftLeft) => {
+            "A=D\nD=D+A\n".repeat(amount as usize)
+        }
+        (TargetProfile::Standard, ShiftArithmeticOperator::ShiftRight) if amount > 0 => {
+            String::from("@R13\nM=D\n")
+                + &create_routine_call(Routine::ShiftRight(amount), file_name, comparison_count)
+        }
+        (TargetProfile::Standard, ShiftArithmeticOperator::ShiftRight) => String::new(),
+    }
+}
+
+/// Applies an extended operator to the top two values of the stack in memory.
+/// Unsigned comparisons become signed ones, while multiplication and division
+/// go through their routines.
+pub(crate) fn create_extended_operator(
+    operator: ExtendedArithmeticOperator,
+    file_name: &str,
+    profile: TargetProfile,
+    comparison_count: &mut u16,
+) -> String {
+    let mut signed = |operator| {
+        COMMAND_FLIP_SIGNS.to_string()
+            + &create_arithmetic_operator(
+                ArithmeticType::Binary(operator),
+                file_name,
+                profile,
+                comparison_count,
+            )
+    };
+    match operator {
+        ExtendedArithmeticOperator::Xor => COMMAND_XOR.to_string(),
+        ExtendedArithmeticOperator::UnsignedLt => signed(BinaryArithmeticOperator::Lt),
+        ExtendedArithmeticOperator::UnsignedGt => signed(BinaryArithmeticOperator::Gt),
+        ExtendedArithmeticOperator::Multiply
+        | ExtendedArithmeticOperator::Divide
+        | ExtendedArithmeticOperator::Modulo => {
+            COMMAND_ROUTINE_OPERANDS.to_string()
+                + &create_extended_routine_call(operator, file_name, comparison_count)
+                + COMMAND_ROUTINE_RESULT
+        }
+    }
+}
+
+/// Panics on any instruction in the module's asm that the profile's CPU
+/// lacks. The code generators lower what they can for the standard profile,
+/// so this catches the extended features that have no plain Hack form.
+fn check_profile(asm: &str, module_name: &str, profile: TargetProfile) {
+    if profile == TargetProfile::Extended {
+        return;
+    }
+    for line in asm.lines() {
+        let instruction = line.split("//").next().unwrap().trim();
+        if instruction.contains("<<") || instruction.contains(">>") {
+            panic!(
+                "{}: `{}` needs the extended Hack CPU, which --profile=standard does not have",
+                module_name, instruction
+            );
+        }
+    }
+}
+
+/// Comparison labels are numbered per file, so the file name keeps them unique
+/// across the whole program.
+pub(crate) fn comparison_label(file_name: &str, comparison_count: u16) -> String {
+    format!("{}.{}", file_name, comparison_count)
+}
+
+/// `entry_frame` has to match the frame kind the entry function returns with.
+pub fn create_bootstrap_code(layout: &MemoryLayout, entry_frame: FrameKind) -> String {
+    let mut code: String = String::new();
+    let registers = [
+        // A validated stack base is below the screen, so it fits an A-instruction
+        ("SP", layout.stack_base as i16),
+        ("LCL", layout.initial_lcl),
+        ("ARG", layout.initial_arg),
+        ("THIS", layout.initial_this),
+        ("THAT", layout.initial_that),
+    ];
+    for (register, value) in registers {
+        code.push_str(
+            &COMMAND_BOOTSTRAP_SET
+                .replace("LOAD_VALUE", &create_load_constant(value))
+                .replace("REGISTER", register),
+        );
+    }
+    let mut entry_call = Call::new(
+        &layout.entry_point,
+        &format!("{}&ret.0", layout.entry_point),
+        0,
     );
-    return code;
+    entry_call.frame = entry_frame;
+    code.push_str(&create_call_operator(&entry_call).unwrap());
+    if layout.halt_after_entry {
+        code.push_str(COMMAND_BOOTSTRAP_HALT);
+    }
+    code
 }
 
-fn create_push_operator(push: &Push, file_name: &str) -> Option<String> {
+/// Loads a signed value into D. A-instructions can only hold 0..=32767, so
+/// negative values are loaded as their magnitude and negated.
+pub(crate) fn create_load_constant(value: i16) -> String {
+    match value {
+        i16::MIN => String::from("@32767\nD=-A\nD=D-1\n"),
+        v if v < 0 => format!("@{}\nD=-A\n", -v),
+        v => format!("@{}\nD=A\n", v),
+    }
+}
+
+fn create_push_operator(push: &Push, file_name: &str, layout: &MemoryLayout) -> Option<String> {
+    create_specialized_push_operator(push)
+        .or_else(|| create_generic_push_operator(push, file_name, layout))
+}
+
+/// Shorter forms for pushes whose operands make part of the generic template
+/// unnecessary. Returns None when the generic form has to be used.
+fn create_specialized_push_operator(push: &Push) -> Option<String> {
+    match (push.segment, push.index) {
+        (Segment::Constant, 0 | 1) => {
+            Some(COMMAND_PUSH_LITERAL.replace("VALUE", &push.index.to_string()))
+        }
+        (Segment::Local | Segment::Argument | Segment::This | Segment::That, 0 | 1) => Some(
+            COMMAND_PUSH_SMALL_INDEX
+                .replace("SEGMENT", &push.segment.to_string())
+                .replace("ADDRESS", small_index_address(push.index)),
+        ),
+        _ => None,
+    }
+}
+
+fn create_generic_push_operator(
+    push: &Push,
+    file_name: &str,
+    layout: &MemoryLayout,
+) -> Option<String> {
     let asm = match push.segment {
         Segment::Local => COMMAND_PUSH
             .replace("SEGMENT", "LCL")
@@ -111,13 +639,15 @@ fn create_push_operator(push: &Push, file_name: &str) -> Option<String> {
         Segment::That => COMMAND_PUSH
             .replace("SEGMENT", "THAT")
             .replace("INDEX", &push.index.to_string()),
-        Segment::Pointer => {
-            let pointer_register = if push.index == 0 { "THIS" } else { "THAT" };
-            COMMAND_PUSH_DIRECT.replace("SEGMENT", pointer_register)
-        }
-        Segment::Temp => COMMAND_PUSH
-            .replace("SEGMENT", "R5")
-            .replace("INDEX", &(push.index + 5).to_string()), // Temp base starts at 5
+        Segment::Pointer => COMMAND_PUSH_DIRECT
+            .replace("ORIGIN", "M")
+            .replace("INDEX", pointer_register(push.index)),
+        Segment::Temp => COMMAND_PUSH_DIRECT
+            .replace("ORIGIN", "M")
+            .replace("INDEX", &layout.temp_address(push.index).to_string()),
+        Segment::Screen | Segment::Keyboard => COMMAND_PUSH_DIRECT
+            .replace("ORIGIN", "M")
+            .replace("INDEX", &io_register(push.segment, push.index)),
         Segment::Constant => COMMAND_PUSH_DIRECT
             .replace("ORIGIN", "A")
             .replace("INDEX", &push.index.to_string()),
@@ -147,7 +677,45 @@ fn create_call_push(push: &Push) -> Option<String> {
     Some(asm)
 }
 
-fn create_pop_operator(pop: &Pop, file_name: &str) -> Option<String> {
+fn create_pop_operator(pop: &Pop, file_name: &str, layout: &MemoryLayout) -> Option<String> {
+    create_specialized_pop_operator(pop, file_name, layout)
+        .or_else(|| create_generic_pop_operator(pop, file_name, layout))
+}
+
+/// Pops whose target address is known at compile time skip R13, as do pops
+/// into the first two slots of a pointer-based segment.
+fn create_specialized_pop_operator(
+    pop: &Pop,
+    file_name: &str,
+    layout: &MemoryLayout,
+) -> Option<String> {
+    match (pop.segment, pop.index) {
+        (Segment::Local | Segment::Argument | Segment::This | Segment::That, 0 | 1) => Some(
+            COMMAND_POP_SMALL_INDEX
+                .replace("SEGMENT", &pop.segment.to_string())
+                .replace("ADDRESS", small_index_address(pop.index)),
+        ),
+        (Segment::Temp, index) => {
+            Some(COMMAND_POP_FIXED.replace("ADDRESS", &layout.temp_address(index).to_string()))
+        }
+        (Segment::Pointer, index) => {
+            Some(COMMAND_POP_FIXED.replace("ADDRESS", pointer_register(index)))
+        }
+        (Segment::Screen, index) => {
+            Some(COMMAND_POP_FIXED.replace("ADDRESS", &io_register(Segment::Screen, index)))
+        }
+        (Segment::Static, index) => {
+            Some(COMMAND_POP_FIXED.replace("ADDRESS", &format!("{}.{}", file_name, index)))
+        }
+        _ => None,
+    }
+}
+
+fn create_generic_pop_operator(
+    pop: &Pop,
+    file_name: &str,
+    layout: &MemoryLayout,
+) -> Option<String> {
     let asm = match pop.segment {
         Segment::Local => COMMAND_POP
             .replace("SEGMENT", "LCL")
@@ -161,22 +729,50 @@ fn create_pop_operator(pop: &Pop, file_name: &str) -> Option<String> {
         Segment::That => COMMAND_POP
             .replace("SEGMENT", "THAT")
             .replace("INDEX", &pop.index.to_string()),
-        Segment::Temp => COMMAND_POP
-            .replace("SEGMENT", "R5")
-            .replace("INDEX", &(pop.index + 5).to_string()),
+        Segment::Temp => {
+            COMMAND_POP_DIRECT.replace("SEGMENT", &layout.temp_address(pop.index).to_string())
+        }
         Segment::Static => {
             COMMAND_POP_DIRECT.replace("SEGMENT", &format!("{}.{}", file_name, pop.index))
         }
-        Segment::Pointer => {
-            let pointer_register = if pop.index == 0 { "THIS" } else { "THAT" };
-            COMMAND_POP_DIRECT.replace("SEGMENT", pointer_register)
+        Segment::Pointer => COMMAND_POP_DIRECT.replace("SEGMENT", pointer_register(pop.index)),
+        Segment::Screen => {
+            COMMAND_POP_DIRECT.replace("SEGMENT", &io_register(pop.segment, pop.index))
         }
         Segment::Constant => panic!("Cannot pop from constant"),
+        Segment::Keyboard => panic!("Cannot pop to keyboard"),
     };
     Some(asm)
 }
 
-fn create_label_operator(label: &Label) -> Option<String> {
+/// Points A at base + index for a base address already in M.
+pub(crate) fn small_index_address(index: u16) -> &'static str {
+    if index == 0 {
+        "A=M\n"
+    } else {
+        "A=M+1\n"
+    }
+}
+
+/// `pointer 0` aliases THIS and `pointer 1` aliases THAT.
+pub(crate) fn pointer_register(index: u16) -> &'static str {
+    if index == 0 {
+        "THIS"
+    } else {
+        "THAT"
+    }
+}
+
+/// `screen i` is addressed off SCREEN and `keyboard 0` is KBD.
+pub(crate) fn io_register(segment: Segment, index: u16) -> String {
+    match (segment, index) {
+        (Segment::Screen, 0) => String::from("SCREEN"),
+        (Segment::Keyboard, 0) => String::from("KBD"),
+        _ => io_address(segment, index).to_string(),
+    }
+}
+
+pub(crate) fn create_label_operator(label: &Label) -> Option<String> {
     Some(COMMAND_LABEL.replace("LABEL_NAME", &label.extract_label_name()))
 }
 
@@ -188,14 +784,29 @@ fn create_if_operator(label: &Label) -> Option<String> {
     )
 }
 
-fn create_goto_operator(label: &Label) -> Option<String> {
+pub(crate) fn create_goto_operator(label: &Label) -> Option<String> {
     Some(COMMAND_GOTO.replace("LABEL", &label.extract_label_name()))
 }
 
-fn create_call_operator(call: &Call) -> Option<String> {
+fn frame_size(frame: FrameKind) -> i16 {
+    match frame {
+        FrameKind::Full => 5,
+        FrameKind::Lite => 3,
+    }
+}
+
+pub(crate) fn create_call_operator(call: &Call) -> Option<String> {
+    let (push_this, push_that) = match call.frame {
+        FrameKind::Full => (
+            create_call_push(&Push::new(Segment::This, 0)).unwrap(),
+            create_call_push(&Push::new(Segment::That, 0)).unwrap(),
+        ),
+        FrameKind::Lite => (String::new(), String::new()),
+    };
     Some(
         COMMAND_CALL
             .replace("FUNCTION_LABEL", &call.return_address)
+            .replace("FRAME_SIZE", &frame_size(call.frame).to_string())
             .replace("N_ARGS", &call.n_args.to_string())
             .replace("FUNCTION_NAME", &call.function_name)
             .replace(
@@ -208,23 +819,130 @@ fn create_call_operator(call: &Call) -> Option<String> {
                 // &create_push_operator(&Push::new(Segment::Argument, 0), "").unwrap(),
                 &create_call_push(&Push::new(Segment::Argument, 0)).unwrap(),
             )
-            .replace(
-                "PUSH_THIS",
-                // &create_push_operator(&Push::new(Segment::This, 0), "").unwrap(),
-                &create_call_push(&Push::new(Segment::This, 0)).unwrap(),
-            )
-            .replace(
-                "PUSH_THAT",
-                // &create_push_operator(&Push::new(Segment::That, 0), "").unwrap(),
-                &create_call_push(&Push::new(Segment::That, 0)).unwrap(),
-            ),
+            .replace("PUSH_THIS", &push_this)
+            .replace("PUSH_THAT", &push_that),
     )
 }
 
-fn create_function_operator(function: &Function) -> Option<String> {
+/// Passes an asm block through, see `Instruction::CAsm` for what it may do.
+pub(crate) fn create_asm_operator(asm: &[String]) -> Option<String> {
+    Some(
+        asm.iter()
+            .fold(String::from("// ASM block\n"), |code, line| {
+                code + line + "\n"
+            }),
+    )
+}
+
+/// Recognises a call whose result is returned straight away. Such a call can
+/// reuse the frame of the function making it instead of building a new one.
+/// Calls to intrinsics are left to their asm.
+pub(crate) fn match_tail_call<'a>(
+    instructions: &'a [Instruction],
+    options: &CodegenOptions,
+) -> Option<(&'a Call, usize)> {
+    match instructions {
+        [Instruction::CCall(call), Instruction::CReturn, ..]
+            if intrinsics::find(call, options).is_none() =>
+        {
+            Some((call, 2))
+        }
+        _ => None,
+    }
+}
+
+/// Compiles `call f n; return` into a jump that reuses the caller's frame.
+/// The callee's frame is staged above the stack, then the arguments and the
+/// frame are moved down to ARG, copying upwards so overlaps are safe.
+pub(crate) fn create_tail_call_operator(call: &Call, caller_frame: FrameKind) -> String {
+    let caller_size = frame_size(caller_frame);
+    let callee_size = frame_size(call.frame);
+    let n_args = call.n_args as i16;
+
+    let mut save_frame = String::new();
+    for slot in 0..callee_size {
+        if slot < caller_size {
+            save_frame.push_str(&load_relative("LCL", slot - caller_size));
+        } else {
+            save_frame.push_str(&format!("@{}\nD=M\n", pointer_register(slot as u16 - 3)));
+        }
+        save_frame.push_str(&store_relative("SP", slot));
+    }
+    // A lite callee will not restore THIS and THAT, so the caller does it now
+    if caller_frame == FrameKind::Full && call.frame == FrameKind::Lite {
+        for slot in 3..5 {
+            save_frame.push_str(&load_relative("LCL", slot - caller_size));
+            save_frame.push_str(&format!("@{}\nM=D\n", pointer_register(slot as u16 - 3)));
+        }
+    }
+
+    let mut move_args = String::new();
+    for index in 0..n_args {
+        move_args.push_str(&load_relative("SP", index - n_args));
+        move_args.push_str(&store_relative("ARG", index));
+    }
+
+    let mut restore_frame = String::new();
+    for slot in 0..callee_size {
+        restore_frame.push_str(&load_relative("SP", slot));
+        restore_frame.push_str(&store_relative("LCL", slot - callee_size));
+    }
+
+    COMMAND_TAIL_CALL
+        .replace("SAVE_FRAME", &save_frame)
+        .replace("MOVE_ARGS", &move_args)
+        .replace("RESTORE_FRAME", &restore_frame)
+        .replace("LCL_OFFSET", &(n_args + callee_size).to_string())
+        .replace("N_ARGS", &n_args.to_string())
+        .replace("FRAME_SIZE", &callee_size.to_string())
+        .replace("FUNCTION_NAME", &call.function_name)
+}
+
+/// Loads the value `offset` addresses away from where `pointer` points into D.
+fn load_relative(pointer: &str, offset: i16) -> String {
+    let distance = offset.unsigned_abs();
+    if distance <= MAX_ADDRESS_STEPS {
+        let step = if offset < 0 { "A=A-1\n" } else { "A=A+1\n" };
+        format!("@{}\nA=M\n{}D=M\n", pointer, step.repeat(distance as usize))
+    } else {
+        let address = if offset < 0 { "A=M-D" } else { "A=D+M" };
+        format!("@{}\nD=A\n@{}\n{}\nD=M\n", distance, pointer, address)
+    }
+}
+
+/// Stores D `offset` addresses away from where `pointer` points. Offsets too
+/// far to step to go through R13.
+fn store_relative(pointer: &str, offset: i16) -> String {
+    let distance = offset.unsigned_abs();
+    if distance <= MAX_ADDRESS_STEPS {
+        let step = if offset < 0 { "A=A-1\n" } else { "A=A+1\n" };
+        format!("@{}\nA=M\n{}M=D\n", pointer, step.repeat(distance as usize))
+    } else {
+        let address = if offset < 0 { "D=D-A" } else { "D=D+A" };
+        format!(
+            "@R13\nM=D\n@{}\nD=M\n@{}\n{}\n@R13\nD=D+M\nA=D-M\nM=D-A\n",
+            pointer, distance, address
+        )
+    }
+}
+
+pub(crate) fn create_function_operator(
+    function: &Function,
+    layout: &MemoryLayout,
+) -> Option<String> {
     let mut setup: String = String::new();
-    for _ in 0..function.n_args {
-        setup.push_str(&create_push_operator(&Push::new(Segment::Constant, 0), "").unwrap());
+    if function.n_args > UNROLLED_LOCALS_LIMIT {
+        setup.push_str(
+            &COMMAND_INIT_LOCALS_LOOP
+                .replace("FUNCTION_NAME", &function.function_name)
+                .replace("N_LOCALS", &function.n_args.to_string()),
+        );
+    } else {
+        for _ in 0..function.n_args {
+            setup.push_str(
+                &create_push_operator(&Push::new(Segment::Constant, 0), "", layout).unwrap(),
+            );
+        }
     }
     Some(
         COMMAND_FUNCTION
@@ -233,9 +951,1001 @@ fn create_function_operator(function: &Function) -> Option<String> {
     )
 }
 
-fn create_return_operator() -> Option<String> {
-    Some(COMMAND_RETURN.replace(
+pub(crate) fn create_return_operator(layout: &MemoryLayout, frame: FrameKind) -> Option<String> {
+    let template = match frame {
+        FrameKind::Full => COMMAND_RETURN,
+        FrameKind::Lite => COMMAND_RETURN_LITE,
+    };
+    Some(template.replace(
         "POP_ARG",
-        &create_pop_operator(&Pop::new(Segment::Argument, 0), "").unwrap(),
+        &create_pop_operator(&Pop::new(Segment::Argument, 0), "", layout).unwrap(),
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::backend;
+    use crate::hack_emulator::HackEmulator;
+    use crate::test_programs::{main_layout, parse_modules, parse_source, run_hack};
+
+    const EDGE_VALUES: [i16; 9] = [i16::MIN, -32767, -1000, -1, 0, 1, 1000, 32766, i16::MAX];
+
+    fn push_value(value: i16) -> Vec<Instruction> {
+        let arithmetic = |operator| Instruction::CArithmetic(ArithmeticType::Binary(operator));
+        let negate =
+            Instruction::CArithmetic(ArithmeticType::Unary(UnaryArithmeticOperator::Negate));
+        match value {
+            i16::MIN => vec![
+                Instruction::CPush(Push::new(Segment::Constant, i16::MAX as u16)),
+                negate,
+                Instruction::CPush(Push::new(Segment::Constant, 1)),
+                arithmetic(BinaryArithmeticOperator::Subtract),
+            ],
+            v if v < 0 => vec![
+                Instruction::CPush(Push::new(Segment::Constant, (-v) as u16)),
+                negate,
+            ],
+            v => vec![Instruction::CPush(Push::new(Segment::Constant, v as u16))],
+        }
+    }
+
+    const STACK_STRATEGIES: [StackStrategy; 4] = [
+        StackStrategy::Memory,
+        StackStrategy::CacheTop,
+        StackStrategy::BatchPointer,
+        StackStrategy::Registers,
+    ];
+
+    fn run(instructions: Vec<Instruction>, stack_strategy: StackStrategy) -> HackEmulator {
+        let options = CodegenOptions {
+            stack_strategy,
+            ..CodegenOptions::default()
+        };
+        run_with_options(instructions, &options)
+    }
+
+    /// Runs instructions up to a halt loop, with the routines they call after it.
+    fn run_with_options(instructions: Vec<Instruction>, options: &CodegenOptions) -> HackEmulator {
+        let routines = create_routines(&[Module::new("Test", instructions.clone())], options);
+        let asm = compile(instructions, "Test", &MemoryLayout::default(), options).concat();
+        let asm = asm + COMMAND_BOOTSTRAP_HALT + &routines;
+        let mut emulator = HackEmulator::with_profile(&asm, options.profile);
+        emulator.ram[0] = 256;
+        emulator.run(10_000);
+        emulator
+    }
+
+    /// Runs instructions that leave exactly one value on the stack under every
+    /// stack strategy, and returns that value once they all agree on it.
+    fn run_single_result(instructions: Vec<Instruction>) -> i16 {
+        let results: Vec<i16> = STACK_STRATEGIES
+            .iter()
+            .map(|strategy| {
+                let emulator = run(instructions.clone(), *strategy);
+                assert_eq!(
+                    emulator.ram[0], 257,
+                    "{:?} left the stack unbalanced",
+                    strategy
+                );
+                emulator.ram[256]
+            })
+            .collect();
+        assert!(
+            results.iter().all(|result| *result == results[0]),
+            "Stack strategies disagree: {:?}",
+            results
+        );
+        results[0]
+    }
+
+    fn run_binary(x: i16, y: i16, operator: BinaryArithmeticOperator) -> i16 {
+        let mut instructions = push_value(x);
+        instructions.extend(push_value(y));
+        instructions.push(Instruction::CArithmetic(ArithmeticType::Binary(operator)));
+        run_single_result(instructions)
+    }
+
+    #[test]
+    fn greater_than_handles_mixed_signs_without_overflow() {
+        for x in EDGE_VALUES {
+            for y in EDGE_VALUES {
+                let expected = if x > y { -1 } else { 0 };
+                assert_eq!(
+                    run_binary(x, y, BinaryArithmeticOperator::Gt),
+                    expected,
+                    "{} gt {}",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn less_than_handles_mixed_signs_without_overflow() {
+        for x in EDGE_VALUES {
+            for y in EDGE_VALUES {
+                let expected = if x < y { -1 } else { 0 };
+                assert_eq!(
+                    run_binary(x, y, BinaryArithmeticOperator::Lt),
+                    expected,
+                    "{} lt {}",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn equal_handles_extreme_values() {
+        for x in EDGE_VALUES {
+            for y in EDGE_VALUES {
+                let expected = if x == y { -1 } else { 0 };
+                assert_eq!(
+                    run_binary(x, y, BinaryArithmeticOperator::Eq),
+                    expected,
+                    "{} eq {}",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn standard_profile_shifts_match_extended_shifts() {
+        let values = EDGE_VALUES
+            .iter()
+            .chain(&[-16384, -12345, -3, 3, 12345, 16384]);
+        for value in values {
+            for (operator, amount) in [
+                (ShiftArithmeticOperator::ShiftLeft, 1),
+                (ShiftArithmeticOperator::ShiftLeft, 5),
+                (ShiftArithmeticOperator::ShiftRight, 1),
+                (ShiftArithmeticOperator::ShiftRight, 3),
+                (ShiftArithmeticOperator::ShiftRight, 15),
+            ] {
+                let expected = match operator {
+                    ShiftArithmeticOperator::ShiftLeft => value.wrapping_shl(amount as u32),
+                    ShiftArithmeticOperator::ShiftRight => value >> amount,
+                };
+                for profile in [TargetProfile::Standard, TargetProfile::Extended] {
+                    for stack_strategy in STACK_STRATEGIES {
+                        let mut instructions = push_value(*value);
+                        instructions.push(Instruction::CArithmetic(ArithmeticType::Shift(
+                            operator, amount,
+                        )));
+                        let options = CodegenOptions {
+                            stack_strategy,
+                            profile,
+                            ..CodegenOptions::default()
+                        };
+                        let emulator = run_with_options(instructions, &options);
+                        let context = (value, operator, amount, profile, stack_strategy);
+                        assert_eq!(emulator.ram[0], 257, "{:?}", context);
+                        assert_eq!(emulator.ram[256], expected, "{:?}", context);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn extended_operators_match_their_definitions() {
+        let values = EDGE_VALUES.iter().chain(&[-7, -2, 2, 7, 255]);
+        let as_flag = |condition: bool| if condition { -1 } else { 0 };
+        for x in values.clone() {
+            for y in values.clone() {
+                let (x, y) = (*x, *y);
+                for (operator, expected) in [
+                    (ExtendedArithmeticOperator::Multiply, x.wrapping_mul(y)),
+                    (
+                        ExtendedArithmeticOperator::Divide,
+                        x.checked_div(y)
+                            .unwrap_or(if y == 0 { 0 } else { i16::MIN }),
+                    ),
+                    (
+                        ExtendedArithmeticOperator::Modulo,
+                        x.checked_rem(y).unwrap_or(if y == 0 { x } else { 0 }),
+                    ),
+                    (ExtendedArithmeticOperator::Xor, x ^ y),
+                    (
+                        ExtendedArithmeticOperator::UnsignedLt,
+                        as_flag((x as u16) < (y as u16)),
+                    ),
+                    (
+                        ExtendedArithmeticOperator::UnsignedGt,
+                        as_flag((x as u16) > (y as u16)),
+                    ),
+                ] {
+                    for profile in [TargetProfile::Standard, TargetProfile::Extended] {
+                        for stack_strategy in STACK_STRATEGIES {
+                            let mut instructions = push_value(x);
+                            instructions.extend(push_value(y));
+                            instructions
+                                .push(Instruction::CArithmetic(ArithmeticType::Extended(operator)));
+                            let options = CodegenOptions {
+                                stack_strategy,
+                                profile,
+                                ..CodegenOptions::default()
+                            };
+                            let emulator = run_with_options(instructions, &options);
+                            let context = (x, operator, y, profile, stack_strategy);
+                            assert_eq!(emulator.ram[0], 257, "{:?}", context);
+                            assert_eq!(emulator.ram[256], expected, "{:?}", context);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    const INTRINSICS_PROGRAM: &str = "function Main.main 0
+        push constant 3000
+        pop pointer 1
+        push constant 1234
+        neg
+        push constant 77
+        call Math.multiply 2
+        pop that 0
+        push constant 1234
+        neg
+        push constant 77
+        call Math.divide 2
+        pop that 1
+        push constant 3005
+        push constant 42
+        call Memory.poke 2
+        pop temp 0
+        push constant 3005
+        call Memory.peek 1
+        push constant 1
+        add
+        pop that 2
+        push constant 100
+        push constant 7
+        call Main.quotient 2
+        pop that 3
+        push constant 0
+        return
+        function Main.quotient 0
+        push argument 0
+        push argument 1
+        call Math.divide 2
+        return
+        function Math.multiply 0
+        push argument 0
+        push argument 1
+        mul
+        return
+        function Math.divide 0
+        push argument 0
+        push argument 1
+        div
+        return
+        function Memory.peek 0
+        push argument 0
+        pop pointer 1
+        push that 0
+        return
+        function Memory.poke 0
+        push argument 0
+        pop pointer 1
+        push argument 1
+        pop that 0
+        push constant 0
+        return";
+
+    #[test]
+    fn intrinsics_match_the_functions_they_replace() {
+        let modules = parse_modules(&[("Main", INTRINSICS_PROGRAM)]);
+        let layout = MemoryLayout {
+            entry_point: String::from("Main.main"),
+            halt_after_entry: true,
+            ..MemoryLayout::default()
+        };
+        for intrinsics in [true, false] {
+            for stack_strategy in STACK_STRATEGIES {
+                let options = CodegenOptions {
+                    stack_strategy,
+                    intrinsics,
+                    ..CodegenOptions::default()
+                };
+                let asm =
+                    backend::compile_program(&mut HackBackend::new(&layout, &options), &modules);
+                assert_eq!(asm.contains("INTRINSIC Memory.poke"), intrinsics);
+                let mut emulator = HackEmulator::new(&asm);
+                emulator.run(100_000);
+                let context = (intrinsics, stack_strategy);
+                assert_eq!(emulator.ram[0], 257, "{:?}", context);
+                assert_eq!(
+                    emulator.ram[3000..3006],
+                    [-29482, -16, 43, 14, 0, 42],
+                    "{:?}",
+                    context
+                );
+            }
+        }
+    }
+
+    const ASM_PROGRAM: &str = "function Main.main 0
+        push constant 3000
+        pop pointer 1
+        push constant 21
+        asm {
+        @SP  // Double the top of the stack in place
+        A=M-1
+        D=M
+        M=D+M
+        }
+        pop that 0
+        asm {
+        @10
+        D=A
+        @R13
+        M=D
+        (Main.main$COUNT)
+        @THAT
+        A=M+1
+        M=M+1
+        @R13
+        MD=M-1
+        @Main.main$COUNT
+        D;JGT
+        }
+        asm \"D=0\"
+        push constant 0
+        return";
+
+    fn parse_program(source: &str) -> Vec<Module> {
+        parse_modules(&[("Main", source)])
+    }
+
+    /// Calls, returns, a loop on a materialised comparison, fused and plain
+    /// comparisons, and stores to that, static and temp.
+    const EQUIVALENCE_PROGRAM: &str = "function Main.main 2
+        push constant 3000
+        pop pointer 1
+        push constant 0
+        pop local 0
+        push constant 0
+        pop local 1
+        label LOOP
+        push local 0
+        push constant 5
+        lt
+        not
+        if-goto END
+        push local 0
+        call Main.double 1
+        push local 1
+        add
+        pop local 1
+        push local 0
+        push constant 1
+        add
+        pop local 0
+        goto LOOP
+        label END
+        push local 1
+        pop that 0
+        push local 1
+        push constant 20
+        eq
+        pop that 1
+        push constant 7
+        push local 1
+        gt
+        pop that 2
+        push local 1
+        push constant 100
+        neg
+        lt
+        pop static 0
+        push static 0
+        pop that 3
+        push pointer 1
+        pop temp 2
+        push temp 2
+        pop that 4
+        push local 0
+        return
+        function Main.double 0
+        push argument 0
+        push argument 0
+        add
+        return";
+
+    /// Runs a program from `Main.main` to the halt loop, returning the
+    /// emulator and the number of instructions executed.
+    fn run_program(modules: &[Module], options: &CodegenOptions) -> (HackEmulator, usize) {
+        let layout = MemoryLayout {
+            entry_point: String::from("Main.main"),
+            halt_after_entry: true,
+            ..MemoryLayout::default()
+        };
+        let asm = backend::compile_program(&mut HackBackend::new(&layout, options), modules);
+        let mut emulator = HackEmulator::with_profile(&asm, options.profile);
+        let steps = emulator.run(100_000);
+        assert!(steps < 100_000, "{:?} did not halt", options.stack_strategy);
+        (emulator, steps)
+    }
+
+    /// Runs the equivalence program under a stack strategy and checks it
+    /// leaves the same registers, statics and results as the memory strategy.
+    /// Returns the instructions executed and the ROM size under both.
+    fn compare_with_memory_strategy(stack_strategy: StackStrategy) -> [(usize, usize); 2] {
+        let modules = parse_program(EQUIVALENCE_PROGRAM);
+        let [memory, other] = [StackStrategy::Memory, stack_strategy].map(|stack_strategy| {
+            run_program(
+                &modules,
+                &CodegenOptions {
+                    stack_strategy,
+                    ..CodegenOptions::default()
+                },
+            )
+        });
+        // R13 to R15 are scratch registers each strategy uses its own way
+        for range in [0..13, 16..32, 3000..3005] {
+            assert_eq!(
+                memory.0.ram[range.clone()],
+                other.0.ram[range],
+                "{:?}",
+                stack_strategy
+            );
+        }
+        assert_eq!(memory.0.ram[256], 5);
+        assert_eq!(memory.0.ram[3000..3005], [20, -1, 0, 0, 3000]);
+        [memory, other].map(|(emulator, steps)| (steps, emulator.rom_size()))
+    }
+
+    #[test]
+    fn push_pointer_reads_this_and_that() {
+        let instructions = vec![
+            Instruction::CPush(Push::new(Segment::Constant, 3000)),
+            Instruction::CPop(Pop::new(Segment::Pointer, 0)),
+            Instruction::CPush(Push::new(Segment::Constant, 4000)),
+            Instruction::CPop(Pop::new(Segment::Pointer, 1)),
+            Instruction::CPush(Push::new(Segment::Pointer, 0)),
+            Instruction::CPush(Push::new(Segment::Pointer, 1)),
+            Instruction::CArithmetic(ArithmeticType::Binary(BinaryArithmeticOperator::Subtract)),
+        ];
+        assert_eq!(run_single_result(instructions), -1000);
+    }
+
+    #[test]
+    fn function_prologue_zeroes_every_local() {
+        let layout = MemoryLayout::default();
+        let limit = UNROLLED_LOCALS_LIMIT;
+        for n_locals in [0, 1, limit, limit + 1, 3 * limit] {
+            let function = Function::new(&String::from("Test.locals"), n_locals);
+            let prologue = create_function_operator(&function, &layout).unwrap();
+            assert_eq!(
+                prologue.contains("INIT_LOCALS"),
+                n_locals > limit,
+                "{} locals",
+                n_locals
+            );
+            let mut emulator = HackEmulator::new(&(prologue + COMMAND_BOOTSTRAP_HALT));
+            emulator.ram[0] = 300;
+            emulator.ram[1] = 300;
+            // Locals must not keep values left over from earlier frames
+            for value in &mut emulator.ram[290..320] {
+                *value = 7;
+            }
+            emulator.run(1_000);
+            let end = 300 + n_locals as usize;
+            assert_eq!(emulator.ram[0], end as i16, "{} locals", n_locals);
+            assert!(
+                emulator.ram[300..end].iter().all(|value| *value == 0),
+                "{} locals",
+                n_locals
+            );
+            assert!(
+                emulator.ram[290..300]
+                    .iter()
+                    .chain(&emulator.ram[end..320])
+                    .all(|value| *value == 7),
+                "{} locals",
+                n_locals
+            );
+        }
+    }
+
+    #[test]
+    fn cache_top_matches_the_memory_strategy() {
+        compare_with_memory_strategy(StackStrategy::CacheTop);
+    }
+
+    #[test]
+    fn batch_sp_matches_the_memory_strategy_in_fewer_instructions() {
+        let [(memory_steps, memory_size), (batch_steps, batch_size)] =
+            compare_with_memory_strategy(StackStrategy::BatchPointer);
+        assert!(batch_steps < memory_steps, "{} steps", batch_steps);
+        assert!(batch_size < memory_size, "{} instructions", batch_size);
+    }
+
+    #[test]
+    fn asm_blocks_pass_through_under_every_strategy() {
+        let modules = parse_program(ASM_PROGRAM);
+        assert!(matches!(
+            &modules[0].instructions[7],
+            Instruction::CAsm(asm) if asm == &["D=0"]
+        ));
+        let layout = MemoryLayout {
+            entry_point: String::from("Main.main"),
+            halt_after_entry: true,
+            ..MemoryLayout::default()
+        };
+        for stack_strategy in STACK_STRATEGIES {
+            let options = CodegenOptions {
+                stack_strategy,
+                ..CodegenOptions::default()
+            };
+            let asm = backend::compile_program(&mut HackBackend::new(&layout, &options), &modules);
+            let mut emulator = HackEmulator::new(&asm);
+            emulator.run(100_000);
+            assert_eq!(emulator.ram[0], 257, "{:?}", stack_strategy);
+            assert_eq!(emulator.ram[3000..3002], [42, 10], "{:?}", stack_strategy);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid asm instruction \"D=D+D\"")]
+    fn asm_blocks_reject_invalid_instructions() {
+        parse_program("function Main.main 0\nasm {\nD=D+D\n}");
+    }
+
+    #[test]
+    fn io_segments_address_screen_and_keyboard_under_every_strategy() {
+        let modules = parse_program(
+            "function Main.main 0
+            push constant 3000
+            pop pointer 1
+            push keyboard 0
+            pop screen 0
+            push constant 7
+            pop screen 8191
+            push screen 0
+            push screen 8191
+            add
+            pop that 0
+            push constant 0
+            return",
+        );
+        let layout = MemoryLayout {
+            entry_point: String::from("Main.main"),
+            halt_after_entry: true,
+            ..MemoryLayout::default()
+        };
+        for stack_strategy in STACK_STRATEGIES {
+            let options = CodegenOptions {
+                stack_strategy,
+                ..CodegenOptions::default()
+            };
+            let asm = backend::compile_program(&mut HackBackend::new(&layout, &options), &modules);
+            let mut emulator = HackEmulator::new(&asm);
+            emulator.ram[24576] = 75;
+            emulator.run(100_000);
+            assert_eq!(emulator.ram[16384], 75, "{:?}", stack_strategy);
+            assert_eq!(emulator.ram[24575], 7, "{:?}", stack_strategy);
+            assert_eq!(emulator.ram[3000], 82, "{:?}", stack_strategy);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Screen index 8192 in \"push screen 8192\" must be below 8192")]
+    fn io_segments_reject_indexes_out_of_bounds() {
+        parse_program("function Main.main 0\npush screen 8192");
+    }
+
+    #[test]
+    #[should_panic(expected = "Not a memory-mapped segment: static")]
+    fn io_address_names_the_segment_it_rejects() {
+        io_address(Segment::Static, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "needs the extended Hack CPU")]
+    fn standard_profile_rejects_extended_instructions() {
+        check_profile("@SP\nA=M-1\nM=M>>\n", "Test", TargetProfile::Standard);
+    }
+
+    #[test]
+    fn fused_compare_branch_matches_materialised_comparison() {
+        let label = |name: &str| Label::new(&String::from("Test.main"), &String::from(name));
+        for operator in [
+            BinaryArithmeticOperator::Eq,
+            BinaryArithmeticOperator::Gt,
+            BinaryArithmeticOperator::Lt,
+        ] {
+            for negated in [false, true] {
+                for x in EDGE_VALUES {
+                    for y in EDGE_VALUES {
+                        let mut instructions = push_value(x);
+                        instructions.extend(push_value(y));
+                        instructions
+                            .push(Instruction::CArithmetic(ArithmeticType::Binary(operator)));
+                        if negated {
+                            instructions.push(Instruction::CArithmetic(ArithmeticType::Unary(
+                                UnaryArithmeticOperator::Not,
+                            )));
+                        }
+                        instructions.extend([
+                            Instruction::CIf(label("TAKEN")),
+                            Instruction::CPush(Push::new(Segment::Constant, 0)),
+                            Instruction::CGoto(label("END")),
+                            Instruction::CLabel(label("TAKEN")),
+                            Instruction::CPush(Push::new(Segment::Constant, 1)),
+                            Instruction::CLabel(label("END")),
+                        ]);
+                        let materialised = run_binary(x, y, operator) != 0;
+                        let expected = (materialised != negated) as i16;
+                        assert_eq!(
+                            run_single_result(instructions),
+                            expected,
+                            "{} {:?} {} (negated: {})",
+                            x,
+                            operator,
+                            y,
+                            negated
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Runs a single push or pop template against a RAM with every segment
+    /// populated, returning the final RAM and the template's size.
+    fn run_template(asm: &str) -> (Vec<i16>, usize) {
+        let mut emulator = HackEmulator::new(asm);
+        emulator.ram[0] = 258;
+        for (register, base) in [(1, 300), (2, 400), (3, 3000), (4, 3010)] {
+            emulator.ram[register] = base;
+        }
+        for address in 5..13 {
+            emulator.ram[address] = 50 + address as i16;
+        }
+        for (base, length) in [(256, 2), (300, 4), (400, 4), (3000, 4), (3010, 4)] {
+            for offset in 0..length {
+                emulator.ram[base + offset] = 7 * (base + offset) as i16;
+            }
+        }
+        emulator.run(100);
+        let size = emulator.rom_size();
+        (emulator.ram, size)
+    }
+
+    #[test]
+    fn specialized_pushes_match_generic_forms() {
+        let layout = MemoryLayout::default();
+        let mut pushes = vec![
+            Push::new(Segment::Constant, 0),
+            Push::new(Segment::Constant, 1),
+        ];
+        for segment in [
+            Segment::Local,
+            Segment::Argument,
+            Segment::This,
+            Segment::That,
+        ] {
+            pushes.extend([Push::new(segment, 0), Push::new(segment, 1)]);
+        }
+        for push in pushes {
+            let specialized = create_specialized_push_operator(&push).unwrap();
+            let generic = create_generic_push_operator(&push, "Test", &layout).unwrap();
+            let (specialized_ram, specialized_size) = run_template(&specialized);
+            let (generic_ram, generic_size) = run_template(&generic);
+            assert_eq!(specialized_ram, generic_ram, "{:?}", push);
+            assert!(specialized_size < generic_size, "{:?}", push);
+        }
+    }
+
+    #[test]
+    fn specialized_pops_match_generic_forms() {
+        let layout = MemoryLayout::default();
+        let mut pops = vec![
+            Pop::new(Segment::Pointer, 0),
+            Pop::new(Segment::Pointer, 1),
+            Pop::new(Segment::Temp, 0),
+            Pop::new(Segment::Temp, 7),
+            Pop::new(Segment::Static, 3),
+        ];
+        for segment in [
+            Segment::Local,
+            Segment::Argument,
+            Segment::This,
+            Segment::That,
+        ] {
+            pops.extend([Pop::new(segment, 0), Pop::new(segment, 1)]);
+        }
+        for pop in pops {
+            let specialized = create_specialized_pop_operator(&pop, "Test", &layout).unwrap();
+            let generic = create_generic_pop_operator(&pop, "Test", &layout).unwrap();
+            let (mut specialized_ram, specialized_size) = run_template(&specialized);
+            let (mut generic_ram, generic_size) = run_template(&generic);
+            // The generic form leaves the target address behind in R13
+            specialized_ram[13] = 0;
+            generic_ram[13] = 0;
+            assert_eq!(specialized_ram, generic_ram, "{:?}", pop);
+            assert!(specialized_size < generic_size, "{:?}", pop);
+        }
+    }
+
+    #[test]
+    fn literal_pushes_match_generic_forms() {
+        let constant = |value| Instruction::CPush(Push::new(Segment::Constant, value));
+        let unary = |operator| Instruction::CArithmetic(ArithmeticType::Unary(operator));
+        let sequences = [
+            (vec![constant(0)], 0),
+            (vec![constant(1)], 1),
+            (vec![constant(0), unary(UnaryArithmeticOperator::Not)], -1),
+            (
+                vec![constant(1), unary(UnaryArithmeticOperator::Negate)],
+                -1,
+            ),
+        ];
+        for (instructions, expected) in sequences {
+            let (value, consumed) = match_literal_push(&instructions).unwrap();
+            assert_eq!(consumed, instructions.len());
+            assert_eq!(value, expected.to_string());
+            assert_eq!(run_single_result(instructions), expected);
+        }
+    }
+
+    /// `Main.count` counts its first argument down through tail calls to
+    /// itself, and then tail-calls `Main.id` with fewer arguments. `Main.main`
+    /// starts it with a tail call that has more arguments than it received.
+    fn tail_recursive_program(main_frame: FrameKind, count_frame: FrameKind) -> Vec<Instruction> {
+        let label = |name: &str| Label::new(&String::from("Main.count"), &String::from(name));
+        let push = |segment, index| Instruction::CPush(Push::new(segment, index));
+        let binary = |operator| Instruction::CArithmetic(ArithmeticType::Binary(operator));
+        let function = |name: &str, n_locals, frame| {
+            let mut function = Function::new(&String::from(name), n_locals);
+            function.frame = frame;
+            Instruction::CFunction(function)
+        };
+        let call = |name: &str, n_args, frame, number| {
+            let return_address = format!("{}&ret.{}", name, number);
+            let mut call = Call::new(&String::from(name), &return_address, n_args);
+            call.frame = frame;
+            Instruction::CCall(call)
+        };
+        let mut instructions = vec![function("Main.main", 0, main_frame)];
+        if main_frame == FrameKind::Full {
+            instructions.extend([
+                push(Segment::Constant, 5000),
+                Instruction::CPop(Pop::new(Segment::Pointer, 0)),
+            ]);
+        }
+        instructions.extend([
+            push(Segment::Constant, 1000),
+            push(Segment::Constant, 0),
+            call("Main.count", 2, count_frame, 1),
+            Instruction::CReturn,
+            function("Main.count", 0, count_frame),
+            push(Segment::Argument, 0),
+            Instruction::CIf(label("MORE")),
+            push(Segment::Argument, 1),
+            call("Main.id", 1, main_frame, 1),
+            Instruction::CReturn,
+            Instruction::CLabel(label("MORE")),
+            push(Segment::Argument, 0),
+            push(Segment::Constant, 1),
+            binary(BinaryArithmeticOperator::Subtract),
+            push(Segment::Argument, 1),
+            push(Segment::Constant, 3),
+            binary(BinaryArithmeticOperator::Add),
+            call("Main.count", 2, count_frame, 2),
+            Instruction::CReturn,
+            function("Main.id", 0, main_frame),
+            push(Segment::Argument, 0),
+            Instruction::CReturn,
+        ]);
+        instructions
+    }
+
+    #[test]
+    fn tail_calls_reuse_the_caller_frame() {
+        let frames = [FrameKind::Full, FrameKind::Lite];
+        let layout = MemoryLayout {
+            entry_point: String::from("Main.main"),
+            halt_after_entry: true,
+            ..MemoryLayout::default()
+        };
+        for main_frame in frames {
+            for count_frame in frames {
+                for stack_strategy in STACK_STRATEGIES {
+                    let options = CodegenOptions {
+                        stack_strategy,
+                        ..CodegenOptions::default()
+                    };
+                    let instructions = tail_recursive_program(main_frame, count_frame);
+                    let asm = create_bootstrap_code(&layout, main_frame)
+                        + &compile(instructions, "Main", &layout, &options).concat();
+                    let mut emulator = HackEmulator::new(&asm);
+                    emulator.run(1_000_000);
+                    let context = (main_frame, count_frame, stack_strategy);
+                    assert_eq!(emulator.ram[0], 257, "{:?}", context);
+                    assert_eq!(emulator.ram[256], 3000, "{:?}", context);
+                    assert_eq!(emulator.ram[3], layout.initial_this, "{:?}", context);
+                    // A thousand nested frames would reach far beyond this
+                    assert!(
+                        emulator.ram[300..5000].iter().all(|value| *value == 0),
+                        "{:?}",
+                        context
+                    );
+                }
+            }
+        }
+    }
+    #[test]
+    fn bootstrap_initialises_the_layout_and_calls_the_entry_function() {
+        let layout = MemoryLayout {
+            stack_base: 1000,
+            ..MemoryLayout::default()
+        };
+        let bootstrap = create_bootstrap_code(&layout, FrameKind::Full);
+        for register in ["SP", "LCL", "ARG", "THIS", "THAT"] {
+            assert!(
+                bootstrap.contains(&format!("// BOOTSTRAP - set {}\n", register)),
+                "{}",
+                register
+            );
+        }
+
+        let asm = bootstrap + "(Sys.init)\n(Sys.init$END)\n@Sys.init$END\n0;JMP\n";
+        let mut emulator = HackEmulator::new(&asm);
+        // Sys.init is the only code that halts
+        assert!(emulator.run(1_000) < 1_000);
+        // The call to Sys.init saved the initial segment pointers above its
+        // return address, then pointed ARG and LCL at its frame
+        assert_eq!(emulator.ram[1001..1005], [-1, -2, -3, -4]);
+        assert_eq!(emulator.ram[0..5], [1005, 1005, 1000, -3, -4]);
+    }
+
+    #[test]
+    fn comparison_labels_stay_unique_across_files() {
+        let modules = parse_modules(&[
+            (
+                "Main",
+                "function Main.main 0
+                push constant 3
+                push constant 2
+                gt
+                call Other.less 0
+                add
+                return",
+            ),
+            (
+                "Other",
+                "function Other.less 0
+                push constant 2
+                push constant 3
+                lt
+                return",
+            ),
+        ]);
+        for stack_strategy in STACK_STRATEGIES {
+            let options = CodegenOptions {
+                stack_strategy,
+                ..CodegenOptions::default()
+            };
+            let emulator = run_hack(&modules, &main_layout(), &options);
+            assert_eq!(emulator.ram[256], -2, "{:?}", stack_strategy);
+        }
+    }
+
+    #[test]
+    fn entry_function_returns_into_the_halt_loop() {
+        let modules = parse_program(
+            "function Main.main 0
+            push constant 40
+            call Main.add_two 1
+            return
+            function Main.add_two 0
+            push argument 0
+            push constant 2
+            add
+            return",
+        );
+        let layout = MemoryLayout {
+            entry_point: String::from("Main.main"),
+            halt_after_entry: true,
+            ..MemoryLayout::default()
+        };
+        let asm = backend::compile_program(
+            &mut HackBackend::new(&layout, &CodegenOptions::default()),
+            &modules,
+        );
+        assert!(asm.contains("(BOOTSTRAP$HALT)"));
+        assert!(!asm.contains("Sys.init"));
+        let mut emulator = HackEmulator::new(&asm);
+        assert!(emulator.run(10_000) < 10_000);
+        assert_eq!(emulator.ram[0], 257);
+        assert_eq!(emulator.ram[256], 42);
+    }
+
+    #[test]
+    fn return_restores_the_caller_through_r14_and_r15() {
+        let layout = MemoryLayout::default();
+        for (frame, saved) in [
+            (FrameKind::Full, vec![300, 400, 3000, 3010]),
+            (FrameKind::Lite, vec![300, 400]),
+        ] {
+            let asm = create_return_operator(&layout, frame).unwrap();
+            let return_address = HackEmulator::new(&asm).rom_size() as i16;
+            let mut emulator = HackEmulator::new(&(asm + COMMAND_BOOTSTRAP_HALT));
+            // The callee has LCL at 500 with its saved frame just below, ARG at 490
+            let frame_base = 500 - saved.len();
+            emulator.ram[frame_base - 1] = return_address;
+            for (offset, pointer) in saved.iter().enumerate() {
+                emulator.ram[frame_base + offset] = *pointer;
+            }
+            emulator.ram[0..3].copy_from_slice(&[503, 500, 490]);
+            emulator.ram[13..16].copy_from_slice(&[7, 7, 7]);
+            emulator.ram[502] = 42;
+            assert!(emulator.run(1_000) < 1_000, "{:?}", frame);
+            assert_eq!(emulator.ram[490], 42, "{:?}", frame);
+            assert_eq!(emulator.ram[0], 491, "{:?}", frame);
+            assert_eq!(emulator.ram[1..1 + saved.len()], saved[..], "{:?}", frame);
+            // R14 walked down the frame from LCL, R15 held the return address
+            assert_eq!(emulator.ram[14], frame_base as i16, "{:?}", frame);
+            assert_eq!(emulator.ram[15], return_address, "{:?}", frame);
+        }
+    }
+
+    #[test]
+    fn routines_leave_the_ram_above_the_stack_alone() {
+        let source = "push constant 100
+            push constant 7
+            mul
+            push constant 300
+            neg
+            push constant 7
+            div
+            push constant 300
+            neg
+            push constant 7
+            mod
+            push constant 300
+            neg
+            shr 3";
+        let instructions = parse_source(source, &mut HashMap::new());
+        for profile in [TargetProfile::Standard, TargetProfile::Extended] {
+            for stack_strategy in STACK_STRATEGIES {
+                let options = CodegenOptions {
+                    stack_strategy,
+                    profile,
+                    ..CodegenOptions::default()
+                };
+                let routines =
+                    create_routines(&[Module::new("Test", instructions.clone())], &options);
+                let asm = compile(
+                    instructions.clone(),
+                    "Test",
+                    &MemoryLayout::default(),
+                    &options,
+                );
+                let asm = asm.concat() + COMMAND_BOOTSTRAP_HALT + &routines;
+                let mut emulator = HackEmulator::with_profile(&asm, profile);
+                emulator.ram[0] = 256;
+                emulator.ram[256..300].fill(12345);
+                emulator.run(10_000);
+                let case = format!("{:?} {:?}", profile, stack_strategy);
+                assert_eq!(emulator.ram[0], 260, "{}", case);
+                assert_eq!(emulator.ram[256..260], [700, -42, -6, -38], "{}", case);
+                assert!(
+                    emulator.ram[260..300].iter().all(|word| *word == 12345),
+                    "{}",
+                    case
+                );
+            }
+        }
+    }
+}
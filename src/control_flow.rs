@@ -0,0 +1,209 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::instructions::{Instruction, Label, Module};
+
+/// Threads jumps through `goto`s and removes jumps to the next instruction,
+/// unreachable code and unused labels, until nothing changes. An `if-goto`
+/// to the next instruction stays, as it still pops its condition.
+pub fn simplify_control_flow(modules: &mut [Module]) {
+    for module in modules.iter_mut() {
+        loop {
+            let length = module.instructions.len();
+            let threaded = thread_jumps(&mut module.instructions);
+            remove_fall_through_jumps(&mut module.instructions);
+            remove_unreachable(&mut module.instructions);
+            remove_unused_labels(&mut module.instructions);
+            if !threaded && module.instructions.len() == length {
+                break;
+            }
+        }
+    }
+}
+
+fn label_positions(instructions: &[Instruction]) -> HashMap<String, usize> {
+    instructions
+        .iter()
+        .enumerate()
+        .filter_map(|(position, instruction)| match instruction {
+            Instruction::CLabel(label) => Some((label.extract_label_name(), position)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The first instruction at or after `position` that is not a label.
+fn skip_labels(instructions: &[Instruction], mut position: usize) -> usize {
+    while let Some(Instruction::CLabel(_)) = instructions.get(position) {
+        position += 1;
+    }
+    position
+}
+
+/// Retargets jumps through chains of labels that only jump on. Returns
+/// whether any jump changed.
+fn thread_jumps(instructions: &mut [Instruction]) -> bool {
+    let labels = label_positions(instructions);
+    let mut changed = false;
+    for position in 0..instructions.len() {
+        let label = match &instructions[position] {
+            Instruction::CGoto(label) | Instruction::CIf(label) => label,
+            _ => continue,
+        };
+        let mut target: &Label = label;
+        let mut visited: HashSet<String> = HashSet::new();
+        while visited.insert(target.extract_label_name()) {
+            let next = match labels.get(&target.extract_label_name()) {
+                Some(label_position) => skip_labels(instructions, *label_position),
+                None => break,
+            };
+            match instructions.get(next) {
+                Some(Instruction::CGoto(next_target)) => target = next_target,
+                _ => break,
+            }
+        }
+        if target.extract_label_name() == label.extract_label_name() {
+            continue;
+        }
+        let target = target.clone();
+        match &mut instructions[position] {
+            Instruction::CGoto(label) | Instruction::CIf(label) => *label = target,
+            _ => unreachable!(),
+        }
+        changed = true;
+    }
+    changed
+}
+
+fn remove_fall_through_jumps(instructions: &mut Vec<Instruction>) {
+    let mut position = 0;
+    while position < instructions.len() {
+        if let Instruction::CGoto(label) = &instructions[position] {
+            let name = label.extract_label_name();
+            let falls_through = instructions[position + 1..]
+                .iter()
+                .map_while(|instruction| match instruction {
+                    Instruction::CLabel(label) => Some(label.extract_label_name()),
+                    _ => None,
+                })
+                .any(|following| following == name);
+            if falls_through {
+                instructions.remove(position);
+                continue;
+            }
+        }
+        position += 1;
+    }
+}
+
+/// Removes instructions that cannot run. Code starts running at the top of
+/// the module and at every function declaration.
+fn remove_unreachable(instructions: &mut Vec<Instruction>) {
+    let labels = label_positions(instructions);
+    let mut reachable = vec![false; instructions.len()];
+    let mut pending: Vec<usize> = vec![0];
+    for (position, instruction) in instructions.iter().enumerate() {
+        if let Instruction::CFunction(_) = instruction {
+            pending.push(position);
+        }
+    }
+    while let Some(position) = pending.pop() {
+        if position >= instructions.len() || reachable[position] {
+            continue;
+        }
+        reachable[position] = true;
+        match &instructions[position] {
+            Instruction::CGoto(label) | Instruction::CIf(label) => {
+                if let Some(target) = labels.get(&label.extract_label_name()) {
+                    pending.push(*target);
+                }
+                if let Instruction::CIf(_) = instructions[position] {
+                    pending.push(position + 1);
+                }
+            }
+            Instruction::CReturn => {}
+            _ => pending.push(position + 1),
+        }
+    }
+    let mut position = 0;
+    instructions.retain(|_| {
+        position += 1;
+        reachable[position - 1]
+    });
+}
+
+fn remove_unused_labels(instructions: &mut Vec<Instruction>) {
+    let used: HashSet<String> = instructions
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::CGoto(label) | Instruction::CIf(label) => Some(label.extract_label_name()),
+            _ => None,
+        })
+        .collect();
+    instructions.retain(|instruction| match instruction {
+        Instruction::CLabel(label) => used.contains(&label.extract_label_name()),
+        _ => true,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_programs::parse_modules;
+
+    fn simplify(source: &str) -> Vec<String> {
+        let mut modules = parse_modules(&[("Main", source)]);
+        simplify_control_flow(&mut modules);
+        modules[0]
+            .instructions
+            .iter()
+            .map(|instruction| match instruction {
+                Instruction::CLabel(label) => format!("label {}", label.label),
+                Instruction::CGoto(label) => format!("goto {}", label.label),
+                Instruction::CIf(label) => format!("if-goto {}", label.label),
+                Instruction::CReturn => String::from("return"),
+                _ => String::from("..."),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn threads_jump_chains_and_removes_dead_code() {
+        let simplified = simplify(
+            "function Main.main 0
+            push argument 0
+            if-goto FIRST
+            goto SECOND
+            label FIRST
+            goto SECOND
+            label SECOND
+            goto END
+            push constant 1
+            label UNUSED
+            label END
+            push constant 0
+            return
+            push constant 2
+            return",
+        );
+        assert_eq!(
+            simplified,
+            vec!["...", "...", "if-goto END", "label END", "...", "return"]
+        );
+    }
+
+    #[test]
+    fn threads_conditional_jumps_and_keeps_loops() {
+        let simplified = simplify(
+            "function Main.main 0
+            label LOOP
+            push constant 0
+            if-goto NEXT
+            label NEXT
+            goto LOOP",
+        );
+        assert_eq!(
+            simplified,
+            vec!["...", "label LOOP", "...", "if-goto LOOP", "goto LOOP"]
+        );
+    }
+}
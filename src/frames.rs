@@ -0,0 +1,167 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::instructions::{FrameKind, Instruction, Module, Segment};
+
+/// The segments each function pushes to or pops from, keyed by function name.
+pub fn analyze_segment_usage(modules: &[Module]) -> HashMap<String, HashSet<Segment>> {
+    let mut usage: HashMap<String, HashSet<Segment>> = HashMap::new();
+    for module in modules {
+        let mut current_function: Option<&str> = None;
+        for instruction in &module.instructions {
+            let segment = match instruction {
+                Instruction::CFunction(function) => {
+                    current_function = Some(&function.function_name);
+                    usage.entry(function.function_name.clone()).or_default();
+                    continue;
+                }
+                Instruction::CPush(push) => push.segment,
+                Instruction::CPop(pop) => pop.segment,
+                _ => continue,
+            };
+            if let Some(function_name) = current_function {
+                usage.get_mut(function_name).unwrap().insert(segment);
+            }
+        }
+    }
+    usage
+}
+
+/// Picks a frame kind for every function defined in the program and marks its
+/// definition and every call to it accordingly. Returns the chosen kinds.
+///
+/// A function gets a lite frame when it never touches `this`, `that` or
+/// `pointer` itself. That is enough even when it calls functions that do: those
+/// get full frames, which restore THIS and THAT when they return. Calls to
+/// functions that are not defined in the program keep full frames.
+pub fn assign_frames(modules: &mut [Module]) -> HashMap<String, FrameKind> {
+    let frames: HashMap<String, FrameKind> = analyze_segment_usage(modules)
+        .into_iter()
+        .map(|(function_name, segments)| {
+            let touches_pointers = segments.contains(&Segment::This)
+                || segments.contains(&Segment::That)
+                || segments.contains(&Segment::Pointer);
+            let frame = if touches_pointers {
+                FrameKind::Full
+            } else {
+                FrameKind::Lite
+            };
+            (function_name, frame)
+        })
+        .collect();
+
+    for module in modules.iter_mut() {
+        for instruction in module.instructions.iter_mut() {
+            match instruction {
+                Instruction::CFunction(function) => {
+                    function.frame = frames[&function.function_name];
+                }
+                Instruction::CCall(call) => {
+                    call.frame = frames
+                        .get(&call.function_name)
+                        .copied()
+                        .unwrap_or(FrameKind::Full);
+                }
+                _ => {}
+            }
+        }
+    }
+    frames
+}
+
+/// The frame the bootstrap calls the entry point with, which the lite-frames
+/// pass may have changed.
+pub fn entry_frame(modules: &[Module], entry_point: &str) -> FrameKind {
+    modules
+        .iter()
+        .flat_map(|module| &module.instructions)
+        .find_map(|instruction| match instruction {
+            Instruction::CFunction(function) if function.function_name == entry_point => {
+                Some(function.frame)
+            }
+            _ => None,
+        })
+        .unwrap_or(FrameKind::Full)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CodegenOptions;
+    use crate::test_programs::{main_layout, parse_modules, run_hack};
+
+    const MAIN: &str = "function Main.main 0
+        push constant 3000
+        call Main.full 1
+        pop temp 0
+        push constant 4
+        call Main.lite 1
+        pop temp 1
+        push constant 0
+        return
+        function Main.full 0
+        push argument 0
+        pop pointer 1
+        push constant 41
+        call Main.lite 1
+        pop that 0
+        push that 0
+        return
+        function Main.lite 0
+        push argument 0
+        push constant 1
+        add
+        return";
+
+    const OTHER: &str = "function Other.this 0
+        push this 0
+        push constant 2
+        call Math.multiply 2
+        return";
+
+    fn call_frames(modules: &[Module]) -> Vec<(String, FrameKind)> {
+        modules
+            .iter()
+            .flat_map(|module| &module.instructions)
+            .filter_map(|instruction| match instruction {
+                Instruction::CCall(call) => Some((call.function_name.clone(), call.frame)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn only_functions_that_touch_pointers_keep_full_frames() {
+        let mut modules = parse_modules(&[("Main", MAIN), ("Other", OTHER)]);
+        let frames = assign_frames(&mut modules);
+        assert_eq!(frames["Main.main"], FrameKind::Lite);
+        assert_eq!(frames["Main.full"], FrameKind::Full);
+        assert_eq!(frames["Main.lite"], FrameKind::Lite);
+        assert_eq!(frames["Other.this"], FrameKind::Full);
+        // Math.multiply is not defined in the program
+        assert_eq!(
+            call_frames(&modules),
+            vec![
+                (String::from("Main.full"), FrameKind::Full),
+                (String::from("Main.lite"), FrameKind::Lite),
+                (String::from("Main.lite"), FrameKind::Lite),
+                (String::from("Math.multiply"), FrameKind::Full),
+            ]
+        );
+        assert_eq!(entry_frame(&modules, "Main.main"), FrameKind::Lite);
+    }
+
+    #[test]
+    fn lite_and_full_frames_call_each_other() {
+        let mut modules = parse_modules(&[("Main", MAIN)]);
+        assign_frames(&mut modules);
+        let layout = main_layout();
+        let emulator = run_hack(&modules, &layout, &CodegenOptions::default());
+        assert_eq!(emulator.ram[0], 257);
+        // The lite caller got the full callee's result, and the lite callee's
+        // result reached the full caller
+        assert_eq!(emulator.ram[5..7], [42, 5]);
+        assert_eq!(emulator.ram[3000], 42);
+        // The full callee restored THAT for its lite caller
+        assert_eq!(emulator.ram[4], layout.initial_that);
+    }
+}
@@ -0,0 +1,213 @@
+//! A minimal Hack assembler and CPU, used by the tests to execute the code the
+//! translator generates. Only accepts the shift instructions when running the
+//! extended CPU.
+
+use std::collections::HashMap;
+
+use crate::compiler::TargetProfile;
+use crate::hack_syntax::is_hack_computation;
+
+const RAM_SIZE: usize = 32768;
+
+#[derive(Debug, Clone)]
+enum HackInstruction {
+    Address(u16),
+    Compute {
+        dest: String,
+        comp: String,
+        jump: String,
+    },
+}
+
+pub struct HackEmulator {
+    rom: Vec<HackInstruction>,
+    pub ram: Vec<i16>,
+    pc: usize,
+    a: i16,
+    d: i16,
+}
+
+impl HackEmulator {
+    pub fn new(asm: &str) -> Self {
+        Self::with_profile(asm, TargetProfile::Extended)
+    }
+
+    /// Panics on any computation the profile's CPU lacks.
+    pub fn with_profile(asm: &str, profile: TargetProfile) -> Self {
+        Self {
+            rom: assemble(asm, profile),
+            ram: vec![0; RAM_SIZE],
+            pc: 0,
+            a: 0,
+            d: 0,
+        }
+    }
+
+    pub fn rom_size(&self) -> usize {
+        self.rom.len()
+    }
+
+    /// Runs until the program counter leaves the ROM, the `(L) @L 0;JMP` halt
+    /// idiom is reached, or `max_steps` instructions have executed. Returns the
+    /// number of instructions executed.
+    pub fn run(&mut self, max_steps: usize) -> usize {
+        let mut steps = 0;
+        while self.pc < self.rom.len() && steps < max_steps {
+            steps += 1;
+            match self.rom[self.pc].clone() {
+                HackInstruction::Address(value) => {
+                    self.a = value as i16;
+                    self.pc += 1;
+                }
+                HackInstruction::Compute { dest, comp, jump } => {
+                    let value = self.compute(&comp);
+                    let address = self.a as u16 as usize;
+                    if dest.contains('M') {
+                        let memory_address = self.memory_address();
+                        self.ram[memory_address] = value;
+                    }
+                    if dest.contains('A') {
+                        self.a = value;
+                    }
+                    if dest.contains('D') {
+                        self.d = value;
+                    }
+                    let jumps = match jump.as_str() {
+                        "" => false,
+                        "JMP" => true,
+                        "JEQ" => value == 0,
+                        "JNE" => value != 0,
+                        "JGT" => value > 0,
+                        "JGE" => value >= 0,
+                        "JLT" => value < 0,
+                        "JLE" => value <= 0,
+                        _ => panic!("Invalid jump {}", jump),
+                    };
+                    if !jumps {
+                        self.pc += 1;
+                    } else if address + 1 == self.pc {
+                        break;
+                    } else {
+                        self.pc = address;
+                    }
+                }
+            }
+        }
+        steps
+    }
+
+    /// The RAM address in A, which like the Hack RAM ignores the top bit.
+    fn memory_address(&self) -> usize {
+        self.a as u16 as usize % RAM_SIZE
+    }
+
+    fn compute(&self, comp: &str) -> i16 {
+        let m = self.ram[self.memory_address()];
+        let register = |name: &str| match name {
+            "A" => self.a,
+            "D" => self.d,
+            "M" => m,
+            "1" => 1,
+            _ => panic!("Invalid operand {}", name),
+        };
+        if let Some(operand) = comp.strip_suffix("<<") {
+            return register(operand).wrapping_shl(1);
+        }
+        if let Some(operand) = comp.strip_suffix(">>") {
+            return register(operand) >> 1;
+        }
+        match comp {
+            "0" => return 0,
+            "1" => return 1,
+            "-1" => return -1,
+            _ => {}
+        }
+        if let Some(operand) = comp.strip_prefix('!') {
+            return !register(operand);
+        }
+        if let Some(operand) = comp.strip_prefix('-') {
+            return register(operand).wrapping_neg();
+        }
+        if comp.len() == 1 {
+            return register(comp);
+        }
+        let (x, operator, y) = (&comp[0..1], &comp[1..2], &comp[2..]);
+        let (x, y) = (register(x), register(y));
+        match operator {
+            "+" => x.wrapping_add(y),
+            "-" => x.wrapping_sub(y),
+            "&" => x & y,
+            "|" => x | y,
+            _ => panic!("Invalid computation {}", comp),
+        }
+    }
+}
+
+fn assemble(asm: &str, profile: TargetProfile) -> Vec<HackInstruction> {
+    let mut symbols: HashMap<String, u16> = HashMap::new();
+    for (name, address) in [
+        ("SP", 0),
+        ("LCL", 1),
+        ("ARG", 2),
+        ("THIS", 3),
+        ("THAT", 4),
+        ("SCREEN", 16384),
+        ("KBD", 24576),
+    ] {
+        symbols.insert(name.to_string(), address);
+    }
+    for register in 0..16 {
+        symbols.insert(format!("R{}", register), register);
+    }
+
+    let mut lines: Vec<&str> = vec![];
+    for line in asm.lines() {
+        let line = line.split("//").next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(label) = line.strip_prefix('(') {
+            let label = label.strip_suffix(')').unwrap().to_string();
+            if symbols.insert(label.clone(), lines.len() as u16).is_some() {
+                panic!("Duplicate label {}", label);
+            }
+        } else {
+            lines.push(line);
+        }
+    }
+
+    let mut next_variable = 16;
+    lines
+        .into_iter()
+        .map(|line| {
+            if let Some(symbol) = line.strip_prefix('@') {
+                let value = match symbol.parse::<u16>() {
+                    Ok(value) if value <= i16::MAX as u16 => value,
+                    Ok(value) => panic!("Constant {} does not fit an A-instruction", value),
+                    Err(_) => *symbols.entry(symbol.to_string()).or_insert_with(|| {
+                        next_variable += 1;
+                        next_variable - 1
+                    }),
+                };
+                return HackInstruction::Address(value);
+            }
+            let (dest, rest) = match line.split_once('=') {
+                Some((dest, rest)) => (dest, rest),
+                None => ("", line),
+            };
+            let (comp, jump) = match rest.split_once(';') {
+                Some((comp, jump)) => (comp, jump),
+                None => (rest, ""),
+            };
+            let comp = comp.replace(' ', "");
+            if !is_hack_computation(&comp, profile) {
+                panic!("Invalid computation {} in {}", comp, line);
+            }
+            HackInstruction::Compute {
+                dest: dest.to_string(),
+                comp,
+                jump: jump.to_string(),
+            }
+        })
+        .collect()
+}
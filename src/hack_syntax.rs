@@ -0,0 +1,157 @@
+//! The syntax of Hack assembly, shared by the checks on `asm` blocks and the
+//! test emulator. The shift computations only exist on the extended CPU.
+
+use crate::compiler::TargetProfile;
+
+const DESTINATIONS: [char; 3] = ['A', 'M', 'D'];
+
+const JUMPS: [&str; 7] = ["JGT", "JEQ", "JGE", "JLT", "JNE", "JLE", "JMP"];
+
+/// Whether the profile's ALU computes `comp`, so that code the stock assembler
+/// rejects fails here too. Binary operators take D first, as in the
+/// nand2tetris table, and only the extended CPU shifts.
+pub fn is_hack_computation(comp: &str, profile: TargetProfile) -> bool {
+    if ["0", "1", "-1"].contains(&comp) {
+        return true;
+    }
+    let unary: &[&str] = match profile {
+        TargetProfile::Standard => &["{}", "!{}", "-{}", "{}+1", "{}-1"],
+        TargetProfile::Extended => &["{}", "!{}", "-{}", "{}+1", "{}-1", "{}<<", "{}>>"],
+    };
+    if ["A", "D", "M"].iter().any(|register| {
+        unary
+            .iter()
+            .any(|form| form.replace("{}", register) == comp)
+    }) {
+        return true;
+    }
+    let binary = ["D+{}", "D-{}", "{}-D", "D&{}", "D|{}"];
+    ["A", "M"].iter().any(|register| {
+        binary
+            .iter()
+            .any(|form| form.replace("{}", register) == comp)
+    })
+}
+
+/// Whether `symbol` can name a label or variable: letters, digits and
+/// `_.$:`, not starting with a digit.
+pub fn is_hack_symbol(symbol: &str) -> bool {
+    match symbol.chars().next() {
+        Some(first) if !first.is_ascii_digit() => symbol
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.$:".contains(c)),
+        _ => false,
+    }
+}
+
+/// Checks a single instruction without comments, explaining what is wrong
+/// with it if it is not Hack assembly for the profile's CPU.
+pub fn check_instruction(instruction: &str, profile: TargetProfile) -> Result<(), String> {
+    let instruction: String = instruction.split_whitespace().collect();
+    if let Some(value) = instruction.strip_prefix('@') {
+        if value.chars().all(|c| c.is_ascii_digit()) && !value.is_empty() {
+            return match value.parse::<u16>() {
+                Ok(constant) if constant <= i16::MAX as u16 => Ok(()),
+                _ => Err(format!("{} does not fit an A-instruction", value)),
+            };
+        }
+        if !is_hack_symbol(value) {
+            return Err(format!("`{}` is not a valid symbol", value));
+        }
+        return Ok(());
+    }
+    if let Some(label) = instruction.strip_prefix('(') {
+        return match label.strip_suffix(')') {
+            Some(label) if is_hack_symbol(label) => Ok(()),
+            _ => Err(format!("`{}` is not a valid label", instruction)),
+        };
+    }
+    let (dest, rest) = instruction.split_once('=').unwrap_or(("", &instruction));
+    let (comp, jump) = rest.split_once(';').unwrap_or((rest, ""));
+    if instruction.contains('=') && dest.is_empty()
+        || !dest.chars().all(|c| DESTINATIONS.contains(&c))
+        || DESTINATIONS
+            .iter()
+            .any(|register| dest.matches(*register).count() > 1)
+    {
+        return Err(format!("`{}` is not a valid destination", dest));
+    }
+    if !is_hack_computation(comp, profile) {
+        return Err(format!("`{}` is not a valid computation", comp));
+    }
+    if rest.contains(';') && !JUMPS.contains(&jump) {
+        return Err(format!("`{}` is not a valid jump", jump));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_each_kind_of_instruction() {
+        for valid in [
+            "@SP",
+            "@32767",
+            "@Main.draw$LOOP",
+            "(LOOP)",
+            "D=M",
+            "AM=M-1",
+            "0;JMP",
+            "D;JGE",
+            "MD=D+1;JNE",
+            "M=D|M",
+            "D=A-D",
+            "M=M<<",
+        ] {
+            assert_eq!(
+                check_instruction(valid, TargetProfile::Extended),
+                Ok(()),
+                "{}",
+                valid
+            );
+        }
+        for invalid in [
+            "@32768",
+            "@1abc",
+            "@",
+            "(LOOP",
+            "()",
+            "D=D+D",
+            "M=M+D",
+            "D=A&D",
+            "AM=M|D",
+            "X=M",
+            "DD=M",
+            "=M",
+            "D;JUMP",
+            "0;",
+            "push constant 1",
+        ] {
+            assert!(
+                check_instruction(invalid, TargetProfile::Extended).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn only_the_extended_profile_shifts() {
+        for shift in ["M=M<<", "D=D>>", "AM=A<<"] {
+            assert_eq!(
+                check_instruction(shift, TargetProfile::Extended),
+                Ok(()),
+                "{}",
+                shift
+            );
+            assert!(
+                check_instruction(shift, TargetProfile::Standard).is_err(),
+                "{}",
+                shift
+            );
+        }
+        assert_eq!(check_instruction("D=D+M", TargetProfile::Standard), Ok(()));
+    }
+}
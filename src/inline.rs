@@ -0,0 +1,471 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::instructions::{Call, Instruction, Label, Module, Pop, Push, Segment};
+
+/// A function whose body can be substituted at its call sites.
+struct Candidate {
+    module: String,
+    n_locals: u16,
+    body: Vec<Instruction>,
+    /// One past the highest `argument` index the body reads.
+    n_args_used: u16,
+    uses_statics: bool,
+    /// The `pointer` indices the body pops to, which the call site saves.
+    pointer_pops: Vec<u16>,
+}
+
+/// Substitutes non-recursive functions of at most `size_limit` instructions
+/// at their call sites. The callee's arguments and locals become extra locals
+/// of the caller, and `static` users are only inlined into their own file.
+pub fn inline_functions(modules: &mut [Module], size_limit: usize) {
+    let candidates = find_candidates(modules, size_limit);
+    let mut site_count: usize = 0;
+    for module in modules.iter_mut() {
+        let instructions = std::mem::take(&mut module.instructions);
+        module.instructions =
+            inline_calls(&module.name, instructions, &candidates, &mut site_count);
+    }
+}
+
+fn find_candidates(modules: &[Module], size_limit: usize) -> HashMap<String, Candidate> {
+    let mut functions: HashMap<String, Candidate> = HashMap::new();
+    for module in modules {
+        let mut current_function: Option<String> = None;
+        for instruction in &module.instructions {
+            if let Instruction::CFunction(function) = instruction {
+                current_function = Some(function.function_name.clone());
+                functions.insert(
+                    function.function_name.clone(),
+                    Candidate {
+                        module: module.name.clone(),
+                        n_locals: function.n_args,
+                        body: vec![],
+                        n_args_used: 0,
+                        uses_statics: false,
+                        pointer_pops: vec![],
+                    },
+                );
+                continue;
+            }
+            if let Some(function_name) = &current_function {
+                let function = functions.get_mut(function_name).unwrap();
+                match instruction {
+                    Instruction::CPush(Push {
+                        segment: Segment::Argument,
+                        index,
+                    })
+                    | Instruction::CPop(Pop {
+                        segment: Segment::Argument,
+                        index,
+                    }) => function.n_args_used = function.n_args_used.max(index + 1),
+                    Instruction::CPush(Push {
+                        segment: Segment::Static,
+                        ..
+                    })
+                    | Instruction::CPop(Pop {
+                        segment: Segment::Static,
+                        ..
+                    }) => function.uses_statics = true,
+                    Instruction::CPop(Pop {
+                        segment: Segment::Pointer,
+                        index,
+                    }) if !function.pointer_pops.contains(index) => {
+                        function.pointer_pops.push(*index)
+                    }
+                    _ => {}
+                }
+                function.body.push(instruction.clone());
+            }
+        }
+    }
+
+    let calls: HashMap<String, HashSet<String>> = functions
+        .iter()
+        .map(|(function_name, function)| {
+            let callees = function
+                .body
+                .iter()
+                .filter_map(|instruction| match instruction {
+                    Instruction::CCall(call) => Some(call.function_name.clone()),
+                    _ => None,
+                })
+                .collect();
+            (function_name.clone(), callees)
+        })
+        .collect();
+
+    functions
+        .into_iter()
+        .filter(|(function_name, function)| {
+            function.body.len() <= size_limit
+                && !function
+                    .body
+                    .iter()
+                    .any(|instruction| matches!(instruction, Instruction::CAsm(_)))
+                && !is_recursive(function_name, &calls)
+                && returns_single_value(&function.body)
+        })
+        .collect()
+}
+
+/// Whether `function_name` can reach itself through calls.
+fn is_recursive(function_name: &str, calls: &HashMap<String, HashSet<String>>) -> bool {
+    let mut visited: HashSet<&str> = HashSet::new();
+    let mut pending: Vec<&str> = vec![function_name];
+    while let Some(caller) = pending.pop() {
+        for callee in calls.get(caller).into_iter().flatten() {
+            if callee == function_name {
+                return true;
+            }
+            if visited.insert(callee) {
+                pending.push(callee);
+            }
+        }
+    }
+    false
+}
+
+/// How many values an instruction pops and pushes.
+fn stack_effect(instruction: &Instruction) -> (i32, i32) {
+    match instruction {
+        Instruction::CArithmetic(operator) if operator.is_binary() => (2, 1),
+        Instruction::CArithmetic(_) => (1, 1),
+        Instruction::CPush(_) => (0, 1),
+        Instruction::CPop(_) | Instruction::CIf(_) | Instruction::CReturn => (1, 0),
+        Instruction::CCall(call) => (call.n_args as i32, 1),
+        Instruction::CLabel(_)
+        | Instruction::CGoto(_)
+        | Instruction::CFunction(_)
+        | Instruction::CAsm(_) => (0, 0),
+    }
+}
+
+/// Whether every `return` leaves exactly the return value on the body's stack,
+/// so it can become a jump to the end of the inlined body.
+fn returns_single_value(body: &[Instruction]) -> bool {
+    let labels: HashMap<&str, usize> = body
+        .iter()
+        .enumerate()
+        .filter_map(|(position, instruction)| match instruction {
+            Instruction::CLabel(label) => Some((label.label.as_str(), position)),
+            _ => None,
+        })
+        .collect();
+    let mut depths: Vec<Option<i32>> = vec![None; body.len()];
+    let mut pending: Vec<(usize, i32)> = vec![(0, 0)];
+    while let Some((position, depth)) = pending.pop() {
+        // Running off the end of the body would continue into the next function
+        if position >= body.len() {
+            return false;
+        }
+        match depths[position] {
+            Some(known) if known == depth => continue,
+            Some(_) => return false,
+            None => depths[position] = Some(depth),
+        }
+        let (pops, pushes) = stack_effect(&body[position]);
+        if depth < pops {
+            return false;
+        }
+        let after = depth - pops + pushes;
+        match &body[position] {
+            Instruction::CReturn => {
+                if depth != 1 {
+                    return false;
+                }
+            }
+            Instruction::CGoto(label) | Instruction::CIf(label) => {
+                match labels.get(label.label.as_str()) {
+                    Some(target) => pending.push((*target, after)),
+                    None => return false,
+                }
+                if let Instruction::CIf(_) = body[position] {
+                    pending.push((position + 1, after));
+                }
+            }
+            _ => pending.push((position + 1, after)),
+        }
+    }
+    true
+}
+
+fn inline_calls(
+    module_name: &str,
+    instructions: Vec<Instruction>,
+    candidates: &HashMap<String, Candidate>,
+    site_count: &mut usize,
+) -> Vec<Instruction> {
+    let mut result: Vec<Instruction> = vec![];
+    // The caller's declaration, its own locals and the extra locals it needs
+    let mut caller: Option<(usize, String, u16)> = None;
+    let mut extra_locals: u16 = 0;
+    for instruction in instructions {
+        match instruction {
+            Instruction::CFunction(ref function) => {
+                add_locals(&mut result, &caller, extra_locals);
+                caller = Some((
+                    result.len(),
+                    function.function_name.clone(),
+                    function.n_args,
+                ));
+                extra_locals = 0;
+                result.push(instruction);
+            }
+            Instruction::CCall(ref call) => {
+                let candidate = candidates.get(&call.function_name).filter(|candidate| {
+                    candidate.n_args_used <= call.n_args
+                        && (!candidate.uses_statics || candidate.module == module_name)
+                });
+                match (candidate, &caller) {
+                    (Some(candidate), Some((_, caller_name, base))) => {
+                        *site_count += 1;
+                        let site = InlineSite {
+                            caller_name,
+                            base: *base,
+                            n_args: call.n_args,
+                            number: *site_count,
+                        };
+                        extra_locals = extra_locals.max(site.expand(candidate, &mut result));
+                    }
+                    _ => result.push(instruction),
+                }
+            }
+            _ => result.push(instruction),
+        }
+    }
+    add_locals(&mut result, &caller, extra_locals);
+    result
+}
+
+fn add_locals(result: &mut [Instruction], caller: &Option<(usize, String, u16)>, extra: u16) {
+    if let Some((position, _, _)) = caller {
+        if let Instruction::CFunction(function) = &mut result[*position] {
+            function.n_args += extra;
+        }
+    }
+}
+
+/// A call being replaced by the callee's body, whose segments map to the
+/// caller's locals from `base`.
+struct InlineSite<'a> {
+    caller_name: &'a str,
+    base: u16,
+    n_args: u16,
+    number: usize,
+}
+
+impl InlineSite<'_> {
+    /// Appends the inlined body and returns how many extra locals it uses.
+    fn expand(&self, candidate: &Candidate, result: &mut Vec<Instruction>) -> u16 {
+        let locals_base = self.base + self.n_args;
+        let saves_base = locals_base + candidate.n_locals;
+        for index in (0..self.n_args).rev() {
+            result.push(Instruction::CPop(Pop::new(
+                Segment::Local,
+                self.base + index,
+            )));
+        }
+        for index in 0..candidate.n_locals {
+            result.push(Instruction::CPush(Push::new(Segment::Constant, 0)));
+            result.push(Instruction::CPop(Pop::new(
+                Segment::Local,
+                locals_base + index,
+            )));
+        }
+        for (save, pointer) in candidate.pointer_pops.iter().enumerate() {
+            result.push(Instruction::CPush(Push::new(Segment::Pointer, *pointer)));
+            result.push(Instruction::CPop(Pop::new(
+                Segment::Local,
+                saves_base + save as u16,
+            )));
+        }
+
+        let end = self.label("");
+        let mut jumps_to_end = false;
+        let last = candidate.body.len() - 1;
+        for (position, instruction) in candidate.body.iter().enumerate() {
+            // The last return falls through to the end of the body
+            if position == last && matches!(instruction, Instruction::CReturn) {
+                continue;
+            }
+            let remap = |segment: Segment, index: u16| match segment {
+                Segment::Argument => (Segment::Local, self.base + index),
+                Segment::Local => (Segment::Local, locals_base + index),
+                _ => (segment, index),
+            };
+            result.push(match instruction {
+                Instruction::CPush(push) => {
+                    let (segment, index) = remap(push.segment, push.index);
+                    Instruction::CPush(Push::new(segment, index))
+                }
+                Instruction::CPop(pop) => {
+                    let (segment, index) = remap(pop.segment, pop.index);
+                    Instruction::CPop(Pop::new(segment, index))
+                }
+                Instruction::CLabel(label) => Instruction::CLabel(self.label(&label.label)),
+                Instruction::CGoto(label) => Instruction::CGoto(self.label(&label.label)),
+                Instruction::CIf(label) => Instruction::CIf(self.label(&label.label)),
+                Instruction::CCall(call) => {
+                    let mut call: Call = call.clone();
+                    call.return_address = format!("{}$INLINE.{}", call.return_address, self.number);
+                    Instruction::CCall(call)
+                }
+                Instruction::CReturn => {
+                    jumps_to_end = true;
+                    Instruction::CGoto(end.clone())
+                }
+                _ => instruction.clone(),
+            });
+        }
+        if jumps_to_end {
+            result.push(Instruction::CLabel(end));
+        }
+
+        for (save, pointer) in candidate.pointer_pops.iter().enumerate() {
+            result.push(Instruction::CPush(Push::new(
+                Segment::Local,
+                saves_base + save as u16,
+            )));
+            result.push(Instruction::CPop(Pop::new(Segment::Pointer, *pointer)));
+        }
+        self.n_args + candidate.n_locals + candidate.pointer_pops.len() as u16
+    }
+
+    /// Renames a body label into the caller, or names the end of the body for
+    /// an empty label. VM labels cannot hold the `$` this adds.
+    fn label(&self, label: &str) -> Label {
+        let name = if label.is_empty() {
+            format!("INLINE${}", self.number)
+        } else {
+            format!("INLINE${}${}", self.number, label)
+        };
+        Label::new(&self.caller_name.to_string(), &name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CodegenOptions;
+    use crate::hack_emulator::HackEmulator;
+    use crate::memory_layout::MemoryLayout;
+    use crate::test_programs::{hack_program, main_layout, parse_modules};
+
+    const MAIN: &str = "function Main.main 1
+        push constant 3000
+        pop pointer 1
+        push constant 4000
+        call Point.getX 1
+        pop that 0
+        push constant 7
+        push constant 5
+        call Main.max 2
+        pop that 1
+        push constant 2
+        push constant 9
+        call Main.max 2
+        pop that 2
+        push pointer 0
+        pop that 3
+        push constant 0
+        return
+        function Main.max 0
+        push argument 0
+        push argument 1
+        gt
+        if-goto FIRST
+        push argument 1
+        return
+        label FIRST
+        push argument 0
+        return";
+
+    const POINT: &str = "function Point.getX 0
+        push argument 0
+        pop pointer 0
+        push this 0
+        return";
+
+    const PROGRAM: [(&str, &str); 2] = [("Main", MAIN), ("Point", POINT)];
+
+    fn run(modules: Vec<Module>) -> Vec<i16> {
+        let asm = hack_program(&modules, &main_layout(), &CodegenOptions::default());
+        let mut emulator = HackEmulator::new(&asm);
+        emulator.ram[4000] = 11;
+        emulator.run(100_000);
+        emulator.ram[3000..3004].to_vec()
+    }
+
+    #[test]
+    fn inlined_calls_match_real_calls() {
+        let mut modules = parse_modules(&PROGRAM);
+        inline_functions(&mut modules, 10);
+        let remaining_calls = modules
+            .iter()
+            .flat_map(|module| &module.instructions)
+            .filter(|instruction| matches!(instruction, Instruction::CCall(_)))
+            .count();
+        assert_eq!(remaining_calls, 0);
+        let expected = run(parse_modules(&PROGRAM));
+        assert_eq!(
+            expected,
+            vec![11, 7, 9, MemoryLayout::default().initial_this]
+        );
+        assert_eq!(run(modules), expected);
+    }
+
+    #[test]
+    fn size_limit_and_recursion_prevent_inlining() {
+        let mut modules = parse_modules(&PROGRAM);
+        inline_functions(&mut modules, 4);
+        let callees: Vec<&str> = modules[0]
+            .instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::CCall(call) => Some(call.function_name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(callees, vec!["Main.max", "Main.max"]);
+
+        let recursive = "function Main.loop 0
+            push argument 0
+            call Main.loop 1
+            return";
+        let mut modules = parse_modules(&[("Main", recursive)]);
+        inline_functions(&mut modules, 10);
+        assert!(matches!(modules[0].instructions[2], Instruction::CCall(_)));
+    }
+
+    #[test]
+    fn inlined_labels_do_not_clash_with_the_callers() {
+        let source = "function Main.main 0
+            push constant 3000
+            pop pointer 1
+            push constant 7
+            push constant 5
+            call Main.max 2
+            goto INLINE.1
+            label INLINE.1
+            pop that 0
+            push constant 0
+            return
+            function Main.max 0
+            push argument 0
+            push argument 1
+            gt
+            if-goto FIRST
+            push argument 1
+            return
+            label FIRST
+            push argument 0
+            return";
+        let mut modules = parse_modules(&[("Main", source)]);
+        inline_functions(&mut modules, 10);
+        assert!(!modules[0]
+            .instructions
+            .iter()
+            .any(|instruction| matches!(instruction, Instruction::CCall(_))));
+        assert_eq!(run(modules)[0], 7);
+    }
+}
@@ -1,5 +1,6 @@
 use core::fmt;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone)]
 pub enum Instruction {
     CArithmetic(ArithmeticType),
@@ -11,13 +12,31 @@ pub enum Instruction {
     CFunction(Function),
     CReturn,
     CCall(Call),
+    /// Hack assembly the Hack target passes through verbatim. It runs with the
+    /// stack in memory, may overwrite A, D, R13 to R15 and the RAM from SP up,
+    /// and must keep SP and the segment pointers and only jump to its own labels,
+    /// which are global, so unique in the program.
+    CAsm(Vec<String>),
 }
 
 #[derive(Debug, Clone, Copy)]
 pub enum ArithmeticType {
     Unary(UnaryArithmeticOperator),
     Binary(BinaryArithmeticOperator),
-    Shift(ShiftArithmeticOperator),
+    /// A shift by a constant amount below 16. `shiftleft` and `shiftright`
+    /// shift by one.
+    Shift(ShiftArithmeticOperator, u16),
+    Extended(ExtendedArithmeticOperator),
+}
+
+impl ArithmeticType {
+    /// Whether the operator pops two values, rather than one.
+    pub fn is_binary(&self) -> bool {
+        matches!(
+            self,
+            ArithmeticType::Binary(_) | ArithmeticType::Extended(_)
+        )
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -37,13 +56,29 @@ pub enum BinaryArithmeticOperator {
     Lt,
 }
 
+/// Shifts right are arithmetic, keeping the sign.
 #[derive(Debug, Clone, Copy)]
 pub enum ShiftArithmeticOperator {
     ShiftLeft,
     ShiftRight,
 }
 
+/// Binary operators beyond the standard VM language, which let programs skip
+/// Jack's Math library. Results wrap to 16 bits.
 #[derive(Debug, Clone, Copy)]
+pub enum ExtendedArithmeticOperator {
+    Multiply,
+    /// Rounds towards zero. Dividing by zero gives 0.
+    Divide,
+    /// The remainder of `Divide`, with the sign of x. Dividing by zero leaves x.
+    Modulo,
+    Xor,
+    /// Compares the words as unsigned values.
+    UnsignedLt,
+    UnsignedGt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Segment {
     Argument,
     Local,
@@ -53,6 +88,10 @@ pub enum Segment {
     That,
     Pointer,
     Temp,
+    /// The memory-mapped screen, `screen i` being SCREEN + i.
+    Screen,
+    /// The memory-mapped keyboard register, read only through `keyboard 0`.
+    Keyboard,
 }
 
 impl Segment {
@@ -66,6 +105,8 @@ impl Segment {
             "that" => Segment::That,
             "pointer" => Segment::Pointer,
             "temp" => Segment::Temp,
+            "screen" => Segment::Screen,
+            "keyboard" => Segment::Keyboard,
             _ => panic!("Not a valid segment"),
         }
     }
@@ -79,9 +120,11 @@ impl fmt::Display for Segment {
             Segment::This => write!(f, "THIS"),
             Segment::That => write!(f, "THAT"),
             Segment::Temp => write!(f, "TEMP"),
-            Segment::Static => todo!(),
-            Segment::Constant => todo!(),
-            Segment::Pointer => todo!(),
+            Segment::Screen => write!(f, "SCREEN"),
+            Segment::Keyboard => write!(f, "KBD"),
+            Segment::Static => write!(f, "static"),
+            Segment::Constant => write!(f, "constant"),
+            Segment::Pointer => write!(f, "pointer"),
         }
     }
 }
@@ -135,11 +178,22 @@ impl Label {
     }
 }
 
+/// The stack frame a call builds for its callee. A full frame saves LCL, ARG,
+/// THIS and THAT. A lite frame only saves LCL and ARG, and is used for callees
+/// that never touch `this`, `that` or `pointer`. Every call to a function and
+/// the function's own returns have to agree on the kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Full,
+    Lite,
+}
+
 #[derive(Debug, Clone)]
 pub struct Call {
     pub function_name: String,
     pub return_address: String,
     pub n_args: u16,
+    pub frame: FrameKind,
 }
 
 impl Call {
@@ -148,6 +202,7 @@ impl Call {
             function_name: function_name.to_string(),
             return_address: return_address.to_string(),
             n_args,
+            frame: FrameKind::Full,
         }
     }
 }
@@ -156,6 +211,7 @@ impl Call {
 pub struct Function {
     pub function_name: String,
     pub n_args: u16,
+    pub frame: FrameKind,
 }
 
 impl Function {
@@ -163,6 +219,24 @@ impl Function {
         Self {
             function_name: function_name.to_string(),
             n_args,
+            frame: FrameKind::Full,
+        }
+    }
+}
+
+/// The instructions parsed from a single VM file. The name scopes the file's
+/// static segment.
+#[derive(Clone, Debug)]
+pub struct Module {
+    pub name: String,
+    pub instructions: Vec<Instruction>,
+}
+
+impl Module {
+    pub fn new(name: &str, instructions: Vec<Instruction>) -> Self {
+        Self {
+            name: name.to_string(),
+            instructions,
         }
     }
 }
@@ -0,0 +1,129 @@
+//! Jack OS functions that the Hack code generators replace with asm of their
+//! own, saving the call and return protocol. Each intrinsic replaces its
+//! arguments on the stack in memory with its result, as the call would, so
+//! the programs may still define the functions for the other targets or for
+//! `--no-intrinsics`.
+
+use crate::asm_templates::{INTRINSIC_PEEK, INTRINSIC_POKE};
+use crate::compiler::{create_arithmetic_operator, CodegenOptions, TargetProfile};
+use crate::instructions::{ArithmeticType, Call, ExtendedArithmeticOperator};
+
+pub struct Intrinsic {
+    pub function_name: &'static str,
+    pub n_args: u16,
+    lowering: Lowering,
+}
+
+enum Lowering {
+    /// An operator on the arguments, which may call its shared routine.
+    Operator(ArithmeticType),
+    /// A template with no labels.
+    Asm(&'static str),
+}
+
+/// `Math.divide` is not among them: the OS calls `Sys.error` on a zero
+/// divisor, where the `div` operator gives 0.
+const INTRINSICS: [Intrinsic; 3] = [
+    Intrinsic {
+        function_name: "Math.multiply",
+        n_args: 2,
+        lowering: Lowering::Operator(ArithmeticType::Extended(
+            ExtendedArithmeticOperator::Multiply,
+        )),
+    },
+    Intrinsic {
+        function_name: "Memory.peek",
+        n_args: 1,
+        lowering: Lowering::Asm(INTRINSIC_PEEK),
+    },
+    Intrinsic {
+        function_name: "Memory.poke",
+        n_args: 2,
+        lowering: Lowering::Asm(INTRINSIC_POKE),
+    },
+];
+
+/// The intrinsic replacing a call, if the options enable intrinsics. Panics
+/// when the call passes a different number of arguments than the intrinsic
+/// takes, as the function is then not the OS one.
+pub fn find(call: &Call, options: &CodegenOptions) -> Option<&'static Intrinsic> {
+    if !options.intrinsics {
+        return None;
+    }
+    let intrinsic = INTRINSICS
+        .iter()
+        .find(|intrinsic| intrinsic.function_name == call.function_name)?;
+    if intrinsic.n_args != call.n_args {
+        panic!(
+            "{} is an intrinsic taking {} arguments, but is called with {}, \
+             use --no-intrinsics to call the function instead",
+            call.function_name, intrinsic.n_args, call.n_args
+        );
+    }
+    Some(intrinsic)
+}
+
+impl Intrinsic {
+    /// The operator the intrinsic applies, if any.
+    pub fn operator(&self) -> Option<ArithmeticType> {
+        match self.lowering {
+            Lowering::Operator(operator) => Some(operator),
+            Lowering::Asm(_) => None,
+        }
+    }
+
+    pub fn create(
+        &self,
+        file_name: &str,
+        profile: TargetProfile,
+        comparison_count: &mut u16,
+    ) -> String {
+        match self.lowering {
+            Lowering::Operator(operator) => {
+                create_arithmetic_operator(operator, file_name, profile, comparison_count)
+            }
+            Lowering::Asm(asm) => asm.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "Math.multiply is an intrinsic taking 2 arguments")]
+    fn calls_must_match_the_intrinsic_arity() {
+        let call = Call::new(
+            &String::from("Math.multiply"),
+            &String::from("Main.main$ret.0"),
+            3,
+        );
+        find(&call, &CodegenOptions::default());
+    }
+
+    #[test]
+    fn disabled_intrinsics_are_not_found() {
+        let call = Call::new(
+            &String::from("Memory.peek"),
+            &String::from("Main.main$ret.0"),
+            1,
+        );
+        let options = CodegenOptions {
+            intrinsics: false,
+            ..CodegenOptions::default()
+        };
+        assert!(find(&call, &CodegenOptions::default()).is_some());
+        assert!(find(&call, &options).is_none());
+    }
+
+    #[test]
+    fn math_divide_keeps_its_zero_divisor_error() {
+        let call = Call::new(
+            &String::from("Math.divide"),
+            &String::from("Main.main$ret.0"),
+            2,
+        );
+        assert!(find(&call, &CodegenOptions::default()).is_none());
+    }
+}
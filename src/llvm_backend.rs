@@ -0,0 +1,486 @@
+use std::ops::Range;
+
+use crate::backend::Backend;
+use crate::frames;
+use crate::instructions::{
+    ArithmeticType, BinaryArithmeticOperator, Call, ExtendedArithmeticOperator, FrameKind,
+    Instruction, Module, Segment, ShiftArithmeticOperator, UnaryArithmeticOperator,
+};
+use crate::llvm_templates::{
+    LLVM_CALL, LLVM_CALL_LITE, LLVM_HALT, LLVM_PROLOGUE, LLVM_RETURN, LLVM_RETURN_LITE,
+};
+use crate::memory_layout::{io_address, MemoryLayout};
+use crate::passes::format_instruction;
+use crate::program_symbols::{function_end, is_halt_loop, ProgramSymbols};
+
+/// The LLVM target, an IR module with an LLVM function per VM function and a
+/// `@ram` array for the Hack RAM, which `main` writes to stdout once the
+/// program halts. LLVM 14 needs `-opaque-pointers` to read it.
+pub struct LlvmBackend<'a> {
+    layout: &'a MemoryLayout,
+    symbols: ProgramSymbols,
+    return_count: usize,
+    /// The number of values and blocks named so far in the current function.
+    values: usize,
+}
+
+impl<'a> LlvmBackend<'a> {
+    pub fn new(layout: &'a MemoryLayout) -> Self {
+        Self {
+            layout,
+            symbols: ProgramSymbols::new(&[]),
+            return_count: 0,
+            values: 0,
+        }
+    }
+}
+
+impl Backend for LlvmBackend<'_> {
+    fn file_extension(&self) -> &'static str {
+        "ll"
+    }
+
+    fn begin_program(&mut self, modules: &[Module]) -> String {
+        self.symbols = ProgramSymbols::new(modules);
+        let layout = self.layout;
+        let mut entry_call = Call::new(
+            &layout.entry_point,
+            &format!("{}&ret.0", layout.entry_point),
+            0,
+        );
+        entry_call.frame = frames::entry_frame(modules, &layout.entry_point);
+        LLVM_PROLOGUE
+            .replace("STACK_BASE", &layout.stack_base.to_string())
+            .replace("INITIAL_LCL", &layout.initial_lcl.to_string())
+            .replace("INITIAL_ARG", &layout.initial_arg.to_string())
+            .replace("INITIAL_THIS", &layout.initial_this.to_string())
+            .replace("INITIAL_THAT", &layout.initial_that.to_string())
+            .replace("ENTRY_CALL", &self.create_call(&entry_call))
+    }
+
+    fn begin_module(&mut self, module: &Module) -> String {
+        format!("\n; {}.vm\n", module.name)
+    }
+
+    /// Functions are translated whole, as each one is an LLVM function whose
+    /// blocks all have to end in a terminator.
+    fn emit_instruction(&mut self, module: &Module, position: usize) -> (String, usize) {
+        if !matches!(module.instructions[position], Instruction::CFunction(_)) {
+            panic!("Code outside of a function in {}.vm", module.name);
+        }
+        let end = function_end(&module.instructions, position);
+        let code = self.create_function(&module.instructions, position..end, &module.name);
+        (code, end - position)
+    }
+}
+
+impl LlvmBackend<'_> {
+    /// Translates the function whose instructions are at `range`, falling
+    /// through to each label with an explicit branch.
+    fn create_function(
+        &mut self,
+        instructions: &[Instruction],
+        range: Range<usize>,
+        module_name: &str,
+    ) -> String {
+        let function = match &instructions[range.start] {
+            Instruction::CFunction(function) => function,
+            _ => unreachable!(),
+        };
+        self.values = 0;
+        let mut code = format!(
+            "\n; {}\ndefine internal void {}() {{\nentry:\n",
+            format_instruction(&instructions[range.start]),
+            function_name(&function.function_name)
+        );
+        for _ in 0..function.n_args {
+            code.push_str("  call void @push(i16 0)\n");
+        }
+
+        let mut terminated = false;
+        for position in range.start + 1..range.end {
+            let instruction = &instructions[position];
+            let comment = format!("  ; {}\n", format_instruction(instruction));
+            if let Instruction::CLabel(label) = instruction {
+                let block = format!("L{}", self.symbols.label(label));
+                if !terminated {
+                    code.push_str(&format!("  br label %{}\n", block));
+                }
+                code.push_str(&format!("{}:\n{}", block, comment));
+                terminated = false;
+                continue;
+            }
+            if terminated {
+                let block = self.value();
+                code.push_str(&format!("B{}:\n", block));
+                terminated = false;
+            }
+            let instruction_code = match instruction {
+                Instruction::CArithmetic(operator) => comment + &self.arithmetic(*operator),
+                Instruction::CPush(push) => {
+                    let (load_code, value) = match push.segment {
+                        Segment::Constant => (String::new(), push.index.to_string()),
+                        segment => {
+                            let (address_code, word) =
+                                self.segment_word(segment, push.index, module_name);
+                            let value = format!("%v{}", self.value());
+                            let load = format!("  {} = call i16 @get(i16 {})\n", value, word);
+                            (address_code + &load, value)
+                        }
+                    };
+                    format!("{}{}  call void @push(i16 {})\n", comment, load_code, value)
+                }
+                Instruction::CPop(pop) => {
+                    if pop.segment == Segment::Constant {
+                        panic!("Cannot pop to constant");
+                    }
+                    let (address_code, word) =
+                        self.segment_word(pop.segment, pop.index, module_name);
+                    let value = format!("%v{}", self.value());
+                    format!(
+                        "{}{}  {} = call i16 @pop()\n  call void @set(i16 {}, i16 {})\n",
+                        comment, address_code, value, word, value
+                    )
+                }
+                Instruction::CLabel(_) | Instruction::CFunction(_) => unreachable!(),
+                Instruction::CGoto(_) if is_halt_loop(instructions, position) => {
+                    terminated = true;
+                    String::from(LLVM_HALT)
+                }
+                Instruction::CGoto(label) => {
+                    terminated = true;
+                    format!("{}  br label %L{}\n", comment, self.symbols.label(label))
+                }
+                Instruction::CIf(label) => {
+                    let value = self.value();
+                    format!(
+                        "{}  %v{value} = call i16 @pop()\n  \
+                         %jump.{value} = icmp ne i16 %v{value}, 0\n  \
+                         br i1 %jump.{value}, label %L{}, label %next.{value}\n\
+                         next.{value}:\n",
+                        comment,
+                        self.symbols.label(label),
+                        value = value
+                    )
+                }
+                Instruction::CReturn => {
+                    terminated = true;
+                    let template = match function.frame {
+                        FrameKind::Full => LLVM_RETURN,
+                        FrameKind::Lite => LLVM_RETURN_LITE,
+                    };
+                    template.replace("SUFFIX", &self.value().to_string())
+                }
+                Instruction::CCall(call) => self.create_call(call),
+                Instruction::CAsm(asm) => panic!(
+                    "{}: the asm block {:?} only compiles for the hack target, not llvm",
+                    module_name, asm
+                ),
+            };
+            code.push_str(&instruction_code);
+        }
+        if !terminated {
+            code.push_str("  ret void\n");
+        }
+        code + "}\n"
+    }
+
+    fn create_call(&mut self, call: &Call) -> String {
+        // Fails early for calls LLVM would reject
+        self.symbols.function(&call.function_name);
+        let (template, frame_size) = match call.frame {
+            FrameKind::Full => (LLVM_CALL, 5),
+            FrameKind::Lite => (LLVM_CALL_LITE, 3),
+        };
+        let return_id = self.return_count;
+        self.return_count += 1;
+        template
+            .replace("RETURN_ID", &(return_id as u16 as i16).to_string())
+            .replace(
+                "COMMENT",
+                &format_instruction(&Instruction::CCall(call.clone())),
+            )
+            .replace("FRAME_SIZE", &(frame_size + call.n_args).to_string())
+            .replace("FUNCTION_NAME", &function_name(&call.function_name))
+            .replace("SUFFIX", &self.value().to_string())
+    }
+
+    /// Numbers a new value or block of the current function.
+    fn value(&mut self) -> usize {
+        self.values += 1;
+        self.values
+    }
+
+    /// The code computing the RAM address of a segment slot, and the operand
+    /// holding it.
+    fn segment_word(
+        &mut self,
+        segment: Segment,
+        index: u16,
+        module_name: &str,
+    ) -> (String, String) {
+        let pointer = match segment {
+            Segment::Local => 1,
+            Segment::Argument => 2,
+            Segment::This => 3,
+            Segment::That => 4,
+            Segment::Pointer => return (String::new(), (3 + index).to_string()),
+            Segment::Temp => return (String::new(), self.layout.temp_address(index).to_string()),
+            Segment::Screen | Segment::Keyboard => {
+                return (String::new(), io_address(segment, index).to_string())
+            }
+            Segment::Static => {
+                let address = self.symbols.static_address(module_name, index);
+                return (String::new(), address.to_string());
+            }
+            Segment::Constant => unreachable!(),
+        };
+        let base = self.value();
+        let word = self.value();
+        (
+            format!(
+                "  %v{} = call i16 @get(i16 {})\n  %v{} = add i16 %v{}, {}\n",
+                base, pointer, word, base, index
+            ),
+            format!("%v{}", word),
+        )
+    }
+
+    /// Instructions applying an operator to the top of the stack.
+    fn arithmetic(&mut self, operator: ArithmeticType) -> String {
+        let x = self.value();
+        let result = self.value();
+        let operand = format!("%v{}", x);
+        let unary = |operation: String| {
+            format!(
+                "  %v{x} = call i16 @pop()\n  %v{result} = {}\n  call void @push(i16 %v{result})\n",
+                operation,
+                x = x,
+                result = result
+            )
+        };
+        let y = self.value();
+        let binary = |operation: &str| {
+            format!(
+                "  %v{y} = call i16 @pop()\n  %v{x} = call i16 @pop()\n  \
+                 %v{result} = {} i16 %v{x}, %v{y}\n  call void @push(i16 %v{result})\n",
+                operation,
+                x = x,
+                y = y,
+                result = result
+            )
+        };
+        // Comparisons give an i1, which becomes -1 or 0
+        let compare = |predicate: &str| {
+            format!(
+                "  %v{y} = call i16 @pop()\n  %v{x} = call i16 @pop()\n  \
+                 %compare.{result} = icmp {} i16 %v{x}, %v{y}\n  \
+                 %v{result} = sext i1 %compare.{result} to i16\n  \
+                 call void @push(i16 %v{result})\n",
+                predicate,
+                x = x,
+                y = y,
+                result = result
+            )
+        };
+        // Division is undefined for a zero divisor and overflows for the
+        // most negative value divided by -1, so it works on i32 values, where
+        // dividing by 65536 instead of 0 gives a quotient of 0 and leaves the
+        // dividend as the remainder
+        let divide = |operation: &str| {
+            format!(
+                "  %v{y} = call i16 @pop()\n  %v{x} = call i16 @pop()\n  \
+                 %wide_x.{result} = sext i16 %v{x} to i32\n  \
+                 %wide_y.{result} = sext i16 %v{y} to i32\n  \
+                 %by_zero.{result} = icmp eq i16 %v{y}, 0\n  \
+                 %divisor.{result} = select i1 %by_zero.{result}, i32 65536, i32 %wide_y.{result}\n  \
+                 %wide.{result} = {} i32 %wide_x.{result}, %divisor.{result}\n  \
+                 %v{result} = trunc i32 %wide.{result} to i16\n  \
+                 call void @push(i16 %v{result})\n",
+                operation,
+                x = x,
+                y = y,
+                result = result
+            )
+        };
+        match operator {
+            ArithmeticType::Unary(UnaryArithmeticOperator::Negate) => {
+                unary(format!("sub i16 0, {}", operand))
+            }
+            ArithmeticType::Unary(UnaryArithmeticOperator::Not) => {
+                unary(format!("xor i16 {}, -1", operand))
+            }
+            ArithmeticType::Binary(BinaryArithmeticOperator::Add) => binary("add"),
+            ArithmeticType::Binary(BinaryArithmeticOperator::Subtract) => binary("sub"),
+            ArithmeticType::Binary(BinaryArithmeticOperator::And) => binary("and"),
+            ArithmeticType::Binary(BinaryArithmeticOperator::Or) => binary("or"),
+            ArithmeticType::Binary(BinaryArithmeticOperator::Eq) => compare("eq"),
+            ArithmeticType::Binary(BinaryArithmeticOperator::Gt) => compare("sgt"),
+            ArithmeticType::Binary(BinaryArithmeticOperator::Lt) => compare("slt"),
+            ArithmeticType::Shift(ShiftArithmeticOperator::ShiftLeft, amount) => {
+                unary(format!("shl i16 {}, {}", operand, amount))
+            }
+            ArithmeticType::Shift(ShiftArithmeticOperator::ShiftRight, amount) => {
+                unary(format!("ashr i16 {}, {}", operand, amount))
+            }
+            ArithmeticType::Extended(ExtendedArithmeticOperator::Multiply) => binary("mul"),
+            ArithmeticType::Extended(ExtendedArithmeticOperator::Divide) => divide("sdiv"),
+            ArithmeticType::Extended(ExtendedArithmeticOperator::Modulo) => divide("srem"),
+            ArithmeticType::Extended(ExtendedArithmeticOperator::Xor) => binary("xor"),
+            ArithmeticType::Extended(ExtendedArithmeticOperator::UnsignedLt) => compare("ult"),
+            ArithmeticType::Extended(ExtendedArithmeticOperator::UnsignedGt) => compare("ugt"),
+        }
+    }
+}
+
+/// Quoted, as VM function names may contain characters LLVM names may not.
+fn function_name(name: &str) -> String {
+    format!("@\"{}\"", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+    use std::{env, fs};
+
+    use super::*;
+    use crate::backend;
+    use crate::compiler::CodegenOptions;
+    use crate::test_programs::{assert_same_ram, parse_modules, run_hack};
+
+    const SYS: &str = "function Sys.init 0
+        push constant 6000
+        pop pointer 1
+        push constant 3
+        push constant 5
+        call Main.power 2
+        pop that 0
+        push constant 182
+        push constant 182
+        call Main.multiply 2
+        pop that 1
+        push static 0
+        pop that 2
+        push constant 1000
+        push constant 0
+        call Main.extended 2
+        pop that 3
+        label HALT
+        goto HALT";
+
+    const MAIN: &str = "function Main.power 0
+        push static 0
+        push constant 1
+        add
+        pop static 0
+        push argument 1
+        push constant 0
+        gt
+        if-goto RECURSE
+        push constant 1
+        return
+        label RECURSE
+        push argument 0
+        push argument 0
+        push argument 1
+        push constant 1
+        sub
+        call Main.power 2
+        call Main.multiply 2
+        return
+        function Main.multiply 1
+        label LOOP
+        push argument 1
+        push constant 0
+        eq
+        if-goto DONE
+        push argument 1
+        push constant 1
+        and
+        push constant 0
+        eq
+        if-goto SKIP
+        push local 0
+        push argument 0
+        add
+        pop local 0
+        label SKIP
+        push argument 0
+        shiftleft
+        pop argument 0
+        push argument 1
+        shiftright
+        pop argument 1
+        goto LOOP
+        label DONE
+        push local 0
+        return
+        function Main.extended 0
+        push argument 0
+        push argument 1
+        mul
+        push argument 0
+        push argument 1
+        div
+        xor
+        push argument 0
+        push argument 1
+        mod
+        shl 2
+        add
+        push argument 0
+        push argument 1
+        ult
+        sub
+        push argument 0
+        shr 3
+        push argument 1
+        ugt
+        sub
+        return";
+
+    /// LLVM versions before 15 need to be told about opaque pointers.
+    fn run_llvm(modules: &[Module], layout: &MemoryLayout) -> Vec<i16> {
+        let directory = env::temp_dir().join(format!("vm_llvm_backend_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let source = directory.join("program.ll");
+        fs::write(
+            &source,
+            backend::compile_program(&mut LlvmBackend::new(layout), modules),
+        )
+        .unwrap();
+        let mut output = Command::new("lli")
+            .arg(&source)
+            .output()
+            .expect("Cannot run lli");
+        if !output.status.success() {
+            output = Command::new("lli")
+                .arg("-opaque-pointers")
+                .arg(&source)
+                .output()
+                .unwrap();
+        }
+        fs::remove_dir_all(&directory).unwrap();
+        assert!(
+            output.status.success(),
+            "The LLVM translation does not run: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output
+            .stdout
+            .chunks(2)
+            .map(|word| i16::from_le_bytes([word[0], word[1]]))
+            .collect()
+    }
+
+    #[test]
+    #[ignore = "needs lli, run with --ignored"]
+    fn llvm_translation_leaves_the_same_ram_as_hack_code() {
+        let layout = MemoryLayout::default();
+        let modules = parse_modules(&[("Sys", SYS), ("Main", MAIN)]);
+        let emulator = run_hack(&modules, &layout, &CodegenOptions::default());
+        assert_eq!(emulator.ram[6000..6004], [243, -32412, 0, 4001]);
+        let ram = run_llvm(&modules, &layout);
+        assert_same_ram(&ram, &emulator.ram, &modules, 6000..6004);
+    }
+}
@@ -0,0 +1,147 @@
+/// The Hack RAM is a global array of words, reached through addresses masked
+/// to 15 bits like the A register. The stack helpers are small internal
+/// functions, which LLVM inlines when optimizing.
+pub const LLVM_PROLOGUE: &str = "@ram = internal global [32768 x i16] zeroinitializer
+; Set when the program reaches Sys.halt, which unwinds every call
+@halted = internal global i1 false
+
+declare i64 @write(i32, ptr, i64)
+
+define internal ptr @address(i16 %word) {
+  %masked = and i16 %word, 32767
+  %index = zext i16 %masked to i64
+  %pointer = getelementptr [32768 x i16], ptr @ram, i64 0, i64 %index
+  ret ptr %pointer
+}
+
+define internal i16 @get(i16 %word) {
+  %pointer = call ptr @address(i16 %word)
+  %value = load i16, ptr %pointer
+  ret i16 %value
+}
+
+define internal void @set(i16 %word, i16 %value) {
+  %pointer = call ptr @address(i16 %word)
+  store i16 %value, ptr %pointer
+  ret void
+}
+
+define internal void @push(i16 %value) {
+  %sp = call i16 @get(i16 0)
+  call void @set(i16 %sp, i16 %value)
+  %next = add i16 %sp, 1
+  call void @set(i16 0, i16 %next)
+  ret void
+}
+
+define internal i16 @pop() {
+  %sp = call i16 @get(i16 0)
+  %next = sub i16 %sp, 1
+  call void @set(i16 0, i16 %next)
+  %value = call i16 @get(i16 %next)
+  ret i16 %value
+}
+
+; Runs the program until it halts and writes the RAM to stdout
+define i32 @main() {
+  call void @run()
+  %written = call i64 @write(i32 1, ptr @ram, i64 65536)
+  ret i32 0
+}
+
+define internal void @run() {
+entry:
+  ; Bootstrap
+  call void @set(i16 0, i16 STACK_BASE)
+  call void @set(i16 1, i16 INITIAL_LCL)
+  call void @set(i16 2, i16 INITIAL_ARG)
+  call void @set(i16 3, i16 INITIAL_THIS)
+  call void @set(i16 4, i16 INITIAL_THAT)
+ENTRY_CALL  ret void
+}
+";
+
+pub const LLVM_CALL: &str = "  ; COMMENT
+  call void @push(i16 RETURN_ID)
+  %lcl.SUFFIX = call i16 @get(i16 1)
+  call void @push(i16 %lcl.SUFFIX)
+  %arg.SUFFIX = call i16 @get(i16 2)
+  call void @push(i16 %arg.SUFFIX)
+  %this.SUFFIX = call i16 @get(i16 3)
+  call void @push(i16 %this.SUFFIX)
+  %that.SUFFIX = call i16 @get(i16 4)
+  call void @push(i16 %that.SUFFIX)
+  %sp.SUFFIX = call i16 @get(i16 0)
+  %callee_arg.SUFFIX = sub i16 %sp.SUFFIX, FRAME_SIZE
+  call void @set(i16 2, i16 %callee_arg.SUFFIX)
+  call void @set(i16 1, i16 %sp.SUFFIX)
+  call void FUNCTION_NAME()
+  %halted.SUFFIX = load i1, ptr @halted
+  br i1 %halted.SUFFIX, label %unwind.SUFFIX, label %return.SUFFIX
+unwind.SUFFIX:
+  ret void
+return.SUFFIX:
+";
+
+pub const LLVM_CALL_LITE: &str = "  ; COMMENT
+  call void @push(i16 RETURN_ID)
+  %lcl.SUFFIX = call i16 @get(i16 1)
+  call void @push(i16 %lcl.SUFFIX)
+  %arg.SUFFIX = call i16 @get(i16 2)
+  call void @push(i16 %arg.SUFFIX)
+  %sp.SUFFIX = call i16 @get(i16 0)
+  %callee_arg.SUFFIX = sub i16 %sp.SUFFIX, FRAME_SIZE
+  call void @set(i16 2, i16 %callee_arg.SUFFIX)
+  call void @set(i16 1, i16 %sp.SUFFIX)
+  call void FUNCTION_NAME()
+  %halted.SUFFIX = load i1, ptr @halted
+  br i1 %halted.SUFFIX, label %unwind.SUFFIX, label %return.SUFFIX
+unwind.SUFFIX:
+  ret void
+return.SUFFIX:
+";
+
+/// The return address in the frame is not needed, as returning from the LLVM
+/// function goes back to the caller.
+pub const LLVM_RETURN: &str = "  ; return
+  %frame.SUFFIX = call i16 @get(i16 1)
+  %value.SUFFIX = call i16 @pop()
+  %arg.SUFFIX = call i16 @get(i16 2)
+  call void @set(i16 %arg.SUFFIX, i16 %value.SUFFIX)
+  %sp.SUFFIX = add i16 %arg.SUFFIX, 1
+  call void @set(i16 0, i16 %sp.SUFFIX)
+  %that_slot.SUFFIX = sub i16 %frame.SUFFIX, 1
+  %that.SUFFIX = call i16 @get(i16 %that_slot.SUFFIX)
+  call void @set(i16 4, i16 %that.SUFFIX)
+  %this_slot.SUFFIX = sub i16 %frame.SUFFIX, 2
+  %this.SUFFIX = call i16 @get(i16 %this_slot.SUFFIX)
+  call void @set(i16 3, i16 %this.SUFFIX)
+  %arg_slot.SUFFIX = sub i16 %frame.SUFFIX, 3
+  %caller_arg.SUFFIX = call i16 @get(i16 %arg_slot.SUFFIX)
+  call void @set(i16 2, i16 %caller_arg.SUFFIX)
+  %lcl_slot.SUFFIX = sub i16 %frame.SUFFIX, 4
+  %lcl.SUFFIX = call i16 @get(i16 %lcl_slot.SUFFIX)
+  call void @set(i16 1, i16 %lcl.SUFFIX)
+  ret void
+";
+
+pub const LLVM_RETURN_LITE: &str = "  ; return // lite frame
+  %frame.SUFFIX = call i16 @get(i16 1)
+  %value.SUFFIX = call i16 @pop()
+  %arg.SUFFIX = call i16 @get(i16 2)
+  call void @set(i16 %arg.SUFFIX, i16 %value.SUFFIX)
+  %sp.SUFFIX = add i16 %arg.SUFFIX, 1
+  call void @set(i16 0, i16 %sp.SUFFIX)
+  %arg_slot.SUFFIX = sub i16 %frame.SUFFIX, 1
+  %caller_arg.SUFFIX = call i16 @get(i16 %arg_slot.SUFFIX)
+  call void @set(i16 2, i16 %caller_arg.SUFFIX)
+  %lcl_slot.SUFFIX = sub i16 %frame.SUFFIX, 2
+  %lcl.SUFFIX = call i16 @get(i16 %lcl_slot.SUFFIX)
+  call void @set(i16 1, i16 %lcl.SUFFIX)
+  ret void
+";
+
+pub const LLVM_HALT: &str = "  ; halt
+  store i1 true, ptr @halted
+  ret void
+";
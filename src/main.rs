@@ -8,21 +8,70 @@ use std::{
 };
 
 mod asm_templates;
+mod backend;
+mod c_backend;
+mod c_templates;
 mod compiler;
+mod control_flow;
+mod frames;
+#[cfg(test)]
+mod hack_emulator;
+mod hack_syntax;
+mod inline;
 mod instructions;
+mod intrinsics;
+mod llvm_backend;
+mod llvm_templates;
+mod memory_layout;
 mod parser;
+mod passes;
+mod program_symbols;
+mod register_backend;
+mod register_ir;
+mod routines;
+mod stack_batch;
+mod stack_cache;
+#[cfg(test)]
+mod test_programs;
+#[cfg(test)]
+mod wasm_interpreter;
+mod wat_backend;
+mod wat_templates;
+mod x86_backend;
+mod x86_templates;
 
-static VM_FILE_EXTENSION: &'static str = "vm";
-static ASM_FILE_EXTENSION: &'static str = "asm";
+use backend::Backend;
+use compiler::{CodegenOptions, StackStrategy, TargetProfile};
+use instructions::Module;
+use memory_layout::MemoryLayout;
+use passes::{InlineFunctions, LiteFrames, PassPipeline, SimplifyControlFlow};
+
+static VM_FILE_EXTENSION: &str = "vm";
+static ASM_FILE_EXTENSION: &str = "asm";
+
+const USAGE: &str = "Invalid usage, please use: VMTranslator [--entry=<function>] [--halt] \
+                     [--stack-strategy=<memory|cache-top|batch-sp|registers>] \
+                     [--profile=<extended|standard>] [--no-intrinsics] \
+                     [-O0|-O1|-O2|-Os] [--passes=<pass>,...] [--dump-passes] [--lite-frames] \
+                     [--inline-limit=<instructions>] [--simplify-jumps] [--target=<target>] \
+                     <input path>";
+
+struct Arguments {
+    input_path: String,
+    layout: MemoryLayout,
+    codegen: CodegenOptions,
+    pipeline: PassPipeline,
+    /// One of `backend::TARGETS`.
+    target: String,
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        panic!("Invalid usage, please use: VMTranslator <input path>")
-    }
-    let argument_path = env::args().nth(1).expect("No path provided");
-    let argument_path = fs::canonicalize(&argument_path).expect("Invalid path provided");
-    let output_path = create_vm_file_path(&argument_path).unwrap();
+    let arguments = parse_arguments(env::args().skip(1).collect());
+    let argument_path = fs::canonicalize(&arguments.input_path).expect("Invalid path provided");
+    let mut backend =
+        backend::create(&arguments.target, &arguments.layout, &arguments.codegen).unwrap();
+    let mut output_path = create_vm_file_path(&argument_path).unwrap();
+    output_path.set_extension(backend.file_extension());
 
     let files_to_compile: Vec<PathBuf> = if argument_path.is_dir() {
         fs::read_dir(&argument_path)
@@ -33,16 +82,126 @@ fn main() {
         vec![argument_path]
     };
 
-    let _ = File::create(&output_path).unwrap(); // Wipe the file if it exists
-
-    compile_files(files_to_compile, &output_path);
+    compile_files(
+        files_to_compile,
+        &output_path,
+        &arguments.pipeline,
+        backend.as_mut(),
+    );
 }
 
-fn compile_files(input_paths: Vec<PathBuf>, output_path: &PathBuf) {
-    let bootstrap = compiler::create_bootstrap_code();
-    append_to_file(output_path, vec![bootstrap]);
+fn parse_arguments(args: Vec<String>) -> Arguments {
+    let mut layout = MemoryLayout::default();
+    let mut codegen = CodegenOptions::default();
+    let mut level: Option<String> = None;
+    let mut pass_names: Option<String> = None;
+    let mut inline_limit: Option<usize> = None;
+    let mut lite_frames = false;
+    let mut simplify_jumps = false;
+    let mut dump_passes = false;
+    let mut hack_only_flags: Vec<String> = vec![];
+    let mut target = String::from(backend::TARGETS[0]);
+    let mut argument_path: Option<String> = None;
+    for arg in args {
+        if let Some(entry_point) = arg.strip_prefix("--entry=") {
+            if entry_point.is_empty() {
+                panic!("{}", USAGE);
+            }
+            layout.entry_point = entry_point.to_string();
+        } else if arg == "--halt" {
+            layout.halt_after_entry = true;
+        } else if let Some(strategy) = arg.strip_prefix("--stack-strategy=") {
+            codegen.stack_strategy = StackStrategy::from(strategy).expect(USAGE);
+            hack_only_flags.push(arg);
+        } else if let Some(profile) = arg.strip_prefix("--profile=") {
+            codegen.profile = TargetProfile::from(profile).expect(USAGE);
+            hack_only_flags.push(arg);
+        } else if arg == "--no-intrinsics" {
+            codegen.intrinsics = false;
+            hack_only_flags.push(arg);
+        } else if let Some(optimization) = arg.strip_prefix("-O") {
+            level = Some(optimization.to_string());
+        } else if let Some(names) = arg.strip_prefix("--passes=") {
+            pass_names = Some(names.to_string());
+        } else if arg == "--dump-passes" {
+            dump_passes = true;
+        } else if let Some(name) = arg.strip_prefix("--target=") {
+            if !backend::TARGETS.contains(&name) {
+                panic!(
+                    "Unknown target {}, please use one of: {}",
+                    name,
+                    backend::TARGETS.join(", ")
+                );
+            }
+            target = name.to_string();
+        } else if arg == "--lite-frames" {
+            lite_frames = true;
+        } else if let Some(limit) = arg.strip_prefix("--inline-limit=") {
+            inline_limit = Some(limit.parse().expect(USAGE));
+        } else if arg == "--simplify-jumps" {
+            simplify_jumps = true;
+        } else if arg.starts_with('-') || argument_path.is_some() {
+            panic!("{}", USAGE);
+        } else {
+            argument_path = Some(arg);
+        }
+    }
+
+    // The other targets have no Hack code generator to configure
+    if target != "hack" && !hack_only_flags.is_empty() {
+        panic!(
+            "{} only applies to the hack target, not {}",
+            hack_only_flags.join(", "),
+            target
+        );
+    }
 
+    // A custom pass list replaces the optimization level's
+    let mut pipeline = match pass_names {
+        Some(names) => {
+            PassPipeline::from_names(&names, inline_limit.unwrap_or(passes::DEFAULT_INLINE_LIMIT))
+        }
+        None => PassPipeline::preset(level.as_deref().unwrap_or("0"), inline_limit),
+    }
+    .expect(USAGE);
+    // The single pass flags add their pass when the pipeline lacks it
+    if let Some(size_limit) = inline_limit {
+        if size_limit > 0 && !pipeline.contains("inline") {
+            pipeline.add(Box::new(InlineFunctions { size_limit }));
+        }
+    }
+    if simplify_jumps && !pipeline.contains("simplify-jumps") {
+        pipeline.add(Box::new(SimplifyControlFlow));
+    }
+    if lite_frames && !pipeline.contains("lite-frames") {
+        pipeline.add(Box::new(LiteFrames));
+    }
+    pipeline.dump_after_each = dump_passes;
+
+    if let Err(error) = layout.validate() {
+        panic!("{}", error);
+    }
+
+    match argument_path {
+        Some(input_path) => Arguments {
+            input_path,
+            layout,
+            codegen,
+            pipeline,
+            target,
+        },
+        None => panic!("{}", USAGE),
+    }
+}
+
+fn compile_files(
+    input_paths: Vec<PathBuf>,
+    output_path: &PathBuf,
+    pipeline: &PassPipeline,
+    backend: &mut dyn Backend,
+) {
     let mut function_calls: HashMap<String, u16> = HashMap::new();
+    let mut modules: Vec<Module> = vec![];
     for input_path in input_paths {
         if let Some(extension) = input_path.extension() {
             if extension.to_str().unwrap_or("").to_lowercase() != VM_FILE_EXTENSION {
@@ -52,13 +211,22 @@ fn compile_files(input_paths: Vec<PathBuf>, output_path: &PathBuf) {
             continue;
         }
 
-        let input_file = PathBuf::from(input_path);
-
-        compile_file(input_file, output_path, &mut function_calls);
+        modules.push(parse_file(input_path, &mut function_calls));
     }
+    if let Err(error) = backend::check_program(backend, &modules) {
+        panic!("{}", error);
+    }
+
+    pipeline.run(&mut modules);
+
+    let _ = File::create(output_path).unwrap(); // Wipe the file if it exists
+    append_to_file(
+        output_path,
+        vec![backend::compile_program(backend, &modules)],
+    );
 }
 
-fn compile_file(input_path: PathBuf, output_path: &PathBuf, function_calls: &mut HashMap<String, u16>) {
+fn parse_file(input_path: PathBuf, function_calls: &mut HashMap<String, u16>) -> Module {
     let contents: String =
         fs::read_to_string(&input_path).expect("Should have been able to read file");
     let lines: Vec<String> = contents.split("\n").map(|s| s.trim().to_string()).collect();
@@ -66,19 +234,13 @@ fn compile_file(input_path: PathBuf, output_path: &PathBuf, function_calls: &mut
     let file_name = &input_path.file_stem().unwrap().to_str().unwrap();
     let instructions = parser::parse(lines, function_calls);
 
-    let compiled_asm = compiler::compile(instructions, file_name);
-
-    append_to_file(output_path, compiled_asm);
+    Module::new(file_name, instructions)
 }
 
 fn append_to_file(path: &PathBuf, s: Vec<String>) {
-    let mut file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .open(path)
-        .unwrap();
+    let mut file = OpenOptions::new().append(true).open(path).unwrap();
     for line in s {
-        file.write(line.as_bytes()).unwrap();
+        file.write_all(line.as_bytes()).unwrap();
     }
 }
 
@@ -105,3 +267,35 @@ fn create_vm_file_path(input: &Path) -> Result<PathBuf, String> {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arguments(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn hack_flags_configure_the_hack_target() {
+        let parsed = parse_arguments(arguments(&[
+            "--target=hack",
+            "--stack-strategy=registers",
+            "--profile=standard",
+            "--no-intrinsics",
+            "Main.vm",
+        ]));
+        assert_eq!(parsed.codegen.stack_strategy, StackStrategy::Registers);
+        assert_eq!(parsed.codegen.profile, TargetProfile::Standard);
+        assert!(!parsed.codegen.intrinsics);
+    }
+
+    #[test]
+    #[should_panic(expected = "--stack-strategy=registers only applies to the hack target, not c")]
+    fn hack_flags_are_rejected_for_other_targets() {
+        parse_arguments(arguments(&[
+            "--target=c",
+            "--stack-strategy=registers",
+            "Main.vm",
+        ]));
+    }
+}
@@ -0,0 +1,158 @@
+use crate::instructions::Segment;
+
+/// Describes where the translator places the stack and the fixed segments, and
+/// what the bootstrap code initialises before handing control to the program.
+///
+/// The defaults follow the nand2tetris reference bootstrap: the stack starts at
+/// 256, temp lives at RAM[5..13] and LCL/ARG/THIS/THAT hold the sentinel values
+/// -1, -2, -3 and -4 until the entry function sets up a real frame.
+///
+/// `entry_point` is the function the bootstrap calls. When `halt_after_entry` is
+/// set, the bootstrap parks the CPU in an infinite loop once that function
+/// returns, so programs without a `Sys.init` that never returns still stop.
+#[derive(Debug, Clone)]
+pub struct MemoryLayout {
+    pub stack_base: u16,
+    pub temp_base: u16,
+    pub initial_lcl: i16,
+    pub initial_arg: i16,
+    pub initial_this: i16,
+    pub initial_that: i16,
+    pub entry_point: String,
+    pub halt_after_entry: bool,
+}
+
+impl MemoryLayout {
+    pub fn temp_address(&self, index: u16) -> u16 {
+        self.temp_base + index
+    }
+
+    /// Checks that temp and the stack stay clear of the RAM the generated code
+    /// reserves and of each other, and that the stack ends below the screen.
+    pub fn validate(&self) -> Result<(), String> {
+        let temp = (self.temp_base as u32, self.temp_base as u32 + TEMP_WORDS);
+        let stack = (self.stack_base as u32, SCREEN_ADDRESS as u32);
+        if stack.0 >= stack.1 {
+            return Err(format!(
+                "The stack base {} must be below the screen at {}",
+                self.stack_base, SCREEN_ADDRESS
+            ));
+        }
+        for (name, reserved) in RESERVED_RAM {
+            if overlaps(stack, reserved) {
+                return Err(format!(
+                    "The stack at {} overlaps the {} at RAM[{}..{}]",
+                    self.stack_base, name, reserved.0, reserved.1
+                ));
+            }
+        }
+        for (name, reserved) in RESERVED_RAM.iter().chain(&[("stack", stack)]) {
+            if overlaps(temp, *reserved) {
+                return Err(format!(
+                    "Temp at RAM[{}..{}] overlaps the {} at RAM[{}..{}]",
+                    temp.0, temp.1, name, reserved.0, reserved.1
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+const TEMP_WORDS: u32 = 8;
+
+/// The RAM ranges the generated code uses outside of temp and the stack. The
+/// assembler places the routine variables with the statics.
+const RESERVED_RAM: [(&str, (u32, u32)); 3] = [
+    ("segment pointers", (0, 5)),
+    ("scratch registers", (13, 16)),
+    ("static variables", (16, 256)),
+];
+
+fn overlaps(a: (u32, u32), b: (u32, u32)) -> bool {
+    a.0 < b.1 && b.0 < a.1
+}
+
+impl Default for MemoryLayout {
+    fn default() -> Self {
+        Self {
+            stack_base: 256,
+            temp_base: 5,
+            initial_lcl: -1,
+            initial_arg: -2,
+            initial_this: -3,
+            initial_that: -4,
+            entry_point: String::from("Sys.init"),
+            halt_after_entry: false,
+        }
+    }
+}
+
+/// The first word of the memory-mapped screen, addressed by `screen 0`.
+pub const SCREEN_ADDRESS: u16 = 16384;
+/// The words the screen maps: 256 rows of 512 pixels, 16 pixels to a word.
+pub const SCREEN_WORDS: u16 = 8192;
+/// The memory-mapped keyboard register, addressed by `keyboard 0`.
+pub const KEYBOARD_ADDRESS: u16 = 24576;
+
+/// The RAM address of a `screen` or `keyboard` slot.
+pub fn io_address(segment: Segment, index: u16) -> u16 {
+    match segment {
+        Segment::Screen => SCREEN_ADDRESS + index,
+        Segment::Keyboard => KEYBOARD_ADDRESS + index,
+        _ => panic!("Not a memory-mapped segment: {}", segment),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_the_default_layout() {
+        assert_eq!(MemoryLayout::default().validate(), Ok(()));
+        let layout = MemoryLayout {
+            stack_base: 1000,
+            temp_base: 300,
+            ..MemoryLayout::default()
+        };
+        assert_eq!(layout.validate(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_overlapping_regions() {
+        for (stack_base, temp_base, error) in [
+            (
+                16384,
+                5,
+                "The stack base 16384 must be below the screen at 16384",
+            ),
+            (
+                200,
+                5,
+                "The stack at 200 overlaps the static variables at RAM[16..256]",
+            ),
+            (
+                256,
+                8,
+                "Temp at RAM[8..16] overlaps the scratch registers at RAM[13..16]",
+            ),
+            (
+                256,
+                0,
+                "Temp at RAM[0..8] overlaps the segment pointers at RAM[0..5]",
+            ),
+            (
+                256,
+                300,
+                "Temp at RAM[300..308] overlaps the stack at RAM[256..16384]",
+            ),
+        ] {
+            let layout = MemoryLayout {
+                stack_base,
+                temp_base,
+                ..MemoryLayout::default()
+            };
+            assert_eq!(layout.validate(), Err(String::from(error)));
+        }
+    }
+}
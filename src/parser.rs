@@ -1,23 +1,29 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Once;
 
+use crate::compiler::TargetProfile;
+use crate::hack_syntax::{check_instruction, is_hack_symbol};
 use crate::instructions::{
-    ArithmeticType, BinaryArithmeticOperator, Call, Function, Instruction, Label, Pop, Push,
-    Segment, ShiftArithmeticOperator, UnaryArithmeticOperator,
+    ArithmeticType, BinaryArithmeticOperator, Call, ExtendedArithmeticOperator, Function,
+    Instruction, Label, Pop, Push, Segment, ShiftArithmeticOperator, UnaryArithmeticOperator,
 };
+use crate::memory_layout::SCREEN_WORDS;
 
 const COMMENT_BEGIN: &str = "//";
 
-const OPERANDS_MEMORY: [&'static str; 2] = ["push", "pop"];
-const OPERANDS_GOTO: [&'static str; 2] = ["goto", "if-goto"];
+const OPERANDS_MEMORY: [&str; 2] = ["push", "pop"];
+const OPERANDS_GOTO: [&str; 2] = ["goto", "if-goto"];
+const OPERANDS_SHIFT: [&str; 2] = ["shl", "shr"];
 
-const OPERAND_LABEL: &'static str = "label";
+const OPERAND_LABEL: &str = "label";
 
-const OPERAND_CALL: &'static str = "call";
+const OPERAND_CALL: &str = "call";
 
-const OPERAND_FUNCTION: &'static str = "function";
+const OPERAND_FUNCTION: &str = "function";
 
-const OPERAND_RETURN: &'static str = "return";
+const OPERAND_RETURN: &str = "return";
+
+const OPERAND_ASM: &str = "asm";
 
 static mut CONST_HASHMAP: Option<HashMap<&'static str, Instruction>> = None;
 static INIT: Once = Once::new();
@@ -59,12 +65,16 @@ fn get_const_hashmap() -> &'static HashMap<&'static str, Instruction> {
             );
             map.insert(
                 "shiftleft",
-                Instruction::CArithmetic(ArithmeticType::Shift(ShiftArithmeticOperator::ShiftLeft)),
+                Instruction::CArithmetic(ArithmeticType::Shift(
+                    ShiftArithmeticOperator::ShiftLeft,
+                    1,
+                )),
             );
             map.insert(
                 "shiftright",
                 Instruction::CArithmetic(ArithmeticType::Shift(
                     ShiftArithmeticOperator::ShiftRight,
+                    1,
                 )),
             );
             map.insert(
@@ -79,9 +89,22 @@ fn get_const_hashmap() -> &'static HashMap<&'static str, Instruction> {
                 "lt",
                 Instruction::CArithmetic(ArithmeticType::Binary(BinaryArithmeticOperator::Lt)),
             );
+            for (name, operator) in [
+                ("mul", ExtendedArithmeticOperator::Multiply),
+                ("div", ExtendedArithmeticOperator::Divide),
+                ("mod", ExtendedArithmeticOperator::Modulo),
+                ("xor", ExtendedArithmeticOperator::Xor),
+                ("ult", ExtendedArithmeticOperator::UnsignedLt),
+                ("ugt", ExtendedArithmeticOperator::UnsignedGt),
+            ] {
+                map.insert(
+                    name,
+                    Instruction::CArithmetic(ArithmeticType::Extended(operator)),
+                );
+            }
             CONST_HASHMAP = Some(map);
         });
-        CONST_HASHMAP.as_ref().unwrap()
+        (*std::ptr::addr_of!(CONST_HASHMAP)).as_ref().unwrap()
     }
 }
 
@@ -92,41 +115,45 @@ pub fn parse(lines: Vec<String>, function_calls: &mut HashMap<String, u16>) -> V
     let mut current_function: String = String::new();
 
     let mut parsed_lines: Vec<Instruction> = vec![];
-    for line in whitespace_cleaned_lines {
+    let mut lines = whitespace_cleaned_lines.into_iter();
+    while let Some(line) = lines.next() {
+        // Inline assembly
+        if let Some(instruction) = operand_asm(&line, &mut lines) {
+            parsed_lines.push(instruction);
+            continue;
+        }
+
         // Arithmetic
-        match operands_arithmetic_implicit.get(line.as_str()) {
-            Some(instr) => {
-                parsed_lines.push(instr.to_owned());
-                continue;
-            }
-            None => {}
+        if let Some(instr) = operands_arithmetic_implicit.get(line.as_str()) {
+            parsed_lines.push(instr.to_owned());
+            continue;
+        }
+
+        // Shifts by a constant
+        if let Some(instruction) = operand_shift(&line) {
+            parsed_lines.push(instruction);
+            continue;
         }
 
         // Memory operations
-        match operand_memory(&line) {
-            Some(instruction) => {
-                parsed_lines.push(instruction);
-                continue;
-            }
-            None => {}
+        if let Some(instruction) = operand_memory(&line) {
+            parsed_lines.push(instruction);
+            continue;
         }
 
         // Labels
         if line.starts_with(OPERAND_LABEL) {
             parsed_lines.push(Instruction::CLabel(Label::new(
                 &current_function,
-                &line.split_whitespace().nth(1).unwrap().to_string(),
+                &label_name(&line),
             )));
             continue;
         }
 
         // Jumps
-        match operand_gotos(&line, &current_function) {
-            Some(instruction) => {
-                parsed_lines.push(instruction);
-                continue;
-            }
-            None => {}
+        if let Some(instruction) = operand_gotos(&line, &current_function) {
+            parsed_lines.push(instruction);
+            continue;
         }
 
         // Call a function
@@ -136,8 +163,9 @@ pub fn parse(lines: Vec<String>, function_calls: &mut HashMap<String, u16>) -> V
             let function_name: String = details.next().unwrap().to_string();
             let n_args: u16 = details.next().unwrap().parse().unwrap();
 
-            current_function = function_name.clone();
-            let n_calls: u16 = match function_calls.get(&current_function) {
+            // Return addresses are numbered per callee, so they stay unique
+            // across every file sharing `function_calls`
+            let n_calls: u16 = match function_calls.get(&function_name) {
                 Some(e) => *e,
                 None => 1,
             };
@@ -149,7 +177,7 @@ pub fn parse(lines: Vec<String>, function_calls: &mut HashMap<String, u16>) -> V
                 n_args,
             )));
             function_calls.insert(
-                current_function.clone(),
+                function_name,
                 match n_calls {
                     1 => 2,
                     _ => n_calls + 1,
@@ -159,12 +187,9 @@ pub fn parse(lines: Vec<String>, function_calls: &mut HashMap<String, u16>) -> V
         }
 
         // Create a function
-        match operand_function(&line, &mut current_function) {
-            Some(instruction) => {
-                parsed_lines.push(instruction);
-                continue;
-            }
-            None => {}
+        if let Some(instruction) = operand_function(&line, &mut current_function) {
+            parsed_lines.push(instruction);
+            continue;
         }
 
         // return
@@ -190,7 +215,7 @@ fn clear_whitespace(lines: Vec<String>) -> Vec<String> {
     whitespace_cleaned_lines
 }
 
-fn operand_memory(line: &String) -> Option<Instruction> {
+fn operand_memory(line: &str) -> Option<Instruction> {
     for operand in OPERANDS_MEMORY {
         if !line.starts_with(operand) {
             continue;
@@ -202,6 +227,19 @@ fn operand_memory(line: &String) -> Option<Instruction> {
 
         let segment: Segment = Segment::from(unparsed_segment);
         let index: u16 = unparsed_index.parse().unwrap();
+        match segment {
+            Segment::Screen if index >= SCREEN_WORDS => panic!(
+                "Screen index {} in \"{}\" must be below {}",
+                index, line, SCREEN_WORDS
+            ),
+            Segment::Keyboard if index != 0 => {
+                panic!("Keyboard index {} in \"{}\" must be 0", index, line)
+            }
+            Segment::Keyboard if operand == "pop" => {
+                panic!("Cannot pop to the read-only keyboard in \"{}\"", line)
+            }
+            _ => {}
+        }
         match operand {
             "push" => {
                 return Some(Instruction::CPush(Push::new(segment, index)));
@@ -215,29 +253,81 @@ fn operand_memory(line: &String) -> Option<Instruction> {
     None
 }
 
-fn operand_gotos(line: &String, current_function: &String) -> Option<Instruction> {
+fn operand_shift(line: &str) -> Option<Instruction> {
+    let mut line_details = line.split_whitespace();
+    let operator = match line_details.next() {
+        Some(operand) if operand == OPERANDS_SHIFT[0] => ShiftArithmeticOperator::ShiftLeft,
+        Some(operand) if operand == OPERANDS_SHIFT[1] => ShiftArithmeticOperator::ShiftRight,
+        _ => return None,
+    };
+    let amount: u16 = line_details.next().unwrap().parse().unwrap();
+    if amount >= 16 {
+        panic!("Shift amount {} in \"{}\" must be below 16", amount, line);
+    }
+    Some(Instruction::CArithmetic(ArithmeticType::Shift(
+        operator, amount,
+    )))
+}
+
+/// Parses `asm "<instruction>"` or an `asm {` line along with the lines up to
+/// the closing `}`, checking every instruction.
+fn operand_asm(line: &str, lines: &mut impl Iterator<Item = String>) -> Option<Instruction> {
+    let rest = line.strip_prefix(OPERAND_ASM)?.trim();
+    let asm: Vec<String> = if rest == "{" {
+        let mut block = vec![];
+        loop {
+            match lines.next() {
+                Some(line) if line.trim() == "}" => break,
+                Some(line) => block.push(line.trim().to_string()),
+                None => panic!("The asm block is not closed with a `}}`"),
+            }
+        }
+        block
+    } else if rest.len() >= 2 && rest.starts_with('"') && rest.ends_with('"') {
+        vec![rest[1..rest.len() - 1].trim().to_string()]
+    } else {
+        return None;
+    };
+    let mut labels = HashSet::new();
+    for instruction in &asm {
+        // Only the code generator knows the profile, and its `check_profile`
+        // rejects the shifts for the standard CPU
+        if let Err(reason) = check_instruction(instruction, TargetProfile::Extended) {
+            panic!("Invalid asm instruction \"{}\": {}", instruction, reason);
+        }
+        if instruction.starts_with('(') && !labels.insert(instruction) {
+            panic!("The asm label {} is defined twice", instruction);
+        }
+    }
+    Some(Instruction::CAsm(asm))
+}
+
+/// The label a `label`, `goto` or `if-goto` line names. The generated labels
+/// use `$`, so VM labels may not.
+fn label_name(line: &str) -> String {
+    let label = line.split_whitespace().nth(1).unwrap();
+    if label.contains('$') || !is_hack_symbol(label) {
+        panic!("Invalid label \"{}\" in \"{}\"", label, line);
+    }
+    label.to_string()
+}
+
+fn operand_gotos(line: &str, current_function: &String) -> Option<Instruction> {
     for operand in OPERANDS_GOTO {
         if !line.starts_with(operand) {
             continue;
         }
-        let mut line_details = line.split_whitespace();
-        let target = line_details.nth(1).unwrap();
+        let target = label_name(line);
         return match operand {
-            "goto" => Some(Instruction::CGoto(Label::new(
-                current_function,
-                &target.to_string(),
-            ))),
-            "if-goto" => Some(Instruction::CIf(Label::new(
-                current_function,
-                &target.to_string(),
-            ))),
+            "goto" => Some(Instruction::CGoto(Label::new(current_function, &target))),
+            "if-goto" => Some(Instruction::CIf(Label::new(current_function, &target))),
             _ => panic!("Invalid operand {}", operand),
         };
     }
     None
 }
 
-fn operand_function(line: &String, current_function: &mut String) -> Option<Instruction> {
+fn operand_function(line: &str, current_function: &mut String) -> Option<Instruction> {
     if !line.starts_with(OPERAND_FUNCTION) {
         return None;
     }
@@ -247,7 +337,52 @@ fn operand_function(line: &String, current_function: &mut String) -> Option<Inst
     let n_args: u16 = line_details.next().unwrap().parse().unwrap();
 
     Some(Instruction::CFunction(Function::new(
-        &current_function,
+        current_function,
         n_args,
     )))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_after_a_call_stay_in_the_calling_function() {
+        let source = "function Main.main 0
+            call Main.helper 0
+            label LOOP
+            if-goto LOOP
+            goto LOOP
+            function Main.helper 0
+            label LOOP";
+        let lines = source.lines().map(|line| line.trim().to_string()).collect();
+        let labels: Vec<String> = parse(lines, &mut HashMap::new())
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::CLabel(label)
+                | Instruction::CGoto(label)
+                | Instruction::CIf(label) => Some(label.extract_label_name()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                "Main.main$LOOP",
+                "Main.main$LOOP",
+                "Main.main$LOOP",
+                "Main.helper$LOOP"
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid label \"$INIT_LOCALS\" in \"label $INIT_LOCALS\"")]
+    fn labels_may_not_contain_dollar_signs() {
+        let lines = vec![
+            String::from("function Main.main 0"),
+            String::from("label $INIT_LOCALS"),
+        ];
+        parse(lines, &mut HashMap::new());
+    }
+}
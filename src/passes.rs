@@ -0,0 +1,282 @@
+use std::io::Write;
+
+use crate::instructions::{
+    ArithmeticType, BinaryArithmeticOperator, ExtendedArithmeticOperator, FrameKind, Instruction,
+    Module, Segment, ShiftArithmeticOperator, UnaryArithmeticOperator,
+};
+use crate::{control_flow, frames, inline};
+
+/// The inlining size limit `-O2` uses unless `--inline-limit` says otherwise.
+pub const DEFAULT_INLINE_LIMIT: usize = 16;
+
+/// The inlining size limit `-Os` uses. Bodies this small take fewer
+/// instructions than the call and return that reach them.
+const SIZE_INLINE_LIMIT: usize = 3;
+
+/// A whole-program transformation run between parsing and code generation.
+pub trait Pass {
+    /// The name `--passes` refers to the pass by.
+    fn name(&self) -> &'static str;
+
+    fn run(&self, modules: &mut [Module]);
+}
+
+/// See `inline::inline_functions`.
+pub struct InlineFunctions {
+    pub size_limit: usize,
+}
+
+impl Pass for InlineFunctions {
+    fn name(&self) -> &'static str {
+        "inline"
+    }
+
+    fn run(&self, modules: &mut [Module]) {
+        inline::inline_functions(modules, self.size_limit);
+    }
+}
+
+/// See `control_flow::simplify_control_flow`.
+pub struct SimplifyControlFlow;
+
+impl Pass for SimplifyControlFlow {
+    fn name(&self) -> &'static str {
+        "simplify-jumps"
+    }
+
+    fn run(&self, modules: &mut [Module]) {
+        control_flow::simplify_control_flow(modules);
+    }
+}
+
+/// See `frames::assign_frames`.
+pub struct LiteFrames;
+
+impl Pass for LiteFrames {
+    fn name(&self) -> &'static str {
+        "lite-frames"
+    }
+
+    fn run(&self, modules: &mut [Module]) {
+        frames::assign_frames(modules);
+    }
+}
+
+/// Creates the pass `--passes` names `name`, or `None` for unknown names.
+pub fn create_pass(name: &str, inline_limit: usize) -> Option<Box<dyn Pass>> {
+    match name {
+        "inline" => Some(Box::new(InlineFunctions {
+            size_limit: inline_limit,
+        })),
+        "simplify-jumps" => Some(Box::new(SimplifyControlFlow)),
+        "lite-frames" => Some(Box::new(LiteFrames)),
+        _ => None,
+    }
+}
+
+/// The passes to run over the program, in order.
+#[derive(Default)]
+pub struct PassPipeline {
+    passes: Vec<Box<dyn Pass>>,
+    /// Print the program to stderr before the first pass and after every
+    /// pass, so a miscompilation can be pinned on a single pass.
+    pub dump_after_each: bool,
+}
+
+impl PassPipeline {
+    /// The passes of an optimization level: `0`, `1`, `2` or `s`. Inlining
+    /// uses `inline_limit` when given, and otherwise `DEFAULT_INLINE_LIMIT`
+    /// at `2` and only bodies small enough to shrink the program at `s`.
+    pub fn preset(level: &str, inline_limit: Option<usize>) -> Option<PassPipeline> {
+        let (names, default_limit) = match level {
+            "0" => ("", 0),
+            "1" => ("simplify-jumps,lite-frames", 0),
+            "2" => ("inline,simplify-jumps,lite-frames", DEFAULT_INLINE_LIMIT),
+            "s" => ("inline,simplify-jumps,lite-frames", SIZE_INLINE_LIMIT),
+            _ => return None,
+        };
+        PassPipeline::from_names(names, inline_limit.unwrap_or(default_limit))
+    }
+
+    /// The passes named in a comma separated list, or `None` when a name is
+    /// unknown.
+    pub fn from_names(names: &str, inline_limit: usize) -> Option<PassPipeline> {
+        let mut pipeline = PassPipeline::default();
+        for name in names.split(',').filter(|name| !name.is_empty()) {
+            pipeline.add(create_pass(name, inline_limit)?);
+        }
+        Some(pipeline)
+    }
+
+    pub fn add(&mut self, pass: Box<dyn Pass>) {
+        self.passes.push(pass);
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.passes.iter().any(|pass| pass.name() == name)
+    }
+
+    pub fn run(&self, modules: &mut [Module]) {
+        if self.dump_after_each {
+            dump(modules, "parse");
+        }
+        for pass in &self.passes {
+            pass.run(modules);
+            if self.dump_after_each {
+                dump(modules, pass.name());
+            }
+        }
+    }
+}
+
+fn dump(modules: &[Module], stage: &str) {
+    let mut stderr = std::io::stderr().lock();
+    writeln!(stderr, "// After {}", stage).unwrap();
+    for module in modules {
+        writeln!(stderr, "// {}.vm", module.name).unwrap();
+        for instruction in &module.instructions {
+            writeln!(stderr, "{}", format_instruction(instruction)).unwrap();
+        }
+    }
+}
+
+/// Writes an instruction back as a line of VM code, noting lite frames in a
+/// comment.
+pub fn format_instruction(instruction: &Instruction) -> String {
+    let frame_note = |frame: FrameKind| match frame {
+        FrameKind::Full => "",
+        FrameKind::Lite => " // lite frame",
+    };
+    match instruction {
+        Instruction::CAsm(asm) => {
+            asm.iter()
+                .fold(String::from("asm {\n"), |code, line| code + line + "\n")
+                + "}"
+        }
+        Instruction::CArithmetic(ArithmeticType::Shift(operator, amount)) if *amount != 1 => {
+            match operator {
+                ShiftArithmeticOperator::ShiftLeft => format!("shl {}", amount),
+                ShiftArithmeticOperator::ShiftRight => format!("shr {}", amount),
+            }
+        }
+        Instruction::CArithmetic(operator) => String::from(match operator {
+            ArithmeticType::Unary(UnaryArithmeticOperator::Negate) => "neg",
+            ArithmeticType::Unary(UnaryArithmeticOperator::Not) => "not",
+            ArithmeticType::Binary(BinaryArithmeticOperator::Add) => "add",
+            ArithmeticType::Binary(BinaryArithmeticOperator::Subtract) => "sub",
+            ArithmeticType::Binary(BinaryArithmeticOperator::And) => "and",
+            ArithmeticType::Binary(BinaryArithmeticOperator::Or) => "or",
+            ArithmeticType::Binary(BinaryArithmeticOperator::Eq) => "eq",
+            ArithmeticType::Binary(BinaryArithmeticOperator::Gt) => "gt",
+            ArithmeticType::Binary(BinaryArithmeticOperator::Lt) => "lt",
+            ArithmeticType::Shift(ShiftArithmeticOperator::ShiftLeft, _) => "shiftleft",
+            ArithmeticType::Shift(ShiftArithmeticOperator::ShiftRight, _) => "shiftright",
+            ArithmeticType::Extended(ExtendedArithmeticOperator::Multiply) => "mul",
+            ArithmeticType::Extended(ExtendedArithmeticOperator::Divide) => "div",
+            ArithmeticType::Extended(ExtendedArithmeticOperator::Modulo) => "mod",
+            ArithmeticType::Extended(ExtendedArithmeticOperator::Xor) => "xor",
+            ArithmeticType::Extended(ExtendedArithmeticOperator::UnsignedLt) => "ult",
+            ArithmeticType::Extended(ExtendedArithmeticOperator::UnsignedGt) => "ugt",
+        }),
+        Instruction::CPush(push) => {
+            format!("push {} {}", segment_name(push.segment), push.index)
+        }
+        Instruction::CPop(pop) => format!("pop {} {}", segment_name(pop.segment), pop.index),
+        Instruction::CLabel(label) => format!("label {}", label.label),
+        Instruction::CGoto(label) => format!("goto {}", label.label),
+        Instruction::CIf(label) => format!("if-goto {}", label.label),
+        Instruction::CFunction(function) => format!(
+            "function {} {}{}",
+            function.function_name,
+            function.n_args,
+            frame_note(function.frame)
+        ),
+        Instruction::CReturn => String::from("return"),
+        Instruction::CCall(call) => format!(
+            "call {} {}{}",
+            call.function_name,
+            call.n_args,
+            frame_note(call.frame)
+        ),
+    }
+}
+
+fn segment_name(segment: Segment) -> &'static str {
+    match segment {
+        Segment::Argument => "argument",
+        Segment::Local => "local",
+        Segment::Static => "static",
+        Segment::Constant => "constant",
+        Segment::This => "this",
+        Segment::That => "that",
+        Segment::Pointer => "pointer",
+        Segment::Temp => "temp",
+        Segment::Screen => "screen",
+        Segment::Keyboard => "keyboard",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_programs::parse_modules;
+
+    const MAIN: &str = "function Main.main 1
+        push constant 7
+        pop local 0
+        label LOOP
+        push local 0
+        push constant 1
+        sub
+        pop local 0
+        push local 0
+        if-goto LOOP
+        push argument 0
+        shiftleft
+        call Main.double 1
+        return
+        function Main.double 0
+        push argument 0
+        push argument 0
+        add
+        return";
+
+    fn format(modules: &[Module]) -> String {
+        modules[0]
+            .instructions
+            .iter()
+            .map(format_instruction)
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn presets_and_pass_lists_pick_passes_in_order() {
+        let names = |pipeline: PassPipeline| -> Vec<&'static str> {
+            pipeline.passes.iter().map(|pass| pass.name()).collect()
+        };
+        assert!(names(PassPipeline::preset("0", None).unwrap()).is_empty());
+        assert_eq!(
+            names(PassPipeline::preset("2", None).unwrap()),
+            vec!["inline", "simplify-jumps", "lite-frames"]
+        );
+        assert_eq!(
+            names(PassPipeline::from_names("lite-frames,inline", 5).unwrap()),
+            vec!["lite-frames", "inline"]
+        );
+        assert!(PassPipeline::preset("3", None).is_none());
+        assert!(PassPipeline::from_names("inline,unroll", 5).is_none());
+    }
+
+    #[test]
+    fn dumped_programs_parse_back_unchanged() {
+        let mut modules = parse_modules(&[("Main", MAIN)]);
+        PassPipeline::preset("2", None).unwrap().run(&mut modules);
+        let dumped = format(&modules);
+        assert!(dumped.contains("function Main.double 0 // lite frame"));
+        assert_eq!(
+            format(&parse_modules(&[("Main", &dumped)])),
+            dumped.replace(" // lite frame", "")
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+
+use crate::instructions::{Instruction, Label, Module, Segment};
+
+/// The Hack assembler gives variables addresses from here on, in the order
+/// they first appear.
+const STATIC_BASE: u16 = 16;
+
+/// Numbers for the functions and labels of a whole program, and the RAM
+/// addresses of its statics, for backends that cannot use the VM names as
+/// symbols. Statics get the addresses the Hack assembler would give them, so
+/// every backend leaves them in the same place.
+pub struct ProgramSymbols {
+    functions: HashMap<String, usize>,
+    labels: HashMap<String, usize>,
+    statics: HashMap<(String, u16), u16>,
+}
+
+impl ProgramSymbols {
+    pub fn new(modules: &[Module]) -> Self {
+        let mut functions = HashMap::new();
+        let mut labels = HashMap::new();
+        let mut statics = HashMap::new();
+        for module in modules {
+            for instruction in &module.instructions {
+                let index = match instruction {
+                    Instruction::CFunction(function) => {
+                        let id = functions.len();
+                        functions.insert(function.function_name.clone(), id);
+                        continue;
+                    }
+                    Instruction::CLabel(label) => {
+                        let id = labels.len();
+                        labels.insert(label.extract_label_name(), id);
+                        continue;
+                    }
+                    Instruction::CPush(push) if push.segment == Segment::Static => push.index,
+                    Instruction::CPop(pop) if pop.segment == Segment::Static => pop.index,
+                    _ => continue,
+                };
+                let address = STATIC_BASE + statics.len() as u16;
+                statics
+                    .entry((module.name.clone(), index))
+                    .or_insert(address);
+            }
+        }
+        Self {
+            functions,
+            labels,
+            statics,
+        }
+    }
+
+    pub fn function(&self, function_name: &str) -> usize {
+        match self.functions.get(function_name) {
+            Some(id) => *id,
+            None => panic!("Call to undefined function {}", function_name),
+        }
+    }
+
+    pub fn label(&self, label: &Label) -> usize {
+        match self.labels.get(&label.extract_label_name()) {
+            Some(id) => *id,
+            None => panic!("Jump to undefined label {}", label.extract_label_name()),
+        }
+    }
+
+    pub fn static_address(&self, module_name: &str, index: u16) -> u16 {
+        self.statics[&(module_name.to_string(), index)]
+    }
+}
+
+/// Whether the instruction at `position` is a `goto` to a label right before
+/// it, the loop `Sys.halt` parks the CPU in. Native backends stop there
+/// instead.
+pub fn is_halt_loop(instructions: &[Instruction], position: usize) -> bool {
+    let target = match &instructions[position] {
+        Instruction::CGoto(label) => label.extract_label_name(),
+        _ => return false,
+    };
+    instructions[..position]
+        .iter()
+        .rev()
+        .map_while(|instruction| match instruction {
+            Instruction::CLabel(label) => Some(label.extract_label_name()),
+            _ => None,
+        })
+        .any(|name| name == target)
+}
+
+/// The position after the last instruction of the function that starts at
+/// `start`, for backends that translate whole functions at once.
+pub fn function_end(instructions: &[Instruction], start: usize) -> usize {
+    instructions[start + 1..]
+        .iter()
+        .position(|instruction| matches!(instruction, Instruction::CFunction(_)))
+        .map_or(instructions.len(), |offset| start + 1 + offset)
+}
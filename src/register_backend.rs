@@ -0,0 +1,809 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::compiler::{
+    self, create_arithmetic_operator, create_call_operator, create_compare_branch_operator,
+    create_function_operator, create_goto_operator, create_label_operator, create_load_constant,
+    create_return_operator, create_shift_on_d, create_tail_call_operator, io_register,
+    pointer_register, CodegenOptions, CompareBranch, StackStrategy,
+};
+use crate::instructions::{
+    ArithmeticType, BinaryArithmeticOperator, Function, Instruction, Label, Module, Segment,
+    UnaryArithmeticOperator,
+};
+use crate::intrinsics;
+use crate::memory_layout::MemoryLayout;
+use crate::register_ir::{
+    lower_function, optimize, same_block, DefUse, IrFunction, IrInstruction, Liveness, Operand,
+    VirtualRegister,
+};
+use crate::routines::calls_routines;
+
+/// R13 is the backend's own scratch. R14 and R15 are free unless a routine
+/// is called, as the return templates only use them once nothing is live.
+const SCRATCH_REGISTERS: [u16; 2] = [14, 15];
+
+/// Locals this close to LCL are reached by stepping A one address at a time.
+const MAX_ADDRESS_STEPS: u16 = 3;
+
+/// The number of `temp` indices.
+const TEMP_COUNT: u16 = 8;
+
+/// The `temp` indices no function in the program touches.
+pub fn unused_temps(modules: &[Module]) -> Vec<u16> {
+    let mut used = [false; TEMP_COUNT as usize];
+    for instruction in modules.iter().flat_map(|module| &module.instructions) {
+        let index = match instruction {
+            Instruction::CPush(push) if push.segment == Segment::Temp => push.index,
+            Instruction::CPop(pop) if pop.segment == Segment::Temp => pop.index,
+            _ => continue,
+        };
+        used[index as usize] = true;
+    }
+    (0..TEMP_COUNT)
+        .filter(|index| !used[*index as usize])
+        .collect()
+}
+
+/// Where a virtual register lives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Location {
+    Register(u16),
+    Home(u16),
+    Reload(Segment, u16),
+}
+
+/// Compiles every function of a module through the register IR, falling back
+/// to the memory strategy for modules the IR cannot represent.
+pub fn compile(
+    instructions: Vec<Instruction>,
+    file_name: &str,
+    layout: &MemoryLayout,
+    options: &CodegenOptions,
+) -> Vec<String> {
+    let functions = match lower_module(&instructions) {
+        Some(functions) => functions,
+        None => {
+            let fallback = CodegenOptions {
+                stack_strategy: StackStrategy::Memory,
+                ..options.clone()
+            };
+            return compiler::compile(instructions, file_name, layout, &fallback);
+        }
+    };
+    let mut registers: Vec<u16> = options
+        .register_temps
+        .iter()
+        .map(|index| layout.temp_address(*index))
+        .collect();
+    if !calls_routines(&instructions, options) {
+        registers.extend(SCRATCH_REGISTERS);
+    }
+
+    let mut comparison_count: u16 = 0;
+    let mut result: Vec<String> = vec![];
+    for mut function in functions {
+        optimize(&mut function);
+        let forwarded = forwarded_registers(&function);
+        let locations = allocate(&function, &registers, &forwarded);
+        let emitter = Emitter {
+            function: &function,
+            locations: &locations,
+            forwarded: &forwarded,
+            file_name,
+            layout,
+            options,
+        };
+        result.push(emitter.emit(&mut comparison_count));
+    }
+    result
+}
+
+fn lower_module(instructions: &[Instruction]) -> Option<Vec<IrFunction>> {
+    let mut functions = vec![];
+    let mut starts = instructions
+        .iter()
+        .enumerate()
+        .filter(|(_, instruction)| matches!(instruction, Instruction::CFunction(_)))
+        .map(|(position, _)| position)
+        .peekable();
+    if starts.peek() != Some(&0) && !instructions.is_empty() {
+        return None;
+    }
+    while let Some(start) = starts.next() {
+        let end = starts.peek().copied().unwrap_or(instructions.len());
+        let function = match &instructions[start] {
+            Instruction::CFunction(function) => function,
+            _ => unreachable!(),
+        };
+        functions.push(lower_function(function, &instructions[start + 1..end])?);
+    }
+    Some(functions)
+}
+
+/// The operand an instruction loads into D before anything else.
+fn first_operand(instruction: &IrInstruction) -> Option<Operand> {
+    match instruction {
+        IrInstruction::Copy { source, .. }
+        | IrInstruction::Store { source, .. }
+        | IrInstruction::Unary { source, .. } => Some(*source),
+        IrInstruction::Binary {
+            operator:
+                ArithmeticType::Binary(
+                    BinaryArithmeticOperator::Eq
+                    | BinaryArithmeticOperator::Gt
+                    | BinaryArithmeticOperator::Lt,
+                )
+                | ArithmeticType::Extended(_),
+            left,
+            ..
+        } => Some(*left),
+        IrInstruction::Binary { right, .. } => Some(*right),
+        IrInstruction::If { condition, .. } => Some(*condition),
+        IrInstruction::Call { args, .. } => args.first().copied(),
+        IrInstruction::Return { value } => Some(*value),
+        IrInstruction::Load { .. } | IrInstruction::Label(_) | IrInstruction::Goto(_) => None,
+    }
+}
+
+/// The registers whose only use loads them first, right after their
+/// definition left them in D.
+fn forwarded_registers(function: &IrFunction) -> HashSet<VirtualRegister> {
+    let def_use = DefUse::new(function);
+    let instructions = &function.instructions;
+    (1..instructions.len())
+        .filter_map(|position| {
+            let register = instructions[position - 1].dest()?;
+            let defs = &def_use.defs[&register];
+            let uses = def_use.uses.get(&register)?;
+            let forwarded = defs.len() == 1
+                && uses.as_slice() == [position]
+                && first_operand(&instructions[position]) == Some(Operand::Register(register));
+            if forwarded {
+                Some(register)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Gives every virtual register a location, handing the RAM registers out by
+/// loop-weighted use counts to values not live across a call.
+fn allocate(
+    function: &IrFunction,
+    registers: &[u16],
+    forwarded: &HashSet<VirtualRegister>,
+) -> HashMap<VirtualRegister, Location> {
+    let liveness = Liveness::new(function);
+    let def_use = DefUse::new(function);
+
+    let mut ranges: HashMap<VirtualRegister, (usize, usize)> = HashMap::new();
+    let mut extend = |register: VirtualRegister, position: usize| {
+        let range = ranges.entry(register).or_insert((position, position));
+        range.0 = range.0.min(position);
+        range.1 = range.1.max(position);
+    };
+    for (position, instruction) in function.instructions.iter().enumerate() {
+        for register in liveness.live_in[position]
+            .iter()
+            .chain(&liveness.live_out[position])
+            .copied()
+            .chain(instruction.dest())
+        {
+            extend(register, position);
+        }
+    }
+
+    let mut crosses_call: HashSet<VirtualRegister> = HashSet::new();
+    for (position, instruction) in function.instructions.iter().enumerate() {
+        if let IrInstruction::Call { dest, .. } = instruction {
+            crosses_call.extend(
+                liveness.live_out[position]
+                    .iter()
+                    .filter(|register| *register != dest),
+            );
+        }
+    }
+
+    let mut locations: HashMap<VirtualRegister, Location> = HashMap::new();
+    for register in ranges
+        .keys()
+        .filter(|register| !forwarded.contains(register))
+    {
+        if let Some((segment, index)) = reloadable(function, &def_use, *register) {
+            // Reloading a far slot costs more than keeping it in a register
+            if index <= MAX_ADDRESS_STEPS || crosses_call.contains(register) {
+                locations.insert(*register, Location::Reload(segment, index));
+            }
+        }
+    }
+
+    let depths = loop_depths(function);
+    let weight = |register: &VirtualRegister| -> u64 {
+        let positions = def_use.defs.get(register).into_iter().flatten();
+        positions
+            .chain(def_use.uses.get(register).into_iter().flatten())
+            .map(|position| 10u64.pow(depths[*position].min(4)))
+            .sum()
+    };
+    let mut candidates: Vec<VirtualRegister> = ranges
+        .keys()
+        .filter(|register| !forwarded.contains(register) && !locations.contains_key(register))
+        .copied()
+        .collect();
+    candidates.sort_by_key(|register| (std::cmp::Reverse(weight(register)), *register));
+
+    let overlaps = |a: (usize, usize), b: (usize, usize)| a.0 <= b.1 && b.0 <= a.1;
+    let mut assigned: HashMap<Location, Vec<(usize, usize)>> = HashMap::new();
+    for register in candidates {
+        let range = ranges[&register];
+        let fits = |location: &Location| {
+            assigned
+                .get(location)
+                .into_iter()
+                .flatten()
+                .all(|other| !overlaps(range, *other))
+        };
+        let mut location = None;
+        if !crosses_call.contains(&register) {
+            location = registers
+                .iter()
+                .map(|address| Location::Register(*address))
+                .find(|location| fits(location));
+        }
+        let location = location.unwrap_or_else(|| {
+            (function.function.n_args..)
+                .map(Location::Home)
+                .find(|location| fits(location))
+                .unwrap()
+        });
+        assigned.entry(location).or_default().push(range);
+        locations.insert(register, location);
+    }
+    locations
+}
+
+/// The `local` or `argument` slot a register was loaded from, when nothing
+/// may write it before the last use in the same block.
+fn reloadable(
+    function: &IrFunction,
+    def_use: &DefUse,
+    register: VirtualRegister,
+) -> Option<(Segment, u16)> {
+    let def = match def_use.defs.get(&register)?.as_slice() {
+        [def] => *def,
+        _ => return None,
+    };
+    let (segment, index) = match function.instructions[def] {
+        IrInstruction::Load {
+            segment: segment @ (Segment::Local | Segment::Argument),
+            index,
+            ..
+        } => (segment, index),
+        _ => return None,
+    };
+    let last_use = *def_use.uses.get(&register)?.iter().max()?;
+    if last_use < def || !same_block(function, def, last_use) {
+        return None;
+    }
+    let overwritten = function.instructions[def..last_use]
+        .iter()
+        .any(|instruction| match instruction {
+            IrInstruction::Store {
+                segment: Segment::This | Segment::That | Segment::Pointer,
+                ..
+            }
+            | IrInstruction::Call { .. } => true,
+            IrInstruction::Store {
+                segment: stored,
+                index: stored_index,
+                ..
+            } => *stored == segment && *stored_index == index,
+            _ => false,
+        });
+    if overwritten {
+        None
+    } else {
+        Some((segment, index))
+    }
+}
+
+/// How many label-to-backward-jump loops enclose each instruction.
+fn loop_depths(function: &IrFunction) -> Vec<u32> {
+    let labels = function.label_positions();
+    let mut depths = vec![0; function.instructions.len()];
+    for (position, instruction) in function.instructions.iter().enumerate() {
+        let label = match instruction {
+            IrInstruction::Goto(label) | IrInstruction::If { label, .. } => label,
+            _ => continue,
+        };
+        let target = labels[&label.extract_label_name()];
+        if target <= position {
+            for depth in &mut depths[target..=position] {
+                *depth += 1;
+            }
+        }
+    }
+    depths
+}
+
+struct Emitter<'a> {
+    function: &'a IrFunction,
+    locations: &'a HashMap<VirtualRegister, Location>,
+    forwarded: &'a HashSet<VirtualRegister>,
+    file_name: &'a str,
+    layout: &'a MemoryLayout,
+    options: &'a CodegenOptions,
+}
+
+impl Emitter<'_> {
+    fn emit(&self, comparison_count: &mut u16) -> String {
+        let homes = self
+            .locations
+            .values()
+            .filter_map(|location| match location {
+                Location::Home(index) => Some(index + 1),
+                Location::Register(_) | Location::Reload(..) => None,
+            })
+            .max()
+            .unwrap_or(self.function.function.n_args);
+        let declaration = Function {
+            n_args: homes,
+            ..self.function.function.clone()
+        };
+        let mut asm = create_function_operator(&declaration, self.layout).unwrap();
+
+        let liveness = Liveness::new(self.function);
+        let instructions = &self.function.instructions;
+        let mut position = 0;
+        while position < instructions.len() {
+            let next = instructions.get(position + 1);
+            match (&instructions[position], next) {
+                // A comparison only consumed by the branch after it
+                (
+                    IrInstruction::Binary {
+                        dest,
+                        operator:
+                            ArithmeticType::Binary(
+                                operator @ (BinaryArithmeticOperator::Eq
+                                | BinaryArithmeticOperator::Gt
+                                | BinaryArithmeticOperator::Lt),
+                            ),
+                        left,
+                        right,
+                    },
+                    Some(IrInstruction::If {
+                        condition: Operand::Register(condition),
+                        label,
+                    }),
+                ) if condition == dest && !liveness.live_out[position + 1].contains(dest) => {
+                    asm.push_str(&self.compare_branch(
+                        *operator,
+                        *left,
+                        *right,
+                        label,
+                        comparison_count,
+                    ));
+                    position += 2;
+                }
+                // A call whose result is returned straight away
+                (
+                    IrInstruction::Call { call, args, dest },
+                    Some(IrInstruction::Return {
+                        value: Operand::Register(value),
+                    }),
+                ) if value == dest && intrinsics::find(call, self.options).is_none() => {
+                    for arg in args {
+                        asm.push_str(&self.push(*arg));
+                    }
+                    asm.push_str(&create_tail_call_operator(
+                        call,
+                        self.function.function.frame,
+                    ));
+                    position += 2;
+                }
+                (instruction, _) => {
+                    let live_out = &liveness.live_out[position];
+                    asm.push_str(&self.emit_instruction(instruction, live_out, comparison_count));
+                    position += 1;
+                }
+            }
+        }
+        asm
+    }
+
+    fn emit_instruction(
+        &self,
+        instruction: &IrInstruction,
+        live_out: &std::collections::HashSet<VirtualRegister>,
+        comparison_count: &mut u16,
+    ) -> String {
+        match instruction {
+            IrInstruction::Copy { dest, source } => self.load(*source) + &self.store(*dest),
+            // Registers reloaded at every use need no load up front
+            IrInstruction::Load { dest, .. }
+                if matches!(self.locations.get(dest), Some(Location::Reload(..))) =>
+            {
+                String::new()
+            }
+            IrInstruction::Load {
+                dest,
+                segment,
+                index,
+            } => self.load_segment(*segment, *index) + &self.store(*dest),
+            IrInstruction::Store {
+                segment,
+                index,
+                source,
+            } => self.store_segment(*segment, *index, *source),
+            IrInstruction::Unary {
+                dest,
+                operator,
+                source,
+            } => {
+                let computation = match (operator, self.options.profile) {
+                    (ArithmeticType::Unary(UnaryArithmeticOperator::Negate), _) => {
+                        String::from("D=-D\n")
+                    }
+                    (ArithmeticType::Unary(UnaryArithmeticOperator::Not), _) => {
+                        String::from("D=!D\n")
+                    }
+                    (ArithmeticType::Shift(operator, amount), profile) => create_shift_on_d(
+                        *operator,
+                        *amount,
+                        profile,
+                        self.file_name,
+                        comparison_count,
+                    ),
+                    (ArithmeticType::Binary(_) | ArithmeticType::Extended(_), _) => {
+                        unreachable!()
+                    }
+                };
+                self.load(*source) + &computation + &self.store(*dest)
+            }
+            IrInstruction::Binary {
+                dest,
+                operator,
+                left,
+                right,
+            } => match operator {
+                ArithmeticType::Binary(
+                    BinaryArithmeticOperator::Eq
+                    | BinaryArithmeticOperator::Gt
+                    | BinaryArithmeticOperator::Lt,
+                )
+                | ArithmeticType::Extended(_) => {
+                    // Comparisons branch on the operands and the extended
+                    // operators call routines, which the memory templates
+                    // handle without any scratch registers
+                    self.push(*left)
+                        + &self.push(*right)
+                        + &create_arithmetic_operator(
+                            *operator,
+                            self.file_name,
+                            self.options.profile,
+                            comparison_count,
+                        )
+                        + "@SP\nAM=M-1\nD=M\n"
+                        + &self.store(*dest)
+                }
+                ArithmeticType::Binary(operator) => {
+                    self.binary(*operator, *left, *right) + &self.store(*dest)
+                }
+                _ => unreachable!(),
+            },
+            IrInstruction::Label(label) => create_label_operator(label).unwrap(),
+            IrInstruction::Goto(label) => create_goto_operator(label).unwrap(),
+            IrInstruction::If { condition, label } => {
+                self.load(*condition) + &format!("@{}\nD;JNE\n", label.extract_label_name())
+            }
+            IrInstruction::Call { call, args, dest } => {
+                let mut asm = String::new();
+                for arg in args {
+                    asm.push_str(&self.push(*arg));
+                }
+                match intrinsics::find(call, self.options) {
+                    Some(intrinsic) => asm.push_str(&intrinsic.create(
+                        self.file_name,
+                        self.options.profile,
+                        comparison_count,
+                    )),
+                    None => asm.push_str(&create_call_operator(call).unwrap()),
+                }
+                if live_out.contains(dest) {
+                    asm + "@SP\nAM=M-1\nD=M\n" + &self.store(*dest)
+                } else {
+                    asm + "@SP\nM=M-1\n"
+                }
+            }
+            IrInstruction::Return { value } => {
+                self.push(*value)
+                    + &create_return_operator(self.layout, self.function.function.frame).unwrap()
+            }
+        }
+    }
+
+    /// Jumps to `label` when `left operator right` holds.
+    fn compare_branch(
+        &self,
+        operator: BinaryArithmeticOperator,
+        left: Operand,
+        right: Operand,
+        label: &Label,
+        comparison_count: &mut u16,
+    ) -> String {
+        let label_name = label.extract_label_name();
+        match (operator, right) {
+            (BinaryArithmeticOperator::Eq, _) => {
+                self.binary(BinaryArithmeticOperator::Subtract, right, left)
+                    + &format!("@{}\nD;JEQ\n", label_name)
+            }
+            (BinaryArithmeticOperator::Gt, Operand::Constant(0)) => {
+                self.load(left) + &format!("@{}\nD;JGT\n", label_name)
+            }
+            (BinaryArithmeticOperator::Lt, Operand::Constant(0)) => {
+                self.load(left) + &format!("@{}\nD;JLT\n", label_name)
+            }
+            _ => {
+                let branch = CompareBranch {
+                    operator,
+                    negated: false,
+                    label,
+                };
+                self.push(left)
+                    + &self.push(right)
+                    + &create_compare_branch_operator(&branch, self.file_name, comparison_count)
+            }
+        }
+    }
+
+    /// Computes `left operator right` into D.
+    fn binary(&self, operator: BinaryArithmeticOperator, left: Operand, right: Operand) -> String {
+        let (with_a, with_m, with_scratch) = match operator {
+            BinaryArithmeticOperator::Add => ("D=D+A\n", "D=D+M\n", "D=D+M\n"),
+            BinaryArithmeticOperator::Subtract => ("D=A-D\n", "D=M-D\n", "D=D-M\n"),
+            BinaryArithmeticOperator::And => ("D=D&A\n", "D=D&M\n", "D=D&M\n"),
+            BinaryArithmeticOperator::Or => ("D=D|A\n", "D=D|M\n", "D=D|M\n"),
+            _ => panic!("{:?} is not computed in D", operator),
+        };
+        let mut asm = self.load(right);
+        match (left, self.address(left)) {
+            (Operand::Constant(value), _) if value >= 0 => {
+                asm.push_str(&format!("@{}\n{}", value, with_a));
+            }
+            (_, Some(address)) => {
+                asm.push_str(&address);
+                asm.push_str(with_m);
+            }
+            _ => {
+                asm.push_str("@R13\nM=D\n");
+                asm.push_str(&self.load(left));
+                asm.push_str("@R13\n");
+                asm.push_str(with_scratch);
+            }
+        }
+        asm
+    }
+
+    fn location(&self, register: VirtualRegister) -> Location {
+        self.locations[&register]
+    }
+
+    /// Points A at a register's location without touching D, if possible.
+    fn address(&self, operand: Operand) -> Option<String> {
+        match operand {
+            Operand::Register(register) => match self.location(register) {
+                Location::Register(address) => Some(format!("@R{}\n", address)),
+                Location::Home(index) if index <= MAX_ADDRESS_STEPS => {
+                    Some(String::from("@LCL\nA=M\n") + &"A=A+1\n".repeat(index as usize))
+                }
+                Location::Reload(segment, index) if index <= MAX_ADDRESS_STEPS => {
+                    Some(format!("@{}\nA=M\n", segment) + &"A=A+1\n".repeat(index as usize))
+                }
+                Location::Home(_) | Location::Reload(..) => None,
+            },
+            Operand::Constant(_) => None,
+        }
+    }
+
+    /// Loads an operand into D.
+    fn load(&self, operand: Operand) -> String {
+        match operand {
+            Operand::Constant(value) => create_load_constant(value),
+            Operand::Register(register) if self.forwarded.contains(&register) => String::new(),
+            Operand::Register(register) => match self.location(register) {
+                Location::Register(address) => format!("@R{}\nD=M\n", address),
+                Location::Home(index) => self.load_segment(Segment::Local, index),
+                Location::Reload(segment, index) => self.load_segment(segment, index),
+            },
+        }
+    }
+
+    /// Stores D into a register's location.
+    fn store(&self, register: VirtualRegister) -> String {
+        if self.forwarded.contains(&register) {
+            return String::new();
+        }
+        match self.location(register) {
+            Location::Register(address) => format!("@R{}\nM=D\n", address),
+            Location::Home(index) => store_relative("LCL", index),
+            Location::Reload(..) => String::new(),
+        }
+    }
+
+    fn push(&self, operand: Operand) -> String {
+        self.load(operand) + "@SP\nAM=M+1\nA=A-1\nM=D\n"
+    }
+
+    fn load_segment(&self, segment: Segment, index: u16) -> String {
+        match segment {
+            Segment::Local | Segment::Argument | Segment::This | Segment::That => {
+                let pointer = segment.to_string();
+                if index <= MAX_ADDRESS_STEPS {
+                    format!(
+                        "@{}\nA=M\n{}D=M\n",
+                        pointer,
+                        "A=A+1\n".repeat(index as usize)
+                    )
+                } else {
+                    format!("@{}\nD=A\n@{}\nA=D+M\nD=M\n", index, pointer)
+                }
+            }
+            Segment::Pointer => format!("@{}\nD=M\n", pointer_register(index)),
+            Segment::Temp => format!("@{}\nD=M\n", self.layout.temp_address(index)),
+            Segment::Screen | Segment::Keyboard => {
+                format!("@{}\nD=M\n", io_register(segment, index))
+            }
+            Segment::Static => format!("@{}.{}\nD=M\n", self.file_name, index),
+            Segment::Constant => create_load_constant(index as i16),
+        }
+    }
+
+    fn store_segment(&self, segment: Segment, index: u16, source: Operand) -> String {
+        let direct = |address: String| self.load(source) + &format!("@{}\nM=D\n", address);
+        match segment {
+            Segment::Local | Segment::Argument | Segment::This | Segment::That => {
+                self.load(source) + &store_relative(&segment.to_string(), index)
+            }
+            Segment::Pointer => direct(pointer_register(index).to_string()),
+            Segment::Temp => direct(self.layout.temp_address(index).to_string()),
+            Segment::Screen => direct(io_register(segment, index)),
+            Segment::Static => direct(format!("{}.{}", self.file_name, index)),
+            Segment::Constant => panic!("Cannot pop to constant"),
+            Segment::Keyboard => panic!("Cannot pop to keyboard"),
+        }
+    }
+}
+
+/// Stores D `index` addresses past where `pointer` points.
+fn store_relative(pointer: &str, index: u16) -> String {
+    if index <= MAX_ADDRESS_STEPS {
+        format!(
+            "@{}\nA=M\n{}M=D\n",
+            pointer,
+            "A=A+1\n".repeat(index as usize)
+        )
+    } else {
+        format!(
+            "@R13\nM=D\n@{}\nD=A\n@{}\nD=D+M\n@R13\nD=D+M\nA=D-M\nM=D-A\n",
+            index, pointer
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_programs::{main_layout, parse_modules, run_hack};
+
+    const MAIN: &str = "function Main.main 2
+        push constant 3000
+        pop pointer 1
+        label LOOP
+        push local 0
+        push constant 10
+        lt
+        not
+        if-goto DONE
+        push local 1
+        push local 0
+        call Main.add 2
+        pop local 1
+        push local 0
+        push constant 1
+        add
+        pop local 0
+        goto LOOP
+        label DONE
+        push local 1
+        pop that 0
+        push constant 5
+        push constant 6
+        call Main.add 2
+        push constant 7
+        push constant 8
+        call Main.add 2
+        sub
+        pop that 1
+        push constant 32767
+        neg
+        push constant 1
+        sub
+        push constant 1
+        lt
+        pop that 2
+        push constant 9
+        pop temp 7
+        push temp 7
+        pop that 3
+        push local 1
+        push constant 45
+        eq
+        pop that 4
+        push constant 0
+        return
+        function Main.add 0
+        push argument 0
+        push argument 1
+        add
+        return";
+
+    fn run(stack_strategy: StackStrategy) -> Vec<i16> {
+        let options = CodegenOptions {
+            stack_strategy,
+            ..CodegenOptions::default()
+        };
+        let emulator = run_hack(&parse_modules(&[("Main", MAIN)]), &main_layout(), &options);
+        assert_eq!(emulator.ram[0], 257, "{:?}", stack_strategy);
+        emulator.ram[3000..3005].to_vec()
+    }
+
+    #[test]
+    fn registers_match_the_memory_strategy() {
+        let expected = run(StackStrategy::Memory);
+        assert_eq!(expected, vec![45, -4, -1, 9, -1]);
+        assert_eq!(run(StackStrategy::Registers), expected);
+    }
+
+    #[test]
+    fn used_temps_are_not_registers() {
+        let modules = parse_modules(&[("Main", MAIN)]);
+        assert_eq!(unused_temps(&modules), vec![0, 1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn reloads_see_writes_through_pointers_and_calls() {
+        // Main.main has its single local at 261, after the bootstrap's frame
+        let source = "function Main.main 1
+            push constant 5
+            pop local 0
+            push local 0
+            push constant 261
+            pop pointer 1
+            push constant 9
+            pop that 0
+            push constant 0
+            add
+            pop temp 0
+            push local 0
+            push constant 261
+            push constant 11
+            call Memory.poke 2
+            pop temp 2
+            push constant 0
+            add
+            pop temp 1
+            push constant 0
+            return";
+        let modules = parse_modules(&[("Main", source)]);
+        for stack_strategy in [StackStrategy::Memory, StackStrategy::Registers] {
+            let options = CodegenOptions {
+                stack_strategy,
+                ..CodegenOptions::default()
+            };
+            let emulator = run_hack(&modules, &main_layout(), &options);
+            assert_eq!(emulator.ram[5..7], [5, 9], "{:?}", stack_strategy);
+            assert_eq!(emulator.ram[261], 11, "{:?}", stack_strategy);
+        }
+    }
+}
@@ -0,0 +1,581 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::instructions::{ArithmeticType, Call, Function, Instruction, Label, Segment};
+
+/// A virtual register. The first ones hold the function's stack slots across
+/// blocks, the rest hold values computed inside a block.
+pub type VirtualRegister = u32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Register(VirtualRegister),
+    Constant(i16),
+}
+
+/// A three-address form of the VM instructions with named operands. It is
+/// not SSA, as slot registers are redefined, so passes check for a single
+/// definition through the def-use chains.
+#[derive(Debug, Clone)]
+pub enum IrInstruction {
+    Copy {
+        dest: VirtualRegister,
+        source: Operand,
+    },
+    Load {
+        dest: VirtualRegister,
+        segment: Segment,
+        index: u16,
+    },
+    Store {
+        segment: Segment,
+        index: u16,
+        source: Operand,
+    },
+    /// A unary or shift operator.
+    Unary {
+        dest: VirtualRegister,
+        operator: ArithmeticType,
+        source: Operand,
+    },
+    /// A standard or extended binary operator.
+    Binary {
+        dest: VirtualRegister,
+        operator: ArithmeticType,
+        left: Operand,
+        right: Operand,
+    },
+    Label(Label),
+    Goto(Label),
+    If {
+        condition: Operand,
+        label: Label,
+    },
+    Call {
+        call: Call,
+        args: Vec<Operand>,
+        dest: VirtualRegister,
+    },
+    Return {
+        value: Operand,
+    },
+}
+
+impl IrInstruction {
+    pub fn dest(&self) -> Option<VirtualRegister> {
+        match self {
+            IrInstruction::Copy { dest, .. }
+            | IrInstruction::Load { dest, .. }
+            | IrInstruction::Unary { dest, .. }
+            | IrInstruction::Binary { dest, .. }
+            | IrInstruction::Call { dest, .. } => Some(*dest),
+            _ => None,
+        }
+    }
+
+    pub fn operands(&self) -> Vec<Operand> {
+        match self {
+            IrInstruction::Copy { source, .. }
+            | IrInstruction::Store { source, .. }
+            | IrInstruction::Unary { source, .. } => vec![*source],
+            IrInstruction::Binary { left, right, .. } => vec![*left, *right],
+            IrInstruction::If { condition, .. } => vec![*condition],
+            IrInstruction::Call { args, .. } => args.clone(),
+            IrInstruction::Return { value } => vec![*value],
+            _ => vec![],
+        }
+    }
+
+    pub fn uses(&self) -> Vec<VirtualRegister> {
+        self.operands()
+            .into_iter()
+            .filter_map(|operand| match operand {
+                Operand::Register(register) => Some(register),
+                Operand::Constant(_) => None,
+            })
+            .collect()
+    }
+
+    fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            IrInstruction::Copy { source, .. }
+            | IrInstruction::Store { source, .. }
+            | IrInstruction::Unary { source, .. } => vec![source],
+            IrInstruction::Binary { left, right, .. } => vec![left, right],
+            IrInstruction::If { condition, .. } => vec![condition],
+            IrInstruction::Call { args, .. } => args.iter_mut().collect(),
+            IrInstruction::Return { value } => vec![value],
+            _ => vec![],
+        }
+    }
+
+    fn set_dest(&mut self, register: VirtualRegister) {
+        match self {
+            IrInstruction::Copy { dest, .. }
+            | IrInstruction::Load { dest, .. }
+            | IrInstruction::Unary { dest, .. }
+            | IrInstruction::Binary { dest, .. }
+            | IrInstruction::Call { dest, .. } => *dest = register,
+            _ => panic!("{:?} does not define a register", self),
+        }
+    }
+
+    /// Whether the instruction can be dropped when its result is unused.
+    fn is_pure(&self) -> bool {
+        matches!(
+            self,
+            IrInstruction::Copy { .. }
+                | IrInstruction::Load { .. }
+                | IrInstruction::Unary { .. }
+                | IrInstruction::Binary { .. }
+        )
+    }
+
+    /// Whether the instruction ends a basic block.
+    fn is_block_boundary(&self) -> bool {
+        matches!(
+            self,
+            IrInstruction::Label(_)
+                | IrInstruction::Goto(_)
+                | IrInstruction::If { .. }
+                | IrInstruction::Return { .. }
+        )
+    }
+}
+
+pub struct IrFunction {
+    pub function: Function,
+    pub instructions: Vec<IrInstruction>,
+}
+
+impl IrFunction {
+    pub fn label_positions(&self) -> HashMap<String, usize> {
+        self.instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(position, instruction)| match instruction {
+                IrInstruction::Label(label) => Some((label.extract_label_name(), position)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The instructions execution may continue with after `position`.
+    pub fn successors(&self, position: usize, labels: &HashMap<String, usize>) -> Vec<usize> {
+        let target = |label: &Label| labels[&label.extract_label_name()];
+        let next = position + 1;
+        let falls_through = next < self.instructions.len();
+        match &self.instructions[position] {
+            IrInstruction::Goto(label) => vec![target(label)],
+            IrInstruction::If { label, .. } if falls_through => vec![target(label), next],
+            IrInstruction::If { label, .. } => vec![target(label)],
+            IrInstruction::Return { .. } => vec![],
+            _ if falls_through => vec![next],
+            _ => vec![],
+        }
+    }
+}
+
+/// The stack depth before each reachable instruction of a function body,
+/// failing when it is not fixed or the body holds asm blocks.
+fn stack_depths(body: &[Instruction]) -> Option<Vec<Option<usize>>> {
+    let labels: HashMap<String, usize> = body
+        .iter()
+        .enumerate()
+        .filter_map(|(position, instruction)| match instruction {
+            Instruction::CLabel(label) => Some((label.extract_label_name(), position)),
+            _ => None,
+        })
+        .collect();
+    let mut depths: Vec<Option<usize>> = vec![None; body.len()];
+    let mut pending: Vec<(usize, usize)> = vec![(0, 0)];
+    while let Some((position, depth)) = pending.pop() {
+        if position >= body.len() {
+            continue;
+        }
+        match depths[position] {
+            Some(known) if known == depth => continue,
+            Some(_) => return None,
+            None => depths[position] = Some(depth),
+        }
+        let (pops, pushes) = match &body[position] {
+            Instruction::CArithmetic(operator) if operator.is_binary() => (2, 1),
+            Instruction::CArithmetic(_) => (1, 1),
+            Instruction::CPush(_) => (0, 1),
+            Instruction::CPop(_) | Instruction::CIf(_) | Instruction::CReturn => (1, 0),
+            Instruction::CCall(call) => (call.n_args as usize, 1),
+            Instruction::CLabel(_) | Instruction::CGoto(_) => (0, 0),
+            Instruction::CFunction(_) | Instruction::CAsm(_) => return None,
+        };
+        if depth < pops {
+            return None;
+        }
+        let after = depth - pops + pushes;
+        match &body[position] {
+            Instruction::CReturn => {}
+            Instruction::CGoto(label) | Instruction::CIf(label) => {
+                pending.push((*labels.get(&label.extract_label_name())?, after));
+                if let Instruction::CIf(_) = body[position] {
+                    pending.push((position + 1, after));
+                }
+            }
+            _ => pending.push((position + 1, after)),
+        }
+    }
+    Some(depths)
+}
+
+/// Lowers the body of a function into the register form, or returns `None`
+/// when its stack depth is not fixed. Values left on the stack at the end of
+/// a block are copied into their slot registers.
+pub fn lower_function(function: &Function, body: &[Instruction]) -> Option<IrFunction> {
+    let depths = stack_depths(body)?;
+    let slot_count = depths.iter().flatten().max().copied().unwrap_or(0) + 1;
+    let slots = |depth: usize| -> Vec<Operand> {
+        (0..depth)
+            .map(|slot| Operand::Register(slot as VirtualRegister))
+            .collect()
+    };
+    let mut next_register = slot_count as VirtualRegister;
+    let mut fresh = || {
+        next_register += 1;
+        next_register - 1
+    };
+
+    let mut instructions: Vec<IrInstruction> = vec![];
+    // `None` after a jump or return, until the next label
+    let mut stack: Option<Vec<Operand>> = Some(vec![]);
+    for (instruction, depth) in body.iter().zip(depths) {
+        let depth = match depth {
+            Some(depth) => depth,
+            None => continue,
+        };
+        if let Instruction::CLabel(label) = instruction {
+            if let Some(stack) = &stack {
+                flush(stack, &mut instructions);
+            }
+            instructions.push(IrInstruction::Label(label.clone()));
+            stack = Some(slots(depth));
+            continue;
+        }
+        let current = stack.as_mut().unwrap();
+        match instruction {
+            Instruction::CPush(push) if push.segment == Segment::Constant => {
+                current.push(Operand::Constant(push.index as i16));
+            }
+            Instruction::CPush(push) => {
+                let dest = fresh();
+                instructions.push(IrInstruction::Load {
+                    dest,
+                    segment: push.segment,
+                    index: push.index,
+                });
+                current.push(Operand::Register(dest));
+            }
+            Instruction::CPop(pop) => {
+                let source = current.pop().unwrap();
+                instructions.push(IrInstruction::Store {
+                    segment: pop.segment,
+                    index: pop.index,
+                    source,
+                });
+            }
+            Instruction::CArithmetic(operator) if operator.is_binary() => {
+                let right = current.pop().unwrap();
+                let left = current.pop().unwrap();
+                let dest = fresh();
+                instructions.push(IrInstruction::Binary {
+                    dest,
+                    operator: *operator,
+                    left,
+                    right,
+                });
+                current.push(Operand::Register(dest));
+            }
+            Instruction::CArithmetic(operator) => {
+                let source = current.pop().unwrap();
+                let dest = fresh();
+                instructions.push(IrInstruction::Unary {
+                    dest,
+                    operator: *operator,
+                    source,
+                });
+                current.push(Operand::Register(dest));
+            }
+            Instruction::CCall(call) => {
+                let args = current.split_off(current.len() - call.n_args as usize);
+                let dest = fresh();
+                instructions.push(IrInstruction::Call {
+                    call: call.clone(),
+                    args,
+                    dest,
+                });
+                current.push(Operand::Register(dest));
+            }
+            Instruction::CIf(label) => {
+                let condition = current.pop().unwrap();
+                flush(current, &mut instructions);
+                instructions.push(IrInstruction::If {
+                    condition,
+                    label: label.clone(),
+                });
+                *current = slots(current.len());
+            }
+            Instruction::CGoto(label) => {
+                flush(current, &mut instructions);
+                instructions.push(IrInstruction::Goto(label.clone()));
+                stack = None;
+            }
+            Instruction::CReturn => {
+                let value = current.pop().unwrap();
+                instructions.push(IrInstruction::Return { value });
+                stack = None;
+            }
+            Instruction::CLabel(_) | Instruction::CFunction(_) | Instruction::CAsm(_) => {
+                unreachable!()
+            }
+        }
+    }
+    Some(IrFunction {
+        function: function.clone(),
+        instructions,
+    })
+}
+
+/// Copies the values on the stack into the registers of their slots.
+fn flush(stack: &[Operand], instructions: &mut Vec<IrInstruction>) {
+    for (slot, operand) in stack.iter().enumerate() {
+        let slot = slot as VirtualRegister;
+        if *operand != Operand::Register(slot) {
+            instructions.push(IrInstruction::Copy {
+                dest: slot,
+                source: *operand,
+            });
+        }
+    }
+}
+
+/// Where each register is defined and used, by instruction position.
+pub struct DefUse {
+    pub defs: HashMap<VirtualRegister, Vec<usize>>,
+    pub uses: HashMap<VirtualRegister, Vec<usize>>,
+}
+
+impl DefUse {
+    pub fn new(function: &IrFunction) -> Self {
+        let mut defs: HashMap<VirtualRegister, Vec<usize>> = HashMap::new();
+        let mut uses: HashMap<VirtualRegister, Vec<usize>> = HashMap::new();
+        for (position, instruction) in function.instructions.iter().enumerate() {
+            if let Some(dest) = instruction.dest() {
+                defs.entry(dest).or_default().push(position);
+            }
+            for register in instruction.uses() {
+                uses.entry(register).or_default().push(position);
+            }
+        }
+        Self { defs, uses }
+    }
+
+    fn single_def(&self, register: VirtualRegister) -> Option<usize> {
+        match self.defs.get(&register).map(Vec::as_slice) {
+            Some([position]) => Some(*position),
+            _ => None,
+        }
+    }
+}
+
+/// The registers live on entry to and exit from each instruction.
+pub struct Liveness {
+    pub live_in: Vec<HashSet<VirtualRegister>>,
+    pub live_out: Vec<HashSet<VirtualRegister>>,
+}
+
+impl Liveness {
+    pub fn new(function: &IrFunction) -> Self {
+        let length = function.instructions.len();
+        let labels = function.label_positions();
+        let successors: Vec<Vec<usize>> = (0..length)
+            .map(|position| function.successors(position, &labels))
+            .collect();
+        let mut live_in: Vec<HashSet<VirtualRegister>> = vec![HashSet::new(); length];
+        let mut live_out: Vec<HashSet<VirtualRegister>> = vec![HashSet::new(); length];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for position in (0..length).rev() {
+                let out: HashSet<VirtualRegister> = successors[position]
+                    .iter()
+                    .flat_map(|successor| live_in[*successor].iter().copied())
+                    .collect();
+                let instruction = &function.instructions[position];
+                let mut entry: HashSet<VirtualRegister> = out.clone();
+                if let Some(dest) = instruction.dest() {
+                    entry.remove(&dest);
+                }
+                entry.extend(instruction.uses());
+                if entry != live_in[position] || out != live_out[position] {
+                    live_in[position] = entry;
+                    live_out[position] = out;
+                    changed = true;
+                }
+            }
+        }
+        Self { live_in, live_out }
+    }
+}
+
+/// Runs copy propagation, copy coalescing and dead code removal until none
+/// of them changes the function.
+pub fn optimize(function: &mut IrFunction) {
+    loop {
+        while propagate_copies(function) || coalesce_copies(function) {}
+        if !remove_dead_code(function) {
+            break;
+        }
+    }
+}
+
+/// Replaces the uses of a register that only copies a constant or an earlier
+/// value of the same block with the copied operand.
+fn propagate_copies(function: &mut IrFunction) -> bool {
+    let def_use = DefUse::new(function);
+    for position in 0..function.instructions.len() {
+        let (dest, source) = match function.instructions[position] {
+            IrInstruction::Copy { dest, source } => (dest, source),
+            _ => continue,
+        };
+        if def_use.single_def(dest).is_none() {
+            continue;
+        }
+        if let Operand::Register(register) = source {
+            match def_use.single_def(register) {
+                Some(def) if def < position && same_block(function, def, position) => {}
+                _ => continue,
+            }
+        }
+        for use_position in def_use.uses.get(&dest).into_iter().flatten() {
+            for operand in function.instructions[*use_position].operands_mut() {
+                if *operand == Operand::Register(dest) {
+                    *operand = source;
+                }
+            }
+        }
+        function.instructions.remove(position);
+        return true;
+    }
+    false
+}
+
+/// Makes a value that is only computed to be copied into a slot register be
+/// computed into the slot register directly.
+fn coalesce_copies(function: &mut IrFunction) -> bool {
+    let def_use = DefUse::new(function);
+    for position in 0..function.instructions.len() {
+        let (dest, source) = match function.instructions[position] {
+            IrInstruction::Copy {
+                dest,
+                source: Operand::Register(source),
+            } => (dest, source),
+            _ => continue,
+        };
+        let def = match def_use.single_def(source) {
+            Some(def) if def < position && same_block(function, def, position) => def,
+            _ => continue,
+        };
+        if def_use.uses.get(&source).map(Vec::len) != Some(1) {
+            continue;
+        }
+        let dest_touched = function.instructions[def + 1..position]
+            .iter()
+            .any(|instruction| {
+                instruction.dest() == Some(dest) || instruction.uses().contains(&dest)
+            });
+        if dest_touched {
+            continue;
+        }
+        function.instructions[def].set_dest(dest);
+        function.instructions.remove(position);
+        return true;
+    }
+    false
+}
+
+fn remove_dead_code(function: &mut IrFunction) -> bool {
+    let liveness = Liveness::new(function);
+    let length = function.instructions.len();
+    let mut position = 0;
+    function.instructions.retain(|instruction| {
+        position += 1;
+        match instruction.dest() {
+            Some(dest) if instruction.is_pure() => liveness.live_out[position - 1].contains(&dest),
+            _ => true,
+        }
+    });
+    function.instructions.len() != length
+}
+
+/// Whether execution always runs straight from `from` on to `to`.
+pub fn same_block(function: &IrFunction, from: usize, to: usize) -> bool {
+    !function.instructions[from..to]
+        .iter()
+        .any(IrInstruction::is_block_boundary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_programs::parse_source;
+
+    fn lower(source: &str) -> Option<IrFunction> {
+        let instructions = parse_source(source, &mut HashMap::new());
+        match &instructions[0] {
+            Instruction::CFunction(function) => lower_function(function, &instructions[1..]),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn copies_into_stack_slots_are_propagated() {
+        let mut function = lower(
+            "function Main.abs 0
+            push argument 0
+            push argument 0
+            push constant 0
+            lt
+            if-goto NEGATIVE
+            return
+            label NEGATIVE
+            neg
+            return",
+        )
+        .unwrap();
+        optimize(&mut function);
+        let liveness = Liveness::new(&function);
+        // The copy into the stack slot before the branch is propagated, so
+        // the loaded argument itself is what stays live across it
+        let argument = match function.instructions[0] {
+            IrInstruction::Load { dest, .. } => dest,
+            _ => panic!("{:?}", function.instructions),
+        };
+        assert_eq!(liveness.live_out[3], HashSet::from([argument]));
+        assert!(!function
+            .instructions
+            .iter()
+            .any(|instruction| matches!(instruction, IrInstruction::Copy { .. })));
+    }
+
+    #[test]
+    fn paths_with_different_stack_depths_are_not_lowered() {
+        let function = lower(
+            "function Main.odd 0
+            push argument 0
+            if-goto DEEPER
+            goto JOIN
+            label DEEPER
+            push constant 1
+            label JOIN
+            push constant 0
+            return",
+        );
+        assert!(function.is_none());
+    }
+}
@@ -0,0 +1,150 @@
+//! Shared routines for the operators the Hack CPU has no instructions for.
+//! Each routine the program calls is emitted once after it, and the calls
+//! jump to it with the operands in R13 and R14 and the return address in R15.
+//! See the routine templates in `asm_templates`.
+
+use std::collections::BTreeSet;
+
+use crate::asm_templates::{
+    COMMAND_ROUTINE_CALL, ROUTINE_DIVIDE, ROUTINE_MULTIPLY_EXTENDED, ROUTINE_MULTIPLY_STANDARD,
+    ROUTINE_RETURN_FROM_VARIABLE, ROUTINE_SHIFT_RIGHT, ROUTINE_SHIFT_RIGHT_BIT,
+    ROUTINE_SHIFT_RIGHT_END,
+};
+use crate::compiler::{comparison_label, CodegenOptions, TargetProfile};
+use crate::instructions::{
+    ArithmeticType, ExtendedArithmeticOperator, Instruction, Module, ShiftArithmeticOperator,
+};
+use crate::intrinsics;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Routine {
+    Multiply,
+    /// Leaves the quotient in D and the remainder in R14.
+    Divide,
+    /// An arithmetic shift right of R13 by a constant amount, for the
+    /// standard profile.
+    ShiftRight(u16),
+}
+
+impl Routine {
+    /// The routine an operator calls under the profile, if any.
+    pub fn of(operator: ArithmeticType, profile: TargetProfile) -> Option<Routine> {
+        match operator {
+            ArithmeticType::Extended(ExtendedArithmeticOperator::Multiply) => {
+                Some(Routine::Multiply)
+            }
+            ArithmeticType::Extended(
+                ExtendedArithmeticOperator::Divide | ExtendedArithmeticOperator::Modulo,
+            ) => Some(Routine::Divide),
+            ArithmeticType::Shift(ShiftArithmeticOperator::ShiftRight, amount)
+                if profile == TargetProfile::Standard && amount > 0 =>
+            {
+                Some(Routine::ShiftRight(amount))
+            }
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Routine::Multiply => String::from("MULTIPLY"),
+            Routine::Divide => String::from("DIVIDE"),
+            Routine::ShiftRight(amount) => format!("SHIFT_RIGHT_{}", amount),
+        }
+    }
+
+    fn create(&self, profile: TargetProfile) -> String {
+        match self {
+            Routine::Multiply if profile == TargetProfile::Extended => {
+                ROUTINE_MULTIPLY_EXTENDED.to_string() + ROUTINE_RETURN_FROM_VARIABLE
+            }
+            Routine::Multiply => {
+                ROUTINE_MULTIPLY_STANDARD.to_string() + ROUTINE_RETURN_FROM_VARIABLE
+            }
+            Routine::Divide => ROUTINE_DIVIDE.to_string() + ROUTINE_RETURN_FROM_VARIABLE,
+            Routine::ShiftRight(amount) => {
+                let mut code =
+                    ROUTINE_SHIFT_RIGHT.replace("SIGN_BITS", &(1 << (15 - amount)).to_string());
+                for bit in *amount..=14 {
+                    code.push_str(
+                        &ROUTINE_SHIFT_RIGHT_BIT
+                            .replace("SHIFTED_MASK", &(1 << (bit - amount)).to_string())
+                            .replace("MASK", &(1 << bit).to_string())
+                            .replace("BIT", &bit.to_string()),
+                    );
+                }
+                (code + ROUTINE_SHIFT_RIGHT_END).replace("AMOUNT", &amount.to_string())
+            }
+        }
+    }
+}
+
+/// Calls the routine with its operands in place, leaving its result in D. The
+/// return label takes a comparison number to stay unique.
+pub fn create_routine_call(
+    routine: Routine,
+    file_name: &str,
+    comparison_count: &mut u16,
+) -> String {
+    *comparison_count += 1;
+    COMMAND_ROUTINE_CALL
+        .replace("ROUTINE_NAME", &routine.name())
+        .replace(
+            "JUMP_NUMBER",
+            &comparison_label(file_name, *comparison_count),
+        )
+}
+
+/// Calls the routine of a multiplication or division with x in R13 and y in
+/// R14, leaving the result in D.
+pub fn create_extended_routine_call(
+    operator: ExtendedArithmeticOperator,
+    file_name: &str,
+    comparison_count: &mut u16,
+) -> String {
+    match operator {
+        ExtendedArithmeticOperator::Multiply => {
+            create_routine_call(Routine::Multiply, file_name, comparison_count)
+        }
+        ExtendedArithmeticOperator::Divide => {
+            create_routine_call(Routine::Divide, file_name, comparison_count)
+        }
+        ExtendedArithmeticOperator::Modulo => {
+            create_routine_call(Routine::Divide, file_name, comparison_count) + "@R14\nD=M\n"
+        }
+        _ => panic!("{:?} has no routine", operator),
+    }
+}
+
+/// The routines the instructions call under the options, including those of
+/// intrinsics.
+fn called_routines(instructions: &[Instruction], options: &CodegenOptions) -> BTreeSet<Routine> {
+    instructions
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::CArithmetic(operator) => Routine::of(*operator, options.profile),
+            Instruction::CCall(call) => intrinsics::find(call, options)
+                .and_then(|intrinsic| intrinsic.operator())
+                .and_then(|operator| Routine::of(operator, options.profile)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whether the instructions call any routine, which may then overwrite R13 to
+/// R15.
+pub fn calls_routines(instructions: &[Instruction], options: &CodegenOptions) -> bool {
+    !called_routines(instructions, options).is_empty()
+}
+
+/// The routines the modules call, emitted once after the program.
+pub fn create_routines(modules: &[Module], options: &CodegenOptions) -> String {
+    let routines: BTreeSet<Routine> = modules
+        .iter()
+        .flat_map(|module| called_routines(&module.instructions, options))
+        .collect();
+    routines
+        .iter()
+        .map(|routine| routine.create(options.profile))
+        .collect()
+}
@@ -0,0 +1,316 @@
+use crate::asm_templates::{
+    BATCHED_BINARY, BATCHED_IF_GOTO, BATCHED_POP, BATCHED_POP_DIRECT, BATCHED_POP_SMALL_INDEX,
+    BATCHED_PUSH, BATCHED_PUSH_DIRECT, BATCHED_PUSH_LITERAL, BATCHED_PUSH_SMALL_INDEX,
+    BATCHED_UNARY,
+};
+use crate::compiler::{
+    create_arithmetic_operator, create_asm_operator, create_call_operator,
+    create_compare_branch_operator, create_function_operator, create_goto_operator,
+    create_label_operator, create_return_operator, create_shift_at, create_tail_call_operator,
+    io_register, match_compare_branch, match_tail_call, pointer_register, small_index_address,
+    CodegenOptions, TargetProfile,
+};
+use crate::instructions::{
+    ArithmeticType, BinaryArithmeticOperator, FrameKind, Instruction, Label, Pop, Push, Segment,
+    UnaryArithmeticOperator,
+};
+use crate::intrinsics;
+use crate::memory_layout::MemoryLayout;
+
+/// Compiles instructions while batching SP updates inside basic blocks.
+///
+/// Stack slots are addressed relative to the SP stored in memory, which is
+/// only written back at block exits (labels, jumps, calls, function entry and
+/// returns) and before the templates that read SP themselves. A template that
+/// leaves A on a stack slot lets the next one step from there instead of
+/// reloading SP.
+pub fn compile(
+    instructions: Vec<Instruction>,
+    file_name: &str,
+    layout: &MemoryLayout,
+    options: &CodegenOptions,
+) -> Vec<String> {
+    let profile = options.profile;
+    let mut result: Vec<String> = vec![];
+    let mut comparison_count: u16 = 0;
+    let mut current_frame = FrameKind::Full;
+    let mut stack = PendingStack::default();
+    let mut position: usize = 0;
+    while position < instructions.len() {
+        // Only the templates that end on a stack slot say where A is
+        let a_slot = stack.a_slot.take();
+        if let Some((call, consumed)) = match_tail_call(&instructions[position..], options) {
+            result.push(stack.write_back());
+            result.push(create_tail_call_operator(call, current_frame));
+            position += consumed;
+            continue;
+        }
+        if let Some((branch, consumed)) = match_compare_branch(&instructions[position..]) {
+            result.push(stack.write_back());
+            result.push(create_compare_branch_operator(
+                &branch,
+                file_name,
+                &mut comparison_count,
+            ));
+            position += consumed;
+            continue;
+        }
+
+        let instruction = instructions[position].clone();
+        position += 1;
+        let compiled_instruction: Option<String> = match instruction {
+            Instruction::CArithmetic(arithmetic_operator) => Some(create_batched_arithmetic(
+                arithmetic_operator,
+                file_name,
+                profile,
+                &mut stack,
+                a_slot,
+                &mut comparison_count,
+            )),
+            Instruction::CPush(push) => {
+                let asm = create_push_operator(&push, file_name, layout, stack.offset, a_slot);
+                stack.a_slot = Some(stack.offset);
+                stack.offset += 1;
+                Some(asm)
+            }
+            Instruction::CPop(pop) => {
+                let asm = create_pop_operator(&pop, file_name, layout, stack.offset, a_slot);
+                stack.offset -= 1;
+                Some(asm)
+            }
+            Instruction::CIf(ref label) => Some(create_if_operator(label, &mut stack, a_slot)),
+            Instruction::CLabel(ref label) => {
+                create_label_operator(label).map(|asm| stack.write_back() + &asm)
+            }
+            Instruction::CGoto(ref label) => {
+                create_goto_operator(label).map(|asm| stack.write_back() + &asm)
+            }
+            Instruction::CCall(ref call) => match intrinsics::find(call, options) {
+                Some(intrinsic) => Some(
+                    stack.write_back()
+                        + &intrinsic.create(file_name, profile, &mut comparison_count),
+                ),
+                None => create_call_operator(call).map(|asm| stack.write_back() + &asm),
+            },
+            Instruction::CFunction(ref function) => {
+                current_frame = function.frame;
+                create_function_operator(function, layout).map(|asm| stack.write_back() + &asm)
+            }
+            Instruction::CAsm(ref asm) => {
+                create_asm_operator(asm).map(|asm| stack.write_back() + &asm)
+            }
+            Instruction::CReturn => {
+                create_return_operator(layout, current_frame).map(|asm| stack.write_back() + &asm)
+            }
+        };
+        match compiled_instruction {
+            Some(instruction_asm) => result.push(instruction_asm),
+            None => panic!("Couldn't compile instruction {:?}", instruction),
+        }
+    }
+    result.push(stack.write_back());
+    result
+}
+
+/// The SP update a basic block has not written back yet.
+#[derive(Default)]
+struct PendingStack {
+    /// The distance between the logical SP and the SP stored in memory.
+    offset: i16,
+    /// The stack slot the last template left A on, relative to the SP stored
+    /// in memory.
+    a_slot: Option<i16>,
+}
+
+impl PendingStack {
+    /// Moves the SP stored in memory by the pending offset, leaving D untouched.
+    fn write_back(&mut self) -> String {
+        self.a_slot = None;
+        if self.offset == 0 {
+            return String::new();
+        }
+        let step = if self.offset < 0 {
+            "M=M-1\n"
+        } else {
+            "M=M+1\n"
+        };
+        let asm = String::from("@SP\n") + &step.repeat(self.offset.unsigned_abs() as usize);
+        self.offset = 0;
+        asm
+    }
+}
+
+/// Points A at the stack slot `slot` places above the SP stored in memory.
+fn stack_slot(slot: i16) -> String {
+    let (first, step) = match slot {
+        0 => return String::from("@SP\nA=M\n"),
+        s if s < 0 => ("A=M-1\n", "A=A-1\n"),
+        _ => ("A=M+1\n", "A=A+1\n"),
+    };
+    String::from("@SP\n") + first + &step.repeat(slot.unsigned_abs() as usize - 1)
+}
+
+/// Points A at `slot`, stepping from the slot A is on when that is shorter
+/// than reloading SP.
+fn stack_slot_from(a_slot: Option<i16>, slot: i16) -> String {
+    let reload = stack_slot(slot);
+    let from = match a_slot {
+        Some(from) => from,
+        None => return reload,
+    };
+    let step = if slot < from { "A=A-1\n" } else { "A=A+1\n" };
+    let steps = (slot - from).unsigned_abs() as usize;
+    if steps < reload.lines().count() {
+        step.repeat(steps)
+    } else {
+        reload
+    }
+}
+
+fn create_batched_arithmetic(
+    arithmetic_operator: ArithmeticType,
+    file_name: &str,
+    profile: TargetProfile,
+    stack: &mut PendingStack,
+    a_slot: Option<i16>,
+    comparison_count: &mut u16,
+) -> String {
+    let top = stack.offset - 1;
+    match arithmetic_operator {
+        ArithmeticType::Unary(operator) => {
+            let sign = match operator {
+                UnaryArithmeticOperator::Negate => "-",
+                UnaryArithmeticOperator::Not => "!",
+            };
+            stack.a_slot = Some(top);
+            BATCHED_UNARY
+                .replace("STACK_SLOT", &stack_slot_from(a_slot, top))
+                .replace("{}", sign)
+        }
+        // Shifting on the standard CPU reloads the slot after A is clobbered
+        ArithmeticType::Shift(operator, amount) => create_shift_at(
+            &stack_slot(top),
+            operator,
+            amount,
+            profile,
+            file_name,
+            comparison_count,
+        ),
+        // Like the comparisons, these templates address the stack through SP
+        ArithmeticType::Extended(_) => {
+            stack.write_back()
+                + &create_arithmetic_operator(
+                    arithmetic_operator,
+                    file_name,
+                    profile,
+                    comparison_count,
+                )
+        }
+        ArithmeticType::Binary(operator) => {
+            let computation = match operator {
+                BinaryArithmeticOperator::Add => "M=D+M\n",
+                BinaryArithmeticOperator::Subtract => "M=M-D\n",
+                BinaryArithmeticOperator::And => "M=D&M\n",
+                BinaryArithmeticOperator::Or => "M=D|M\n",
+                BinaryArithmeticOperator::Eq
+                | BinaryArithmeticOperator::Gt
+                | BinaryArithmeticOperator::Lt => {
+                    // The comparison templates address the stack through SP
+                    return stack.write_back()
+                        + &create_arithmetic_operator(
+                            arithmetic_operator,
+                            file_name,
+                            profile,
+                            comparison_count,
+                        );
+                }
+            };
+            stack.offset -= 1;
+            stack.a_slot = Some(top - 1);
+            BATCHED_BINARY.replace("STACK_SLOT", &stack_slot_from(a_slot, top)) + computation
+        }
+    }
+}
+
+fn create_push_operator(
+    push: &Push,
+    file_name: &str,
+    layout: &MemoryLayout,
+    offset: i16,
+    a_slot: Option<i16>,
+) -> String {
+    match (push.segment, push.index) {
+        // Literals are written without touching A, so A can step to the slot
+        (Segment::Constant, 0 | 1) => {
+            return BATCHED_PUSH_LITERAL
+                .replace("STACK_SLOT", &stack_slot_from(a_slot, offset))
+                .replace("VALUE", &push.index.to_string());
+        }
+        (Segment::Local | Segment::Argument | Segment::This | Segment::That, 0 | 1) => {
+            return BATCHED_PUSH_SMALL_INDEX
+                .replace("SEGMENT", &push.segment.to_string())
+                .replace("ADDRESS", small_index_address(push.index))
+                .replace("STACK_SLOT", &stack_slot(offset));
+        }
+        _ => {}
+    }
+    let direct = |origin: &str, index: &str| {
+        BATCHED_PUSH_DIRECT
+            .replace("ORIGIN", origin)
+            .replace("INDEX", index)
+            .replace("STACK_SLOT", &stack_slot(offset))
+    };
+    match push.segment {
+        Segment::Local | Segment::Argument | Segment::This | Segment::That => BATCHED_PUSH
+            .replace("SEGMENT", &push.segment.to_string())
+            .replace("INDEX", &push.index.to_string())
+            .replace("STACK_SLOT", &stack_slot(offset)),
+        Segment::Pointer => direct("M", pointer_register(push.index)),
+        Segment::Temp => direct("M", &layout.temp_address(push.index).to_string()),
+        Segment::Screen | Segment::Keyboard => direct("M", &io_register(push.segment, push.index)),
+        Segment::Constant => direct("A", &push.index.to_string()),
+        Segment::Static => direct("M", &format!("{}.{}", file_name, push.index)),
+    }
+}
+
+fn create_pop_operator(
+    pop: &Pop,
+    file_name: &str,
+    layout: &MemoryLayout,
+    offset: i16,
+    a_slot: Option<i16>,
+) -> String {
+    let top = offset - 1;
+    let direct = |segment: &str| {
+        BATCHED_POP_DIRECT
+            .replace("SEGMENT", segment)
+            .replace("STACK_SLOT", &stack_slot_from(a_slot, top))
+    };
+    match pop.segment {
+        Segment::Local | Segment::Argument | Segment::This | Segment::That if pop.index <= 1 => {
+            BATCHED_POP_SMALL_INDEX
+                .replace("STACK_SLOT", &stack_slot_from(a_slot, top))
+                .replace("SEGMENT", &pop.segment.to_string())
+                .replace("ADDRESS", small_index_address(pop.index))
+        }
+        Segment::Local | Segment::Argument | Segment::This | Segment::That => BATCHED_POP
+            .replace("SEGMENT", &pop.segment.to_string())
+            .replace("INDEX", &pop.index.to_string())
+            .replace("STACK_SLOT", &stack_slot(top)),
+        Segment::Pointer => direct(pointer_register(pop.index)),
+        Segment::Temp => direct(&layout.temp_address(pop.index).to_string()),
+        Segment::Static => direct(&format!("{}.{}", file_name, pop.index)),
+        Segment::Screen => direct(&io_register(pop.segment, pop.index)),
+        Segment::Constant => panic!("Cannot pop from constant"),
+        Segment::Keyboard => panic!("Cannot pop to keyboard"),
+    }
+}
+
+fn create_if_operator(label: &Label, stack: &mut PendingStack, a_slot: Option<i16>) -> String {
+    let slot = stack_slot_from(a_slot, stack.offset - 1);
+    stack.offset -= 1;
+    BATCHED_IF_GOTO
+        .replace("STACK_SLOT", &slot)
+        .replace("STACK_WRITE_BACK", &stack.write_back())
+        .replace("LABEL", &label.extract_label_name())
+}
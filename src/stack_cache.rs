@@ -0,0 +1,253 @@
+use crate::asm_templates::{
+    CACHED_BINARY, CACHED_BRANCH, CACHED_COMPARE_EQUAL, CACHED_COMPARE_RESULT,
+    CACHED_COMPARE_SIGNED, CACHED_LOAD, CACHED_POP, CACHED_POP_DIRECT, CACHED_PUSH,
+    CACHED_PUSH_DIRECT, CACHED_ROUTINE_OPERANDS, CACHED_SPILL, CACHED_UNARY,
+};
+use crate::compiler::{
+    comparison_label, create_asm_operator, create_call_operator, create_extended_operator,
+    create_function_operator, create_goto_operator, create_label_operator, create_return_operator,
+    create_shift_on_d, create_tail_call_operator, io_register, match_compare_branch,
+    match_tail_call, pointer_register, CodegenOptions, TargetProfile,
+};
+use crate::instructions::{
+    ArithmeticType, BinaryArithmeticOperator, ExtendedArithmeticOperator, FrameKind, Instruction,
+    Label, Pop, Push, Segment, UnaryArithmeticOperator,
+};
+use crate::intrinsics;
+use crate::memory_layout::MemoryLayout;
+use crate::routines::create_extended_routine_call;
+
+/// Compiles instructions while keeping the top of the stack in D.
+///
+/// `cached` tracks whether D currently holds the top of the stack. Values are
+/// only written back to the memory stack ("spilled") where another piece of
+/// code expects the whole stack in memory: labels, jumps, calls, function
+/// entry and returns. Every template that runs while the top is cached must
+/// leave D untouched or consume it.
+pub fn compile(
+    instructions: Vec<Instruction>,
+    file_name: &str,
+    layout: &MemoryLayout,
+    options: &CodegenOptions,
+) -> Vec<String> {
+    let profile = options.profile;
+    let mut result: Vec<String> = vec![];
+    let mut comparison_count: u16 = 0;
+    let mut current_frame = FrameKind::Full;
+    let mut cached = false;
+    let mut position: usize = 0;
+    while position < instructions.len() {
+        if let Some((call, consumed)) = match_tail_call(&instructions[position..], options) {
+            result.push(spill(&mut cached));
+            result.push(create_tail_call_operator(call, current_frame));
+            position += consumed;
+            continue;
+        }
+        if let Some((branch, consumed)) = match_compare_branch(&instructions[position..]) {
+            result.push(load(&mut cached));
+            result.push(create_compare(
+                branch.operator,
+                file_name,
+                &mut comparison_count,
+            ));
+            result.push(
+                CACHED_BRANCH
+                    .replace("LABEL", &branch.label.extract_label_name())
+                    .replace("JUMP_TYPE", branch.jump_type()),
+            );
+            cached = false;
+            position += consumed;
+            continue;
+        }
+
+        let instruction = instructions[position].clone();
+        position += 1;
+        let compiled_instruction: Option<String> = match instruction {
+            Instruction::CArithmetic(arithmetic_operator) => Some(
+                load(&mut cached)
+                    + &create_arithmetic_operator(
+                        arithmetic_operator,
+                        file_name,
+                        profile,
+                        &mut comparison_count,
+                    ),
+            ),
+            Instruction::CPush(push) => {
+                let asm = spill(&mut cached) + &create_push_operator(&push, file_name, layout);
+                cached = true;
+                Some(asm)
+            }
+            Instruction::CPop(pop) => {
+                let asm = load(&mut cached) + &create_pop_operator(&pop, file_name, layout);
+                cached = false;
+                Some(asm)
+            }
+            Instruction::CIf(ref label) => {
+                let asm = load(&mut cached) + &create_if_operator(label);
+                cached = false;
+                Some(asm)
+            }
+            Instruction::CLabel(ref label) => {
+                create_label_operator(label).map(|asm| spill(&mut cached) + &asm)
+            }
+            Instruction::CGoto(ref label) => {
+                create_goto_operator(label).map(|asm| spill(&mut cached) + &asm)
+            }
+            Instruction::CCall(ref call) => match intrinsics::find(call, options) {
+                Some(intrinsic) => Some(
+                    spill(&mut cached)
+                        + &intrinsic.create(file_name, profile, &mut comparison_count),
+                ),
+                None => create_call_operator(call).map(|asm| spill(&mut cached) + &asm),
+            },
+            Instruction::CFunction(ref function) => {
+                current_frame = function.frame;
+                create_function_operator(function, layout).map(|asm| spill(&mut cached) + &asm)
+            }
+            Instruction::CAsm(ref asm) => {
+                create_asm_operator(asm).map(|asm| spill(&mut cached) + &asm)
+            }
+            Instruction::CReturn => {
+                create_return_operator(layout, current_frame).map(|asm| spill(&mut cached) + &asm)
+            }
+        };
+        match compiled_instruction {
+            Some(instruction_asm) => result.push(instruction_asm),
+            None => panic!("Couldn't compile instruction {:?}", instruction),
+        }
+    }
+    result.push(spill(&mut cached));
+    result
+}
+
+/// Makes sure the top of the stack is in D.
+fn load(cached: &mut bool) -> String {
+    if *cached {
+        return String::new();
+    }
+    *cached = true;
+    CACHED_LOAD.to_string()
+}
+
+/// Makes sure the whole stack is in memory.
+fn spill(cached: &mut bool) -> String {
+    if !*cached {
+        return String::new();
+    }
+    *cached = false;
+    CACHED_SPILL.to_string()
+}
+
+/// Expects y in D and leaves a value whose sign matches x - y, with x popped.
+/// Always takes a new comparison number, which the caller may reuse for its
+/// own labels.
+fn create_compare(
+    operator: BinaryArithmeticOperator,
+    file_name: &str,
+    comparison_count: &mut u16,
+) -> String {
+    *comparison_count += 1;
+    match operator {
+        BinaryArithmeticOperator::Eq => CACHED_COMPARE_EQUAL.to_string(),
+        _ => CACHED_COMPARE_SIGNED.replace(
+            "JUMP_NUMBER",
+            &comparison_label(file_name, *comparison_count),
+        ),
+    }
+}
+
+fn create_arithmetic_operator(
+    arithmetic_operator: ArithmeticType,
+    file_name: &str,
+    profile: TargetProfile,
+    comparison_count: &mut u16,
+) -> String {
+    match arithmetic_operator {
+        ArithmeticType::Unary(operator) => match operator {
+            UnaryArithmeticOperator::Negate => CACHED_UNARY.replace("{}", "-"),
+            UnaryArithmeticOperator::Not => CACHED_UNARY.replace("{}", "!"),
+        },
+        ArithmeticType::Binary(operator) => match operator {
+            BinaryArithmeticOperator::Add => CACHED_BINARY.replace("{}", "D+M"),
+            BinaryArithmeticOperator::Subtract => CACHED_BINARY.replace("{}", "M-D"),
+            BinaryArithmeticOperator::And => CACHED_BINARY.replace("{}", "D&M"),
+            BinaryArithmeticOperator::Or => CACHED_BINARY.replace("{}", "D|M"),
+            BinaryArithmeticOperator::Eq
+            | BinaryArithmeticOperator::Gt
+            | BinaryArithmeticOperator::Lt => {
+                let jump_type = match operator {
+                    BinaryArithmeticOperator::Eq => "JEQ",
+                    BinaryArithmeticOperator::Gt => "JGT",
+                    _ => "JLT",
+                };
+                create_compare(operator, file_name, comparison_count)
+                    + &CACHED_COMPARE_RESULT
+                        .replace("JUMP_TYPE", jump_type)
+                        .replace(
+                            "JUMP_NUMBER",
+                            &comparison_label(file_name, *comparison_count),
+                        )
+            }
+        },
+        ArithmeticType::Shift(operator, amount) => {
+            create_shift_on_d(operator, amount, profile, file_name, comparison_count)
+        }
+        ArithmeticType::Extended(operator) => match operator {
+            ExtendedArithmeticOperator::Multiply
+            | ExtendedArithmeticOperator::Divide
+            | ExtendedArithmeticOperator::Modulo => {
+                CACHED_ROUTINE_OPERANDS.to_string()
+                    + &create_extended_routine_call(operator, file_name, comparison_count)
+            }
+            _ => {
+                CACHED_SPILL.to_string()
+                    + &create_extended_operator(operator, file_name, profile, comparison_count)
+                    + CACHED_LOAD
+            }
+        },
+    }
+}
+
+fn create_push_operator(push: &Push, file_name: &str, layout: &MemoryLayout) -> String {
+    let direct = |origin: &str, index: &str| {
+        CACHED_PUSH_DIRECT
+            .replace("ORIGIN", origin)
+            .replace("INDEX", index)
+    };
+    match push.segment {
+        Segment::Local | Segment::Argument | Segment::This | Segment::That => CACHED_PUSH
+            .replace("SEGMENT", &push.segment.to_string())
+            .replace("INDEX", &push.index.to_string()),
+        Segment::Pointer => direct("M", pointer_register(push.index)),
+        Segment::Temp => direct("M", &layout.temp_address(push.index).to_string()),
+        Segment::Screen | Segment::Keyboard => direct("M", &io_register(push.segment, push.index)),
+        Segment::Constant => direct("A", &push.index.to_string()),
+        Segment::Static => direct("M", &format!("{}.{}", file_name, push.index)),
+    }
+}
+
+fn create_pop_operator(pop: &Pop, file_name: &str, layout: &MemoryLayout) -> String {
+    match pop.segment {
+        Segment::Local | Segment::Argument | Segment::This | Segment::That => CACHED_POP
+            .replace("SEGMENT", &pop.segment.to_string())
+            .replace("INDEX", &pop.index.to_string()),
+        Segment::Pointer => CACHED_POP_DIRECT.replace("SEGMENT", pointer_register(pop.index)),
+        Segment::Temp => {
+            CACHED_POP_DIRECT.replace("SEGMENT", &layout.temp_address(pop.index).to_string())
+        }
+        Segment::Static => {
+            CACHED_POP_DIRECT.replace("SEGMENT", &format!("{}.{}", file_name, pop.index))
+        }
+        Segment::Screen => {
+            CACHED_POP_DIRECT.replace("SEGMENT", &io_register(pop.segment, pop.index))
+        }
+        Segment::Constant => panic!("Cannot pop from constant"),
+        Segment::Keyboard => panic!("Cannot pop to keyboard"),
+    }
+}
+
+fn create_if_operator(label: &Label) -> String {
+    CACHED_BRANCH
+        .replace("LABEL", &label.extract_label_name())
+        .replace("JUMP_TYPE", "JNE")
+}
@@ -0,0 +1,82 @@
+//! Helpers for the tests that translate and run whole VM programs.
+
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+use crate::backend;
+use crate::compiler::{CodegenOptions, HackBackend};
+use crate::hack_emulator::HackEmulator;
+use crate::instructions::{Instruction, Module, Segment};
+use crate::memory_layout::MemoryLayout;
+use crate::parser;
+
+/// Parses a module per name and source, numbering the calls across all of them.
+pub fn parse_modules(sources: &[(&str, &str)]) -> Vec<Module> {
+    let mut function_calls = HashMap::new();
+    sources
+        .iter()
+        .map(|(name, source)| Module::new(name, parse_source(source, &mut function_calls)))
+        .collect()
+}
+
+/// Parses a source with indented lines.
+pub fn parse_source(source: &str, function_calls: &mut HashMap<String, u16>) -> Vec<Instruction> {
+    let lines = source.lines().map(|line| line.trim().to_string()).collect();
+    parser::parse(lines, function_calls)
+}
+
+/// Runs `Main.main` and halts once it returns.
+pub fn main_layout() -> MemoryLayout {
+    MemoryLayout {
+        entry_point: String::from("Main.main"),
+        halt_after_entry: true,
+        ..MemoryLayout::default()
+    }
+}
+
+/// The Hack translation with its bootstrap and routines.
+pub fn hack_program(modules: &[Module], layout: &MemoryLayout, options: &CodegenOptions) -> String {
+    backend::compile_program(&mut HackBackend::new(layout, options), modules)
+}
+
+pub fn run_hack(
+    modules: &[Module],
+    layout: &MemoryLayout,
+    options: &CodegenOptions,
+) -> HackEmulator {
+    let asm = hack_program(modules, layout, options);
+    let mut emulator = HackEmulator::with_profile(&asm, options.profile);
+    emulator.run(1_000_000);
+    emulator
+}
+
+/// Where the Hack assembler places the statics, ahead of any other variable.
+pub fn static_range(modules: &[Module]) -> Range<usize> {
+    let statics: HashSet<(&str, u16)> = modules
+        .iter()
+        .flat_map(|module| {
+            module
+                .instructions
+                .iter()
+                .filter_map(move |instruction| match instruction {
+                    Instruction::CPush(push) if push.segment == Segment::Static => {
+                        Some((module.name.as_str(), push.index))
+                    }
+                    Instruction::CPop(pop) if pop.segment == Segment::Static => {
+                        Some((module.name.as_str(), pop.index))
+                    }
+                    _ => None,
+                })
+        })
+        .collect();
+    16..16 + statics.len()
+}
+
+/// Compares the RAM a native translation left with the Hack code's. R13 to
+/// R15 and the routine variables belong to the Hack code only, and the stack
+/// holds return addresses.
+pub fn assert_same_ram(ram: &[i16], hack_ram: &[i16], modules: &[Module], results: Range<usize>) {
+    for range in [0..13, static_range(modules), results] {
+        assert_eq!(ram[range.clone()], hack_ram[range]);
+    }
+}
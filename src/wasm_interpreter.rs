@@ -0,0 +1,471 @@
+//! A minimal interpreter for the WebAssembly text the WAT backend emits, used
+//! by the tests to run it without a wasm runtime. Supports folded and flat
+//! instructions, blocks, loops, branches, calls, globals, the i32 arithmetic
+//! the backend uses and 16-bit memory access.
+
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::rc::Rc;
+use std::slice::Iter;
+
+const PAGE_SIZE: usize = 65536;
+
+enum Expression {
+    Atom(String),
+    List(Vec<Expression>),
+}
+
+enum Op {
+    Const(i32),
+    LocalGet(usize),
+    LocalSet(usize),
+    GlobalGet(usize),
+    GlobalSet(usize),
+    Call(usize),
+    Numeric(String),
+    Block { is_loop: bool, body: Vec<Op> },
+    If { then: Vec<Op>, otherwise: Vec<Op> },
+    Br(usize),
+    BrIf(usize),
+    BrTable(Vec<usize>),
+    Return,
+}
+
+/// How running a sequence of instructions ended.
+enum Flow {
+    Next,
+    /// Breaking out of the n-th enclosing block, 0 being the innermost.
+    Branch(usize),
+    Return,
+}
+
+struct Function {
+    params: usize,
+    locals: usize,
+    results: usize,
+    body: Rc<[Op]>,
+}
+
+pub struct WasmInterpreter {
+    functions: Vec<Function>,
+    exports: HashMap<String, usize>,
+    globals: Vec<i32>,
+    pub memory: Vec<u8>,
+    stack: Vec<i32>,
+}
+
+impl WasmInterpreter {
+    pub fn new(wat: &str) -> Self {
+        let fields = match parse(wat).pop() {
+            Some(Expression::List(module)) if atom(&module[0]) == Some("module") => module,
+            _ => panic!("Expected a module"),
+        };
+        let mut function_names = HashMap::new();
+        let mut global_names = HashMap::new();
+        let mut globals = vec![];
+        let mut memory = vec![];
+        let mut function_count = 0;
+        for field in &fields[1..] {
+            let items = list(field);
+            match atom(&items[0]) {
+                Some("func") => {
+                    if let Some(name) = items.get(1).and_then(atom) {
+                        function_names.insert(name.to_string(), function_count);
+                    }
+                    function_count += 1;
+                }
+                Some("global") => {
+                    global_names.insert(atom(&items[1]).unwrap().to_string(), globals.len());
+                    let init = list(items.last().unwrap());
+                    globals.push(atom(&init[1]).unwrap().parse().unwrap());
+                }
+                Some("memory") => {
+                    let pages: usize = atom(items.last().unwrap()).unwrap().parse().unwrap();
+                    memory = vec![0; pages * PAGE_SIZE];
+                }
+                _ => panic!("Unsupported module field"),
+            }
+        }
+
+        let mut functions = vec![];
+        let mut exports = HashMap::new();
+        for field in &fields[1..] {
+            let items = list(field);
+            if atom(&items[0]) != Some("func") {
+                continue;
+            }
+            let mut context = Context {
+                functions: &function_names,
+                globals: &global_names,
+                locals: HashMap::new(),
+                labels: vec![],
+            };
+            let mut function = Function {
+                params: 0,
+                locals: 0,
+                results: 0,
+                body: Rc::from(vec![]),
+            };
+            let mut items = items[1..].iter().peekable();
+            if items.peek().and_then(|item| atom(item)).is_some() {
+                items.next();
+            }
+            while let Some(Expression::List(header)) = items.peek() {
+                let kind = atom(&header[0]).unwrap();
+                match kind {
+                    "export" => {
+                        let name = atom(&header[1]).unwrap().trim_matches('"');
+                        exports.insert(name.to_string(), functions.len());
+                    }
+                    "param" | "local" => {
+                        let index = context.locals.len();
+                        context
+                            .locals
+                            .insert(atom(&header[1]).unwrap().to_string(), index);
+                        if kind == "param" {
+                            function.params += 1;
+                        } else {
+                            function.locals += 1;
+                        }
+                    }
+                    "result" => function.results += 1,
+                    _ => break,
+                }
+                items.next();
+            }
+            function.body = Rc::from(context.sequence(&mut items, false));
+            functions.push(function);
+        }
+
+        Self {
+            functions,
+            exports,
+            globals,
+            memory,
+            stack: vec![],
+        }
+    }
+
+    /// Calls an exported function that takes no arguments.
+    pub fn call(&mut self, name: &str) {
+        let function = self.exports[name];
+        self.invoke(function);
+    }
+
+    /// The memory as 16-bit words.
+    pub fn words(&self) -> Vec<i16> {
+        self.memory
+            .chunks(2)
+            .map(|word| i16::from_le_bytes([word[0], word[1]]))
+            .collect()
+    }
+
+    fn invoke(&mut self, index: usize) {
+        let function = &self.functions[index];
+        let (params, results) = (function.params, function.results);
+        let mut locals = self.stack.split_off(self.stack.len() - params);
+        locals.resize(params + function.locals, 0);
+        let height = self.stack.len();
+        let body = Rc::clone(&function.body);
+        self.execute(&body, &mut locals);
+        let values = self.stack.split_off(self.stack.len() - results);
+        self.stack.truncate(height);
+        self.stack.extend(values);
+    }
+
+    fn execute(&mut self, body: &[Op], locals: &mut [i32]) -> Flow {
+        for op in body {
+            match op {
+                Op::Const(value) => self.stack.push(*value),
+                Op::LocalGet(index) => self.stack.push(locals[*index]),
+                Op::LocalSet(index) => locals[*index] = self.pop(),
+                Op::GlobalGet(index) => self.stack.push(self.globals[*index]),
+                Op::GlobalSet(index) => self.globals[*index] = self.pop(),
+                Op::Call(index) => self.invoke(*index),
+                Op::Numeric(name) => self.numeric(name),
+                Op::Block { is_loop, body } => {
+                    let height = self.stack.len();
+                    loop {
+                        match self.execute(body, locals) {
+                            Flow::Next => break,
+                            Flow::Branch(0) => {
+                                self.stack.truncate(height);
+                                if !is_loop {
+                                    break;
+                                }
+                            }
+                            Flow::Branch(depth) => return Flow::Branch(depth - 1),
+                            Flow::Return => return Flow::Return,
+                        }
+                    }
+                }
+                Op::If { then, otherwise } => {
+                    let branch = if self.pop() != 0 { then } else { otherwise };
+                    match self.execute(branch, locals) {
+                        Flow::Next | Flow::Branch(0) => {}
+                        Flow::Branch(depth) => return Flow::Branch(depth - 1),
+                        Flow::Return => return Flow::Return,
+                    }
+                }
+                Op::Br(depth) => return Flow::Branch(*depth),
+                Op::BrIf(depth) => {
+                    if self.pop() != 0 {
+                        return Flow::Branch(*depth);
+                    }
+                }
+                Op::BrTable(depths) => {
+                    let index = (self.pop() as u32 as usize).min(depths.len() - 1);
+                    return Flow::Branch(depths[index]);
+                }
+                Op::Return => return Flow::Return,
+            }
+        }
+        Flow::Next
+    }
+
+    fn numeric(&mut self, name: &str) {
+        let value = match name {
+            "i32.eqz" => (self.pop() == 0) as i32,
+            "select" => {
+                let condition = self.pop();
+                let right = self.pop();
+                let left = self.pop();
+                if condition != 0 {
+                    left
+                } else {
+                    right
+                }
+            }
+            "i32.load16_s" => {
+                let address = self.pop() as u32 as usize;
+                i16::from_le_bytes([self.memory[address], self.memory[address + 1]]) as i32
+            }
+            "i32.store16" => {
+                let value = self.pop() as i16;
+                let address = self.pop() as u32 as usize;
+                self.memory[address..address + 2].copy_from_slice(&value.to_le_bytes());
+                return;
+            }
+            _ => {
+                let right = self.pop();
+                let left = self.pop();
+                match name {
+                    "i32.add" => left.wrapping_add(right),
+                    "i32.sub" => left.wrapping_sub(right),
+                    "i32.mul" => left.wrapping_mul(right),
+                    "i32.div_s" => left.checked_div(right).expect("Integer divide by zero"),
+                    "i32.rem_s" => left.checked_rem(right).expect("Integer divide by zero"),
+                    "i32.and" => left & right,
+                    "i32.or" => left | right,
+                    "i32.xor" => left ^ right,
+                    "i32.shl" => left.wrapping_shl(right as u32),
+                    "i32.shr_s" => left.wrapping_shr(right as u32),
+                    "i32.eq" => (left == right) as i32,
+                    "i32.gt_s" => (left > right) as i32,
+                    "i32.lt_s" => (left < right) as i32,
+                    "i32.gt_u" => ((left as u32) > (right as u32)) as i32,
+                    "i32.lt_u" => ((left as u32) < (right as u32)) as i32,
+                    _ => panic!("Unsupported instruction {}", name),
+                }
+            }
+        };
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> i32 {
+        self.stack.pop().expect("Empty value stack")
+    }
+}
+
+/// Names in scope while compiling a function body.
+struct Context<'a> {
+    functions: &'a HashMap<String, usize>,
+    globals: &'a HashMap<String, usize>,
+    locals: HashMap<String, usize>,
+    /// The labels of the enclosing blocks, innermost last.
+    labels: Vec<Option<String>>,
+}
+
+impl Context<'_> {
+    /// Compiles instructions up to the end of `items`, or up to an `end` for
+    /// the body of a flat block.
+    fn sequence(&mut self, items: &mut Peekable<Iter<Expression>>, until_end: bool) -> Vec<Op> {
+        let mut ops = vec![];
+        while let Some(item) = items.next() {
+            let name = match item {
+                Expression::List(folded) => {
+                    self.folded(folded, &mut ops);
+                    continue;
+                }
+                Expression::Atom(name) => name.as_str(),
+            };
+            if name == "end" && until_end {
+                return ops;
+            }
+            if name == "block" || name == "loop" {
+                let label = items.next_if(|item| is_label(item)).and_then(atom);
+                self.labels.push(label.map(String::from));
+                let body = self.sequence(items, true);
+                self.labels.pop();
+                ops.push(Op::Block {
+                    is_loop: name == "loop",
+                    body,
+                });
+                continue;
+            }
+            let mut immediates = vec![];
+            let count = match name {
+                "br_table" => usize::MAX,
+                "i32.const" | "local.get" | "local.set" | "global.get" | "global.set" | "call"
+                | "br" | "br_if" => 1,
+                _ => 0,
+            };
+            while immediates.len() < count {
+                match items
+                    .next_if(|item| matches!(item, Expression::Atom(_)) && item_is_immediate(item))
+                {
+                    Some(item) => immediates.push(atom(item).unwrap()),
+                    None => break,
+                }
+            }
+            ops.push(self.plain(name, &immediates));
+        }
+        if until_end {
+            panic!("Missing end");
+        }
+        ops
+    }
+
+    fn folded(&mut self, items: &[Expression], ops: &mut Vec<Op>) {
+        let name = atom(&items[0]).expect("Expected an instruction");
+        let mut rest = items[1..].iter().peekable();
+        match name {
+            "block" | "loop" => {
+                let label = rest.next_if(|item| is_label(item)).and_then(atom);
+                self.labels.push(label.map(String::from));
+                let body = self.sequence(&mut rest, false);
+                self.labels.pop();
+                ops.push(Op::Block {
+                    is_loop: name == "loop",
+                    body,
+                });
+            }
+            "if" => {
+                let label = rest.next_if(|item| is_label(item)).and_then(atom);
+                let mut then = vec![];
+                let mut otherwise = vec![];
+                for item in rest {
+                    let clause = list(item);
+                    let branch = match atom(&clause[0]) {
+                        Some("then") => &mut then,
+                        Some("else") => &mut otherwise,
+                        _ => {
+                            self.folded(clause, ops);
+                            continue;
+                        }
+                    };
+                    self.labels.push(label.map(String::from));
+                    *branch = self.sequence(&mut clause[1..].iter().peekable(), false);
+                    self.labels.pop();
+                }
+                ops.push(Op::If { then, otherwise });
+            }
+            _ => {
+                let mut immediates = vec![];
+                for item in rest {
+                    match item {
+                        Expression::Atom(immediate) => immediates.push(immediate.as_str()),
+                        Expression::List(operand) => self.folded(operand, ops),
+                    }
+                }
+                ops.push(self.plain(name, &immediates));
+            }
+        }
+    }
+
+    fn plain(&self, name: &str, immediates: &[&str]) -> Op {
+        match name {
+            "i32.const" => Op::Const(parse_integer(immediates[0])),
+            "local.get" => Op::LocalGet(self.locals[immediates[0]]),
+            "local.set" => Op::LocalSet(self.locals[immediates[0]]),
+            "global.get" => Op::GlobalGet(self.globals[immediates[0]]),
+            "global.set" => Op::GlobalSet(self.globals[immediates[0]]),
+            "call" => Op::Call(self.functions[immediates[0]]),
+            "br" => Op::Br(self.depth(immediates[0])),
+            "br_if" => Op::BrIf(self.depth(immediates[0])),
+            "br_table" => Op::BrTable(immediates.iter().map(|label| self.depth(label)).collect()),
+            "return" => Op::Return,
+            _ => Op::Numeric(name.to_string()),
+        }
+    }
+
+    fn depth(&self, label: &str) -> usize {
+        self.labels
+            .iter()
+            .rev()
+            .position(|name| name.as_deref() == Some(label))
+            .unwrap_or_else(|| panic!("Unknown label {}", label))
+    }
+}
+
+fn parse(text: &str) -> Vec<Expression> {
+    let mut stack = vec![vec![]];
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' => stack.push(vec![]),
+            ')' => {
+                let items = stack.pop().expect("Unbalanced parentheses");
+                stack
+                    .last_mut()
+                    .expect("Unbalanced parentheses")
+                    .push(Expression::List(items));
+            }
+            ';' if chars.peek() == Some(&';') => while chars.next_if(|&c| c != '\n').is_some() {},
+            c if c.is_whitespace() => {}
+            c => {
+                let mut token = c.to_string();
+                while let Some(c) = chars.next_if(|&c| !c.is_whitespace() && c != '(' && c != ')') {
+                    token.push(c);
+                }
+                stack.last_mut().unwrap().push(Expression::Atom(token));
+            }
+        }
+    }
+    match stack.pop() {
+        Some(items) if stack.is_empty() => items,
+        _ => panic!("Unbalanced parentheses"),
+    }
+}
+
+fn parse_integer(text: &str) -> i32 {
+    match text.strip_prefix("0x") {
+        Some(hex) => i32::from_str_radix(hex, 16).unwrap(),
+        None => text.parse().unwrap(),
+    }
+}
+
+fn atom(expression: &Expression) -> Option<&str> {
+    match expression {
+        Expression::Atom(name) => Some(name),
+        Expression::List(_) => None,
+    }
+}
+
+fn list(expression: &Expression) -> &[Expression] {
+    match expression {
+        Expression::List(items) => items,
+        Expression::Atom(name) => panic!("Expected a list, found {}", name),
+    }
+}
+
+fn is_label(expression: &Expression) -> bool {
+    matches!(atom(expression), Some(name) if name.starts_with('$'))
+}
+
+/// Immediates are names or numbers, unlike the instructions that follow them.
+fn item_is_immediate(expression: &Expression) -> bool {
+    matches!(atom(expression), Some(name) if name.starts_with(|c: char| {
+        c == '$' || c == '-' || c.is_ascii_digit()
+    }))
+}
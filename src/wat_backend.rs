@@ -0,0 +1,444 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::backend::Backend;
+use crate::frames;
+use crate::instructions::{
+    ArithmeticType, BinaryArithmeticOperator, Call, ExtendedArithmeticOperator, FrameKind,
+    Instruction, Label, Module, Segment, ShiftArithmeticOperator, UnaryArithmeticOperator,
+};
+use crate::memory_layout::{io_address, MemoryLayout};
+use crate::passes::format_instruction;
+use crate::program_symbols::{function_end, is_halt_loop, ProgramSymbols};
+use crate::wat_templates::{
+    WAT_CALL, WAT_CALL_LITE, WAT_FUNCTION, WAT_HALT, WAT_PROLOGUE, WAT_RETURN, WAT_RETURN_LITE,
+};
+
+/// The WebAssembly target, a text module with a wasm function per VM function
+/// and an exported `ram` memory for the Hack RAM. The host calls `run` and
+/// reads the RAM once it returns.
+pub struct WatBackend<'a> {
+    layout: &'a MemoryLayout,
+    symbols: ProgramSymbols,
+    return_count: usize,
+}
+
+impl<'a> WatBackend<'a> {
+    pub fn new(layout: &'a MemoryLayout) -> Self {
+        Self {
+            layout,
+            symbols: ProgramSymbols::new(&[]),
+            return_count: 0,
+        }
+    }
+}
+
+impl Backend for WatBackend<'_> {
+    fn file_extension(&self) -> &'static str {
+        "wat"
+    }
+
+    fn begin_program(&mut self, modules: &[Module]) -> String {
+        self.symbols = ProgramSymbols::new(modules);
+        let layout = self.layout;
+        let mut entry_call = Call::new(
+            &layout.entry_point,
+            &format!("{}&ret.0", layout.entry_point),
+            0,
+        );
+        entry_call.frame = frames::entry_frame(modules, &layout.entry_point);
+        WAT_PROLOGUE
+            .replace("STACK_BASE", &layout.stack_base.to_string())
+            .replace("INITIAL_LCL", &layout.initial_lcl.to_string())
+            .replace("INITIAL_ARG", &layout.initial_arg.to_string())
+            .replace("INITIAL_THIS", &layout.initial_this.to_string())
+            .replace("INITIAL_THAT", &layout.initial_that.to_string())
+            .replace("ENTRY_CALL", &self.create_call(&entry_call))
+    }
+
+    fn begin_module(&mut self, module: &Module) -> String {
+        format!("\n  ;; {}.vm\n", module.name)
+    }
+
+    /// Functions are translated whole, as their control flow has to be
+    /// structured into blocks.
+    fn emit_instruction(&mut self, module: &Module, position: usize) -> (String, usize) {
+        if !matches!(module.instructions[position], Instruction::CFunction(_)) {
+            panic!("Code outside of a function in {}.vm", module.name);
+        }
+        let end = function_end(&module.instructions, position);
+        let code = self.create_function(&module.instructions, position..end, &module.name);
+        (code, end - position)
+    }
+
+    fn end_program(&mut self) -> String {
+        String::from(")\n")
+    }
+}
+
+impl WatBackend<'_> {
+    /// Translates the function whose instructions are at `range`. Its labels
+    /// end nested blocks, so a jump forward breaks out of a block, and a jump
+    /// backward sets `$block` and goes around a loop with a `br_table`.
+    fn create_function(
+        &mut self,
+        instructions: &[Instruction],
+        range: Range<usize>,
+        module_name: &str,
+    ) -> String {
+        let function = match &instructions[range.start] {
+            Instruction::CFunction(function) => function,
+            _ => unreachable!(),
+        };
+        let segments: HashMap<String, usize> = instructions[range.clone()]
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::CLabel(label) => Some(label.extract_label_name()),
+                _ => None,
+            })
+            .zip(1..)
+            .collect();
+        let segment_of = |label: &Label| match segments.get(&label.extract_label_name()) {
+            Some(segment) => *segment,
+            None => panic!("Jump to undefined label {}", label.extract_label_name()),
+        };
+
+        let mut segment = 0;
+        let mut jumps_backward = false;
+        for position in range.clone() {
+            match &instructions[position] {
+                Instruction::CLabel(_) => segment += 1,
+                Instruction::CGoto(_) if is_halt_loop(instructions, position) => {}
+                Instruction::CGoto(label) | Instruction::CIf(label) => {
+                    jumps_backward |= segment_of(label) <= segment;
+                }
+                _ => {}
+            }
+        }
+
+        let mut code = WAT_FUNCTION
+            .replace("FUNCTION_NAME", &function_name(&function.function_name))
+            .replace("COMMENT", &format_instruction(&instructions[range.start]));
+        for _ in 0..function.n_args {
+            code.push_str("    (call $push (i32.const 0))\n");
+        }
+        if jumps_backward {
+            code.push_str("    loop $dispatch\n");
+        }
+        for i in (1..=segments.len()).rev() {
+            code.push_str(&format!("    block $S{}\n", i));
+        }
+        if jumps_backward {
+            let targets: Vec<String> = (0..=segments.len()).map(|i| format!("$S{}", i)).collect();
+            code.push_str(&format!(
+                "    block $S0\n    local.get $block\n    br_table {}\n    end\n",
+                targets.join(" ")
+            ));
+        }
+
+        let mut segment = 0;
+        let jump = |target: usize, segment: usize| {
+            if target > segment {
+                format!("(br $S{})", target)
+            } else {
+                format!("(local.set $block (i32.const {})) (br $dispatch)", target)
+            }
+        };
+        for position in range.start + 1..range.end {
+            let instruction = &instructions[position];
+            let comment = format!("    ;; {}\n", format_instruction(instruction));
+            let instruction_code = match instruction {
+                Instruction::CArithmetic(operator) => {
+                    format!("{}    {}\n", comment, arithmetic(*operator))
+                }
+                Instruction::CPush(push) => {
+                    let value = match push.segment {
+                        Segment::Constant => format!("(i32.const {})", push.index),
+                        segment => format!(
+                            "(call $get {})",
+                            self.segment_word(segment, push.index, module_name)
+                        ),
+                    };
+                    format!("{}    (call $push {})\n", comment, value)
+                }
+                Instruction::CPop(pop) => {
+                    let word = match pop.segment {
+                        Segment::Constant => panic!("Cannot pop to constant"),
+                        segment => self.segment_word(segment, pop.index, module_name),
+                    };
+                    format!("{}    (call $set {} (call $pop))\n", comment, word)
+                }
+                Instruction::CLabel(_) => {
+                    segment += 1;
+                    format!("    end\n{}", comment)
+                }
+                Instruction::CGoto(_) if is_halt_loop(instructions, position) => {
+                    String::from(WAT_HALT)
+                }
+                Instruction::CGoto(label) => {
+                    format!("{}    {}\n", comment, jump(segment_of(label), segment))
+                }
+                Instruction::CIf(label) => format!(
+                    "{}    (if (call $pop) (then {}))\n",
+                    comment,
+                    jump(segment_of(label), segment)
+                ),
+                Instruction::CFunction(_) => unreachable!(),
+                Instruction::CReturn => String::from(match function.frame {
+                    FrameKind::Full => WAT_RETURN,
+                    FrameKind::Lite => WAT_RETURN_LITE,
+                }),
+                Instruction::CCall(call) => self.create_call(call),
+                Instruction::CAsm(asm) => panic!(
+                    "{}: the asm block {:?} only compiles for the hack target, not wat",
+                    module_name, asm
+                ),
+            };
+            code.push_str(&instruction_code);
+        }
+        if jumps_backward {
+            code.push_str("    end\n");
+        }
+        code + "  )\n"
+    }
+
+    fn create_call(&mut self, call: &Call) -> String {
+        // Fails early for calls the wasm module would not validate with
+        self.symbols.function(&call.function_name);
+        let (template, frame_size) = match call.frame {
+            FrameKind::Full => (WAT_CALL, 5),
+            FrameKind::Lite => (WAT_CALL_LITE, 3),
+        };
+        let return_id = self.return_count;
+        self.return_count += 1;
+        template
+            .replace("RETURN_ID", &return_id.to_string())
+            .replace(
+                "COMMENT",
+                &format_instruction(&Instruction::CCall(call.clone())),
+            )
+            .replace("FRAME_SIZE", &(frame_size + call.n_args).to_string())
+            .replace("FUNCTION_NAME", &function_name(&call.function_name))
+    }
+
+    /// An expression for the RAM address of a segment slot.
+    fn segment_word(&self, segment: Segment, index: u16, module_name: &str) -> String {
+        let address = match segment {
+            Segment::Local => 1,
+            Segment::Argument => 2,
+            Segment::This => 3,
+            Segment::That => 4,
+            Segment::Pointer => return format!("(i32.const {})", 3 + index),
+            Segment::Temp => return format!("(i32.const {})", self.layout.temp_address(index)),
+            Segment::Screen | Segment::Keyboard => {
+                return format!("(i32.const {})", io_address(segment, index))
+            }
+            Segment::Static => {
+                return format!(
+                    "(i32.const {})",
+                    self.symbols.static_address(module_name, index)
+                )
+            }
+            Segment::Constant => unreachable!(),
+        };
+        format!(
+            "(i32.add (call $get (i32.const {})) (i32.const {}))",
+            address, index
+        )
+    }
+}
+
+/// VM function names only use characters that wasm identifiers allow.
+fn function_name(name: &str) -> String {
+    format!("${}", name)
+}
+
+/// A wasm instruction applying an operator to the top of the stack. Values
+/// are sign extended when popped and truncated when pushed, so results wrap
+/// to 16 bits.
+fn arithmetic(operator: ArithmeticType) -> String {
+    let binary = |operation: &str| {
+        format!(
+            "(local.set $y (call $pop)) (call $push ({} (call $pop) (local.get $y)))",
+            operation
+        )
+    };
+    // Comparisons give 1 or 0, which become -1 or 0
+    let compare = |operation: &str| {
+        format!(
+            "(local.set $y (call $pop)) \
+             (call $push (i32.sub (i32.const 0) ({} (call $pop) (local.get $y))))",
+            operation
+        )
+    };
+    let divide = |operation: &str| {
+        format!(
+            "(local.set $y (call $pop)) \
+             (call $push ({} (call $pop) \
+             (select (local.get $y) (i32.const 65536) (local.get $y))))",
+            operation
+        )
+    };
+    match operator {
+        ArithmeticType::Unary(UnaryArithmeticOperator::Negate) => {
+            String::from("(call $push (i32.sub (i32.const 0) (call $pop)))")
+        }
+        ArithmeticType::Unary(UnaryArithmeticOperator::Not) => {
+            String::from("(call $push (i32.xor (call $pop) (i32.const -1)))")
+        }
+        ArithmeticType::Binary(BinaryArithmeticOperator::Add) => binary("i32.add"),
+        ArithmeticType::Binary(BinaryArithmeticOperator::Subtract) => binary("i32.sub"),
+        ArithmeticType::Binary(BinaryArithmeticOperator::And) => binary("i32.and"),
+        ArithmeticType::Binary(BinaryArithmeticOperator::Or) => binary("i32.or"),
+        ArithmeticType::Binary(BinaryArithmeticOperator::Eq) => compare("i32.eq"),
+        ArithmeticType::Binary(BinaryArithmeticOperator::Gt) => compare("i32.gt_s"),
+        ArithmeticType::Binary(BinaryArithmeticOperator::Lt) => compare("i32.lt_s"),
+        ArithmeticType::Shift(ShiftArithmeticOperator::ShiftLeft, amount) => {
+            format!("(call $push (i32.shl (call $pop) (i32.const {})))", amount)
+        }
+        ArithmeticType::Shift(ShiftArithmeticOperator::ShiftRight, amount) => format!(
+            "(call $push (i32.shr_s (call $pop) (i32.const {})))",
+            amount
+        ),
+        ArithmeticType::Extended(ExtendedArithmeticOperator::Multiply) => binary("i32.mul"),
+        // Dividing by 65536 instead of 0 gives a quotient of 0 and leaves
+        // the dividend as the remainder, while the most negative value
+        // divided by -1 only overflows once it is truncated
+        ArithmeticType::Extended(ExtendedArithmeticOperator::Divide) => divide("i32.div_s"),
+        ArithmeticType::Extended(ExtendedArithmeticOperator::Modulo) => divide("i32.rem_s"),
+        ArithmeticType::Extended(ExtendedArithmeticOperator::Xor) => binary("i32.xor"),
+        // Sign extension keeps the unsigned order of 16 bit values
+        ArithmeticType::Extended(ExtendedArithmeticOperator::UnsignedLt) => compare("i32.lt_u"),
+        ArithmeticType::Extended(ExtendedArithmeticOperator::UnsignedGt) => compare("i32.gt_u"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend;
+    use crate::compiler::CodegenOptions;
+    use crate::passes::LiteFrames;
+    use crate::passes::Pass;
+    use crate::test_programs::{assert_same_ram, parse_modules, run_hack};
+    use crate::wasm_interpreter::WasmInterpreter;
+
+    const SYS: &str = "function Sys.init 0
+        push constant 5000
+        pop pointer 1
+        push constant 10
+        call Main.sum 1
+        pop that 0
+        push constant 6
+        call Main.countdown 1
+        pop that 1
+        push constant 32767
+        push constant 2
+        call Main.wrap 2
+        pop that 2
+        push constant 32767
+        neg
+        push constant 1
+        sub
+        push constant 1
+        neg
+        call Main.extended 2
+        pop that 3
+        label HALT
+        goto HALT";
+
+    const MAIN: &str = "function Main.sum 0
+        push argument 0
+        if-goto RECURSE
+        push constant 0
+        return
+        label RECURSE
+        push argument 0
+        push argument 0
+        push constant 1
+        sub
+        call Main.sum 1
+        add
+        return
+        function Main.countdown 1
+        label LOOP
+        push argument 0
+        push constant 0
+        eq
+        if-goto DONE
+        push local 0
+        push argument 0
+        add
+        pop local 0
+        push argument 0
+        push constant 1
+        sub
+        pop argument 0
+        goto LOOP
+        label DONE
+        push local 0
+        return
+        function Main.wrap 0
+        push argument 0
+        push argument 1
+        add
+        shiftleft
+        push argument 0
+        neg
+        shiftright
+        not
+        or
+        return
+        function Main.extended 0
+        push argument 0
+        push argument 1
+        mul
+        push argument 0
+        push argument 1
+        div
+        xor
+        push argument 0
+        push argument 1
+        mod
+        shl 2
+        add
+        push argument 0
+        push argument 1
+        ult
+        sub
+        push argument 0
+        shr 3
+        push argument 1
+        ugt
+        sub
+        return";
+
+    const PROGRAM: [(&str, &str); 2] = [("Sys", SYS), ("Main", MAIN)];
+
+    fn run_wat(modules: &[Module], layout: &MemoryLayout) -> Vec<i16> {
+        let wat = backend::compile_program(&mut WatBackend::new(layout), modules);
+        let mut interpreter = WasmInterpreter::new(&wat);
+        interpreter.call("run");
+        interpreter.words()
+    }
+
+    #[test]
+    fn wat_translation_leaves_the_same_ram_as_hack_code() {
+        let layout = MemoryLayout::default();
+        let modules = parse_modules(&PROGRAM);
+        let emulator = run_hack(&modules, &layout, &CodegenOptions::default());
+        assert_eq!(emulator.ram[5000..5004], [55, 21, 16383, 1]);
+        assert_same_ram(
+            &run_wat(&modules, &layout),
+            &emulator.ram,
+            &modules,
+            5000..5004,
+        );
+
+        // Lite frames change where the frames are, but not the results
+        let mut lite_modules = parse_modules(&PROGRAM);
+        LiteFrames.run(&mut lite_modules);
+        let ram = run_wat(&lite_modules, &layout);
+        assert_eq!(ram[5000..5004], emulator.ram[5000..5004]);
+    }
+}
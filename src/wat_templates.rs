@@ -0,0 +1,86 @@
+/// The exported memory is the Hack RAM, one 16-bit word per RAM address.
+/// Words are stored truncated and loaded sign extended, which gives the
+/// 16-bit wraparound of the Hack ALU. Addresses wrap at 15 bits like the A
+/// register.
+pub const WAT_PROLOGUE: &str = "(module
+  (memory (export \"ram\") 1)
+  ;; Set when the program reaches Sys.halt, which unwinds every call
+  (global $halted (mut i32) (i32.const 0))
+
+  (func $address (param $word i32) (result i32)
+    (i32.shl (i32.and (local.get $word) (i32.const 0x7FFF)) (i32.const 1)))
+  (func $get (param $word i32) (result i32)
+    (i32.load16_s (call $address (local.get $word))))
+  (func $set (param $word i32) (param $value i32)
+    (i32.store16 (call $address (local.get $word)) (local.get $value)))
+  (func $push (param $value i32)
+    (call $set (call $get (i32.const 0)) (local.get $value))
+    (call $set (i32.const 0) (i32.add (call $get (i32.const 0)) (i32.const 1))))
+  (func $pop (result i32)
+    (call $set (i32.const 0) (i32.sub (call $get (i32.const 0)) (i32.const 1)))
+    (call $get (call $get (i32.const 0))))
+
+  (func (export \"run\")
+    ;; Bootstrap
+    (call $set (i32.const 0) (i32.const STACK_BASE))
+    (call $set (i32.const 1) (i32.const INITIAL_LCL))
+    (call $set (i32.const 2) (i32.const INITIAL_ARG))
+    (call $set (i32.const 3) (i32.const INITIAL_THIS))
+    (call $set (i32.const 4) (i32.const INITIAL_THAT))
+ENTRY_CALL  )
+";
+
+/// Every function has the same locals, whether it needs them or not.
+pub const WAT_FUNCTION: &str = "
+  (func FUNCTION_NAME ;; COMMENT
+    (local $y i32) (local $frame i32) (local $block i32)
+";
+
+pub const WAT_CALL: &str = "    ;; COMMENT
+    (call $push (i32.const RETURN_ID))
+    (call $push (call $get (i32.const 1)))
+    (call $push (call $get (i32.const 2)))
+    (call $push (call $get (i32.const 3)))
+    (call $push (call $get (i32.const 4)))
+    (call $set (i32.const 2) (i32.sub (call $get (i32.const 0)) (i32.const FRAME_SIZE)))
+    (call $set (i32.const 1) (call $get (i32.const 0)))
+    (call FUNCTION_NAME)
+    (if (global.get $halted) (then (return)))
+";
+
+pub const WAT_CALL_LITE: &str = "    ;; COMMENT
+    (call $push (i32.const RETURN_ID))
+    (call $push (call $get (i32.const 1)))
+    (call $push (call $get (i32.const 2)))
+    (call $set (i32.const 2) (i32.sub (call $get (i32.const 0)) (i32.const FRAME_SIZE)))
+    (call $set (i32.const 1) (call $get (i32.const 0)))
+    (call FUNCTION_NAME)
+    (if (global.get $halted) (then (return)))
+";
+
+/// The return address in the frame is not needed, as returning from the wasm
+/// function goes back to the caller.
+pub const WAT_RETURN: &str = "    ;; return
+    (local.set $frame (call $get (i32.const 1)))
+    (call $set (call $get (i32.const 2)) (call $pop))
+    (call $set (i32.const 0) (i32.add (call $get (i32.const 2)) (i32.const 1)))
+    (call $set (i32.const 4) (call $get (i32.sub (local.get $frame) (i32.const 1))))
+    (call $set (i32.const 3) (call $get (i32.sub (local.get $frame) (i32.const 2))))
+    (call $set (i32.const 2) (call $get (i32.sub (local.get $frame) (i32.const 3))))
+    (call $set (i32.const 1) (call $get (i32.sub (local.get $frame) (i32.const 4))))
+    return
+";
+
+pub const WAT_RETURN_LITE: &str = "    ;; return // lite frame
+    (local.set $frame (call $get (i32.const 1)))
+    (call $set (call $get (i32.const 2)) (call $pop))
+    (call $set (i32.const 0) (i32.add (call $get (i32.const 2)) (i32.const 1)))
+    (call $set (i32.const 2) (call $get (i32.sub (local.get $frame) (i32.const 1))))
+    (call $set (i32.const 1) (call $get (i32.sub (local.get $frame) (i32.const 2))))
+    return
+";
+
+pub const WAT_HALT: &str = "    ;; halt
+    (global.set $halted (i32.const 1))
+    return
+";
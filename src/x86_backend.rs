@@ -0,0 +1,379 @@
+use crate::backend::Backend;
+use crate::frames;
+use crate::instructions::{
+    ArithmeticType, BinaryArithmeticOperator, Call, ExtendedArithmeticOperator, FrameKind,
+    Instruction, Module, Segment, ShiftArithmeticOperator, UnaryArithmeticOperator,
+};
+use crate::memory_layout::{io_address, MemoryLayout};
+use crate::passes::format_instruction;
+use crate::program_symbols::{is_halt_loop, ProgramSymbols};
+use crate::x86_templates::{
+    X86_CALL, X86_CALL_LITE, X86_EPILOGUE, X86_PROLOGUE, X86_RETURN, X86_RETURN_LITE,
+};
+
+/// The RAM words holding LCL, ARG, THIS and THAT.
+const POINTER_REGISTERS: [(Segment, u16); 4] = [
+    (Segment::Local, 1),
+    (Segment::Argument, 2),
+    (Segment::This, 3),
+    (Segment::That, 4),
+];
+
+/// The x86-64 target, GNU assembly for a static Linux executable that runs
+/// the program on a model of the Hack RAM and writes it to stdout once it
+/// halts. Calls push their call site number as the return address.
+pub struct X86Backend<'a> {
+    layout: &'a MemoryLayout,
+    symbols: ProgramSymbols,
+    return_count: usize,
+    /// The frame of the function being translated, which its returns unwind.
+    frame: FrameKind,
+}
+
+impl<'a> X86Backend<'a> {
+    pub fn new(layout: &'a MemoryLayout) -> Self {
+        Self {
+            layout,
+            symbols: ProgramSymbols::new(&[]),
+            return_count: 0,
+            frame: FrameKind::Full,
+        }
+    }
+}
+
+impl Backend for X86Backend<'_> {
+    fn file_extension(&self) -> &'static str {
+        "s"
+    }
+
+    fn begin_program(&mut self, modules: &[Module]) -> String {
+        self.symbols = ProgramSymbols::new(modules);
+        let layout = self.layout;
+        let mut entry_call = Call::new(
+            &layout.entry_point,
+            &format!("{}&ret.0", layout.entry_point),
+            0,
+        );
+        entry_call.frame = frames::entry_frame(modules, &layout.entry_point);
+        X86_PROLOGUE
+            .replace("STACK_BASE", &layout.stack_base.to_string())
+            .replace("INITIAL_LCL", &layout.initial_lcl.to_string())
+            .replace("INITIAL_ARG", &layout.initial_arg.to_string())
+            .replace("INITIAL_THIS", &layout.initial_this.to_string())
+            .replace("INITIAL_THAT", &layout.initial_that.to_string())
+            + &self.create_call(&entry_call)
+            + "    jmp halt\n"
+    }
+
+    fn begin_module(&mut self, module: &Module) -> String {
+        format!("\n/* {}.vm */\n", module.name)
+    }
+
+    fn emit_instruction(&mut self, module: &Module, position: usize) -> (String, usize) {
+        if is_halt_loop(&module.instructions, position) {
+            return (
+                String::from("    jmp halt                    /* halt */\n"),
+                1,
+            );
+        }
+        let code = self.create_instruction(&module.instructions[position], &module.name);
+        (code, 1)
+    }
+
+    fn end_program(&mut self) -> String {
+        let return_table: String = (0..self.return_count)
+            .map(|id| format!("    .long return_{} - return_table\n", id))
+            .collect();
+        X86_EPILOGUE
+            .replace("RETURN_COUNT", &self.return_count.to_string())
+            .replace("RETURN_TABLE", &return_table)
+    }
+}
+
+impl X86Backend<'_> {
+    fn create_instruction(&mut self, instruction: &Instruction, module_name: &str) -> String {
+        let comment = format!("    /* {} */\n", format_instruction(instruction));
+        match instruction {
+            Instruction::CArithmetic(operator) => comment + &arithmetic(*operator),
+            Instruction::CPush(push) => {
+                let load = match push.segment {
+                    Segment::Constant => format!("    mov ax, {}\n", push.index),
+                    segment => format!(
+                        "{}    mov ax, {}\n",
+                        address(segment, push.index),
+                        self.operand(segment, push.index, module_name)
+                    ),
+                };
+                comment + &load + "    PUSH_AX\n"
+            }
+            Instruction::CPop(pop) => {
+                if pop.segment == Segment::Constant {
+                    panic!("Cannot pop to constant");
+                }
+                comment
+                    + "    POP_AX\n"
+                    + &address(pop.segment, pop.index)
+                    + &format!(
+                        "    mov {}, ax\n",
+                        self.operand(pop.segment, pop.index, module_name)
+                    )
+            }
+            Instruction::CLabel(label) => {
+                format!("label_{}:\n", self.symbols.label(label)) + &comment
+            }
+            Instruction::CGoto(label) => {
+                comment + &format!("    jmp label_{}\n", self.symbols.label(label))
+            }
+            Instruction::CIf(label) => {
+                comment
+                    + &format!(
+                        "    POP_AX\n    test ax, ax\n    jnz label_{}\n",
+                        self.symbols.label(label)
+                    )
+            }
+            Instruction::CFunction(function) => {
+                self.frame = function.frame;
+                let mut code = format!(
+                    "\nfunction_{}:\n{}",
+                    self.symbols.function(&function.function_name),
+                    comment
+                );
+                if function.n_args > 0 {
+                    code.push_str("    xor eax, eax\n");
+                }
+                for _ in 0..function.n_args {
+                    code.push_str("    PUSH_AX\n");
+                }
+                code
+            }
+            Instruction::CReturn => String::from(match self.frame {
+                FrameKind::Full => X86_RETURN,
+                FrameKind::Lite => X86_RETURN_LITE,
+            }),
+            Instruction::CCall(call) => self.create_call(call),
+            Instruction::CAsm(asm) => panic!(
+                "{}: the asm block {:?} only compiles for the hack target, not x86",
+                module_name, asm
+            ),
+        }
+    }
+
+    fn create_call(&mut self, call: &Call) -> String {
+        let template = match call.frame {
+            FrameKind::Full => X86_CALL,
+            FrameKind::Lite => X86_CALL_LITE,
+        };
+        let return_id = self.return_count;
+        self.return_count += 1;
+        template
+            .replace("RETURN_ID", &return_id.to_string())
+            .replace(
+                "COMMENT",
+                &format_instruction(&Instruction::CCall(call.clone())),
+            )
+            .replace("N_ARGS", &call.n_args.to_string())
+            .replace(
+                "FUNCTION_LABEL",
+                &format!("function_{}", self.symbols.function(&call.function_name)),
+            )
+            .replace("RETURN_LABEL", &format!("return_{}", return_id))
+    }
+
+    /// The memory operand of a segment slot, once `address` has pointed esi
+    /// at it.
+    fn operand(&self, segment: Segment, index: u16, module_name: &str) -> String {
+        let address = match segment {
+            Segment::Local | Segment::Argument | Segment::This | Segment::That => {
+                return String::from("word ptr [rbx + rsi * 2]");
+            }
+            Segment::Pointer => 3 + index,
+            Segment::Temp => self.layout.temp_address(index),
+            Segment::Screen | Segment::Keyboard => io_address(segment, index),
+            Segment::Static => self.symbols.static_address(module_name, index),
+            Segment::Constant => unreachable!(),
+        };
+        format!("word ptr [rbx + {}]", 2 * u32::from(address))
+    }
+}
+
+/// Points esi at a slot of a segment reached through a pointer register.
+/// Other segments are at fixed addresses and need nothing.
+fn address(segment: Segment, index: u16) -> String {
+    match POINTER_REGISTERS
+        .iter()
+        .find(|(pointer, _)| *pointer == segment)
+    {
+        Some((_, register)) => format!("    SLOT {}, {}\n", register, index),
+        None => String::new(),
+    }
+}
+
+/// Applies an operator to the top of the stack in place.
+fn arithmetic(operator: ArithmeticType) -> String {
+    let in_place =
+        |instruction: &str| format!("    TOP\n    {} word ptr [rbx + rsi * 2]\n", instruction);
+    let binary = |instruction: &str| {
+        format!(
+            "    POP_DX\n    TOP\n    {} word ptr [rbx + rsi * 2], dx\n",
+            instruction
+        )
+    };
+    match operator {
+        ArithmeticType::Unary(UnaryArithmeticOperator::Negate) => in_place("neg"),
+        ArithmeticType::Unary(UnaryArithmeticOperator::Not) => in_place("not"),
+        ArithmeticType::Binary(BinaryArithmeticOperator::Add) => binary("add"),
+        ArithmeticType::Binary(BinaryArithmeticOperator::Subtract) => binary("sub"),
+        ArithmeticType::Binary(BinaryArithmeticOperator::And) => binary("and"),
+        ArithmeticType::Binary(BinaryArithmeticOperator::Or) => binary("or"),
+        ArithmeticType::Binary(BinaryArithmeticOperator::Eq) => String::from("    COMPARE sete\n"),
+        ArithmeticType::Binary(BinaryArithmeticOperator::Gt) => String::from("    COMPARE setg\n"),
+        ArithmeticType::Binary(BinaryArithmeticOperator::Lt) => String::from("    COMPARE setl\n"),
+        ArithmeticType::Shift(ShiftArithmeticOperator::ShiftLeft, amount) => {
+            format!("    TOP\n    shl word ptr [rbx + rsi * 2], {}\n", amount)
+        }
+        // An arithmetic shift, like the Hack ALU's
+        ArithmeticType::Shift(ShiftArithmeticOperator::ShiftRight, amount) => {
+            format!("    TOP\n    sar word ptr [rbx + rsi * 2], {}\n", amount)
+        }
+        ArithmeticType::Extended(ExtendedArithmeticOperator::Multiply) => {
+            String::from("    MULTIPLY\n")
+        }
+        ArithmeticType::Extended(ExtendedArithmeticOperator::Divide) => {
+            String::from("    DIVIDE ax\n")
+        }
+        ArithmeticType::Extended(ExtendedArithmeticOperator::Modulo) => {
+            String::from("    DIVIDE dx\n")
+        }
+        ArithmeticType::Extended(ExtendedArithmeticOperator::Xor) => binary("xor"),
+        ArithmeticType::Extended(ExtendedArithmeticOperator::UnsignedLt) => {
+            String::from("    COMPARE setb\n")
+        }
+        ArithmeticType::Extended(ExtendedArithmeticOperator::UnsignedGt) => {
+            String::from("    COMPARE seta\n")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+    use std::{env, fs};
+
+    use super::*;
+    use crate::backend;
+    use crate::compiler::CodegenOptions;
+    use crate::test_programs::{assert_same_ram, parse_modules, run_hack};
+
+    const SYS: &str = "function Sys.init 0
+        push constant 4000
+        pop pointer 1
+        push constant 32767
+        push constant 2
+        call Main.wrap 2
+        pop that 0
+        push constant 16384
+        push constant 3
+        neg
+        call Main.wrap 2
+        pop that 1
+        push static 0
+        pop that 2
+        push constant 30000
+        push constant 7
+        neg
+        call Main.extended 2
+        pop that 3
+        label HALT
+        goto HALT";
+
+    const MAIN: &str = "function Main.wrap 1
+        push argument 0
+        push argument 1
+        add
+        pop local 0
+        push local 0
+        shiftleft
+        push local 0
+        shiftright
+        sub
+        push argument 1
+        push constant 0
+        lt
+        if-goto NEGATIVE
+        neg
+        label NEGATIVE
+        push static 0
+        push constant 1
+        add
+        pop static 0
+        return
+        function Main.extended 0
+        push argument 0
+        push argument 1
+        mul
+        push argument 0
+        push argument 1
+        div
+        xor
+        push argument 0
+        push argument 1
+        mod
+        shl 2
+        add
+        push argument 0
+        push argument 1
+        ult
+        sub
+        push argument 0
+        shr 3
+        push argument 1
+        ugt
+        sub
+        return";
+
+    fn run_x86(modules: &[Module], layout: &MemoryLayout) -> Vec<i16> {
+        let directory = env::temp_dir().join(format!("vm_x86_backend_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let source = directory.join("program.s");
+        let object = directory.join("program.o");
+        let binary = directory.join("program");
+        fs::write(
+            &source,
+            backend::compile_program(&mut X86Backend::new(layout), modules),
+        )
+        .unwrap();
+        let assembled = Command::new("as")
+            .arg("-o")
+            .arg(&object)
+            .arg(&source)
+            .status()
+            .expect("Cannot run as");
+        assert!(assembled.success(), "The x86 translation does not assemble");
+        let linked = Command::new("ld")
+            .arg("-o")
+            .arg(&binary)
+            .arg(&object)
+            .status()
+            .expect("Cannot run ld");
+        assert!(linked.success(), "The x86 translation does not link");
+        let output = Command::new(&binary).output().unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(output.stdout.len(), 65536);
+        output
+            .stdout
+            .chunks(2)
+            .map(|word| i16::from_le_bytes([word[0], word[1]]))
+            .collect()
+    }
+
+    #[test]
+    #[ignore = "needs as and ld, run with --ignored"]
+    fn x86_translation_leaves_the_same_ram_as_hack_code() {
+        let layout = MemoryLayout::default();
+        let modules = parse_modules(&[("Sys", SYS), ("Main", MAIN)]);
+        let emulator = run_hack(&modules, &layout, &CodegenOptions::default());
+        assert_eq!(emulator.ram[4000..4004], [-16386, 24572, 0, 9480]);
+        let ram = run_x86(&modules, &layout);
+        assert_same_ram(&ram, &emulator.ram, &modules, 4000..4004);
+    }
+}
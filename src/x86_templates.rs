@@ -0,0 +1,204 @@
+/// rbx holds the address of the RAM for the whole program. Every RAM word is
+/// reached through an address masked to 15 bits, like the A register.
+pub const X86_PROLOGUE: &str = "    .intel_syntax noprefix
+
+/* esi = the address `index` words past where RAM[pointer] points */
+    .macro SLOT pointer, index
+    movzx esi, word ptr [rbx + 2 * \\pointer]
+    add esi, \\index
+    and esi, 0x7FFF
+    .endm
+
+/* esi = the address of the top of the stack */
+    .macro TOP
+    movzx esi, word ptr [rbx]
+    dec esi
+    and esi, 0x7FFF
+    .endm
+
+    .macro PUSH_AX
+    movzx ecx, word ptr [rbx]
+    and ecx, 0x7FFF
+    mov word ptr [rbx + rcx * 2], ax
+    inc word ptr [rbx]
+    .endm
+
+    .macro POP_AX
+    dec word ptr [rbx]
+    movzx ecx, word ptr [rbx]
+    and ecx, 0x7FFF
+    mov ax, word ptr [rbx + rcx * 2]
+    .endm
+
+    .macro POP_DX
+    dec word ptr [rbx]
+    movzx ecx, word ptr [rbx]
+    and ecx, 0x7FFF
+    mov dx, word ptr [rbx + rcx * 2]
+    .endm
+
+/* The top of the stack = the top of the stack compared to the word popped
+   before it, as -1 or 0 */
+    .macro COMPARE set
+    POP_DX
+    TOP
+    cmp word ptr [rbx + rsi * 2], dx
+    \\set al
+    movzx ax, al
+    neg ax
+    mov word ptr [rbx + rsi * 2], ax
+    .endm
+
+/* The top of the stack = the top of the stack times the word popped before
+   it, wrapped to 16 bits */
+    .macro MULTIPLY
+    POP_DX
+    TOP
+    mov ax, word ptr [rbx + rsi * 2]
+    imul ax, dx
+    mov word ptr [rbx + rsi * 2], ax
+    .endm
+
+/* The top of the stack = the quotient (ax) or remainder (dx) of the top of
+   the stack divided by the word popped before it. Dividing 32-bit values
+   lets the most negative word divided by -1 wrap, and dividing by 0 gives a
+   quotient of 0 and leaves the dividend as the remainder */
+    .macro DIVIDE result
+    POP_DX
+    TOP
+    movsx ecx, dx
+    movsx eax, word ptr [rbx + rsi * 2]
+    mov edx, eax
+    test ecx, ecx
+    jz 1f
+    cdq
+    idiv ecx
+    jmp 2f
+1:
+    xor eax, eax
+2:
+    mov word ptr [rbx + rsi * 2], \\result
+    .endm
+
+/* RAM[register] = the word `offset` words below the frame in r8 */
+    .macro RESTORE register, offset
+    lea esi, [r8 - \\offset]
+    and esi, 0x7FFF
+    mov ax, word ptr [rbx + rsi * 2]
+    mov word ptr [rbx + 2 * \\register], ax
+    .endm
+
+    .bss
+    .balign 16
+ram:
+    .zero 32768         /* RAM[0..16384) */
+screen:
+    .zero 16384         /* SCREEN, RAM[16384..24576), never drawn */
+kbd:
+    .zero 16384         /* KBD, RAM[24576], reads as no key pressed */
+
+    .text
+    .globl _start
+_start:
+    lea rbx, [rip + ram]
+    mov word ptr [rbx], STACK_BASE        /* Bootstrap */
+    mov word ptr [rbx + 2], INITIAL_LCL
+    mov word ptr [rbx + 4], INITIAL_ARG
+    mov word ptr [rbx + 6], INITIAL_THIS
+    mov word ptr [rbx + 8], INITIAL_THAT
+";
+
+pub const X86_CALL: &str = "    mov ax, RETURN_ID           /* COMMENT */
+    PUSH_AX
+    mov ax, word ptr [rbx + 2]
+    PUSH_AX
+    mov ax, word ptr [rbx + 4]
+    PUSH_AX
+    mov ax, word ptr [rbx + 6]
+    PUSH_AX
+    mov ax, word ptr [rbx + 8]
+    PUSH_AX
+    mov ax, word ptr [rbx]
+    sub ax, 5 + N_ARGS
+    mov word ptr [rbx + 4], ax
+    mov ax, word ptr [rbx]
+    mov word ptr [rbx + 2], ax
+    jmp FUNCTION_LABEL
+RETURN_LABEL:
+";
+
+pub const X86_CALL_LITE: &str = "    mov ax, RETURN_ID           /* COMMENT */
+    PUSH_AX
+    mov ax, word ptr [rbx + 2]
+    PUSH_AX
+    mov ax, word ptr [rbx + 4]
+    PUSH_AX
+    mov ax, word ptr [rbx]
+    sub ax, 3 + N_ARGS
+    mov word ptr [rbx + 4], ax
+    mov ax, word ptr [rbx]
+    mov word ptr [rbx + 2], ax
+    jmp FUNCTION_LABEL
+RETURN_LABEL:
+";
+
+pub const X86_RETURN: &str = "    movzx r8d, word ptr [rbx + 2] /* return */
+    lea esi, [r8 - 5]
+    and esi, 0x7FFF
+    movzx r9d, word ptr [rbx + rsi * 2]
+    POP_AX
+    SLOT 2, 0
+    mov word ptr [rbx + rsi * 2], ax
+    mov ax, word ptr [rbx + 4]
+    inc ax
+    mov word ptr [rbx], ax
+    RESTORE 4, 1
+    RESTORE 3, 2
+    RESTORE 2, 3
+    RESTORE 1, 4
+    jmp dispatch
+";
+
+pub const X86_RETURN_LITE: &str = "    movzx r8d, word ptr [rbx + 2] /* return // lite frame */
+    lea esi, [r8 - 3]
+    and esi, 0x7FFF
+    movzx r9d, word ptr [rbx + rsi * 2]
+    POP_AX
+    SLOT 2, 0
+    mov word ptr [rbx + rsi * 2], ax
+    mov ax, word ptr [rbx + 4]
+    inc ax
+    mov word ptr [rbx], ax
+    RESTORE 2, 1
+    RESTORE 1, 2
+    jmp dispatch
+";
+
+/// Returns jump back through the ids their calls pushed, which index a table
+/// of offsets so the code also links as a position independent executable.
+/// Halting writes the RAM to stdout and exits.
+pub const X86_EPILOGUE: &str = "
+dispatch:
+    cmp r9, RETURN_COUNT
+    jae halt
+    lea rdx, [rip + return_table]
+    movsxd rax, dword ptr [rdx + r9 * 4]
+    add rax, rdx
+    jmp rax
+
+halt:
+    mov eax, 1                  /* write(1, ram, 65536) */
+    mov edi, 1
+    lea rsi, [rip + ram]
+    mov edx, 65536
+    syscall
+    mov eax, 60                 /* exit(0) */
+    xor edi, edi
+    syscall
+
+    .section .rodata
+    .balign 4
+return_table:
+RETURN_TABLE
+    .section .note.GNU-stack, \"\", @progbits
+";